@@ -0,0 +1,604 @@
+//! Prometheus metrics.
+//!
+//! The components record what happens with the free functions of this module, the registry is
+//! rendered in the Prometheus text format when `/metrics` is scraped.
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+
+use crate::proxy_state::{self, ProxyState, ProxyStates};
+
+/// Max number of shares waiting for an ack we keep track of, older ones are forgotten.
+const MAX_PENDING_ACKS: usize = 10_000;
+
+/// Pipeline and channel id of a downstream. Each translator gives its own channel ids and each
+/// pool connection its own sequence numbers, the pipeline tells them apart when the hashrate is
+/// split, see [`crate::split`].
+type Key = (Option<Arc<str>>, u32);
+
+/// Pipeline, name and whether it is up of a component, see [`component_states`].
+type ComponentState = (Option<Arc<str>>, &'static str, bool);
+
+/// Name, help and value of a metric reported for each downstream.
+type DownstreamMetric<T> = (&'static str, &'static str, fn(&DownstreamMetrics) -> T);
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counters and gauges of a connected SV1 downstream.
+#[derive(Debug, Clone, Default)]
+pub struct DownstreamMetrics {
+    /// Pool of the pipeline of the downstream, when the hashrate is split
+    pub pool: Option<String>,
+    pub host: String,
+    pub authorized_names: Vec<String>,
+    pub shares_received: u64,
+    pub shares_validated: u64,
+    pub shares_forwarded: u64,
+    pub shares_acknowledged: u64,
+    pub estimated_hashrate: f32,
+    pub difficulty: f32,
+    pub last_share: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Metrics {
+    downstreams: DashMap<Key, DownstreamMetrics>,
+    rejects: DashMap<String, u64>,
+    /// Sequence number of the shares sent up -> channel id of the downstream that found the share
+    pending_acks: DashMap<Key, u32>,
+    /// By pipeline, see [`Key`]
+    upstream_nominal_hashrate: DashMap<Option<Arc<str>>, f32>,
+    pool_latencies: DashMap<SocketAddr, Vec<(&'static str, Duration)>>,
+    /// Missing and extra shares found by the last reconciliation with the pool
+    reconciliation: Mutex<Option<(u64, u64)>>,
+    shares_lost: AtomicU64,
+    late_acks: AtomicU64,
+    unexpected_acks: AtomicU64,
+    unknown_channel_messages: AtomicU64,
+    shares_pending_ack: AtomicU64,
+    blocks_found: AtomicU64,
+}
+
+impl Metrics {
+    fn update_downstream(&self, key: Key, f: impl FnOnce(&mut DownstreamMetrics)) {
+        if let Some(mut downstream) = self.downstreams.get_mut(&key) {
+            f(&mut downstream);
+        }
+    }
+
+    fn share_forwarded(&self, (pool, downstream_id): Key, sequence_number: u32) {
+        self.update_downstream((pool.clone(), downstream_id), |d| d.shares_forwarded += 1);
+        if self.pending_acks.len() >= MAX_PENDING_ACKS {
+            // Forget the oldest half of the pipeline, the pool will never ack them
+            let keep = MAX_PENDING_ACKS as u32 / 2;
+            self.pending_acks
+                .retain(|(p, s), _| *p != pool || sequence_number.wrapping_sub(*s) < keep);
+        }
+        self.pending_acks
+            .insert((pool, sequence_number), downstream_id);
+    }
+
+    fn share_acknowledged(&self, (pool, sequence_number): Key) {
+        if let Some((_, downstream_id)) = self.pending_acks.remove(&(pool.clone(), sequence_number))
+        {
+            self.update_downstream((pool, downstream_id), |d| d.shares_acknowledged += 1);
+        }
+    }
+
+    fn downstreams(&self) -> Vec<(u32, DownstreamMetrics)> {
+        let mut downstreams: Vec<(Key, DownstreamMetrics)> = self
+            .downstreams
+            .iter()
+            .map(|d| (d.key().clone(), d.value().clone()))
+            .collect();
+        downstreams.sort_by(|(a, _), (b, _)| a.cmp(b));
+        downstreams
+            .into_iter()
+            .map(|((_, id), d)| (id, d))
+            .collect()
+    }
+
+    fn render(&self, component_states: &[ComponentState]) -> String {
+        let mut out = String::new();
+        let downstreams = self.downstreams();
+
+        let counters: [DownstreamMetric<u64>; 4] = [
+            (
+                "shares_received_total",
+                "Shares received from the downstream",
+                |d| d.shares_received,
+            ),
+            (
+                "shares_validated_total",
+                "Shares that passed the proxy validation",
+                |d| d.shares_validated,
+            ),
+            (
+                "shares_forwarded_total",
+                "Shares meeting the upstream target sent to the pool",
+                |d| d.shares_forwarded,
+            ),
+            (
+                "shares_acknowledged_total",
+                "Shares acknowledged by the pool",
+                |d| d.shares_acknowledged,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, help, "counter");
+            for (id, d) in &downstreams {
+                let _ = writeln!(out, "demand_cli_{name}{} {}", labels(*id, d), value(d));
+            }
+        }
+        let gauges: [DownstreamMetric<f32>; 2] = [
+            (
+                "downstream_hashrate",
+                "Estimated hashrate of the downstream in h/s",
+                |d| d.estimated_hashrate,
+            ),
+            (
+                "downstream_difficulty",
+                "Current difficulty of the downstream",
+                |d| d.difficulty,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, help, "gauge");
+            for (id, d) in &downstreams {
+                let _ = writeln!(out, "demand_cli_{name}{} {}", labels(*id, d), value(d));
+            }
+        }
+
+        header(
+            &mut out,
+            "share_rejects_total",
+            "Rejected shares by reason",
+            "counter",
+        );
+        let mut rejects: Vec<(String, u64)> = self
+            .rejects
+            .iter()
+            .map(|r| (r.key().clone(), *r.value()))
+            .collect();
+        rejects.sort();
+        for (reason, count) in rejects {
+            let _ = writeln!(
+                out,
+                "demand_cli_share_rejects_total{{reason=\"{}\"}} {count}",
+                escape(&reason)
+            );
+        }
+
+        header(
+            &mut out,
+            "upstream_channel_nominal_hashrate",
+            "Nominal hashrate of the upstream channel in h/s",
+            "gauge",
+        );
+        // The pipelines that are gone are forgotten
+        self.upstream_nominal_hashrate
+            .retain(|pool, _| pool.is_none() || component_states.iter().any(|(p, _, _)| p == pool));
+        let mut nominal_hashrates: Vec<(Option<Arc<str>>, f32)> = self
+            .upstream_nominal_hashrate
+            .iter()
+            .map(|h| (h.key().clone(), *h.value()))
+            .collect();
+        nominal_hashrates.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (pool, nominal_hashrate) in nominal_hashrates {
+            let _ = writeln!(
+                out,
+                "demand_cli_upstream_channel_nominal_hashrate{} {nominal_hashrate}",
+                pool_label(pool.as_deref())
+            );
+        }
+
+        header(
+            &mut out,
+            "pool_latency_seconds",
+            "Latency of each stage of the pool connection setup",
+            "gauge",
+        );
+        let mut latencies: Vec<(SocketAddr, Vec<(&str, Duration)>)> = self
+            .pool_latencies
+            .iter()
+            .map(|l| (*l.key(), l.value().clone()))
+            .collect();
+        latencies.sort_by_key(|(pool, _)| *pool);
+        for (pool, stages) in latencies {
+            for (stage, latency) in stages {
+                let _ = writeln!(
+                    out,
+                    "demand_cli_pool_latency_seconds{{pool=\"{pool}\",stage=\"{stage}\"}} {}",
+                    latency.as_secs_f64()
+                );
+            }
+        }
+
+        let counters = [
+            (
+                "shares_lost_total",
+                "Shares not acknowledged by the pool within the ack timeout",
+                "counter",
+                &self.shares_lost,
+            ),
+            (
+                "share_acks_late_total",
+                "Acks received after the share was counted as lost",
+                "counter",
+                &self.late_acks,
+            ),
+            (
+                "share_acks_unexpected_total",
+                "Acks for shares that were not waiting for one, duplicates or unknown",
+                "counter",
+                &self.unexpected_acks,
+            ),
+            (
+                "pool_messages_unknown_channel_total",
+                "Messages from the pool for a channel the proxy did not open, relayed anyway",
+                "counter",
+                &self.unknown_channel_messages,
+            ),
+            (
+                "shares_pending_ack",
+                "Shares sent to the pool waiting for an ack",
+                "gauge",
+                &self.shares_pending_ack,
+            ),
+            (
+                "blocks_found_total",
+                "Shares that met the network target",
+                "counter",
+                &self.blocks_found,
+            ),
+        ];
+        for (name, help, kind, value) in counters {
+            header(&mut out, name, help, kind);
+            let _ = writeln!(out, "demand_cli_{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let reconciliation = *self
+            .reconciliation
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some((missing, extra)) = reconciliation {
+            header(
+                &mut out,
+                "reconciliation_missing_shares",
+                "Shares sent to the pool missing from its window at the last reconciliation",
+                "gauge",
+            );
+            let _ = writeln!(out, "demand_cli_reconciliation_missing_shares {missing}");
+            header(
+                &mut out,
+                "reconciliation_extra_shares",
+                "Shares in the pool window beyond the ones sent at the last reconciliation",
+                "gauge",
+            );
+            let _ = writeln!(out, "demand_cli_reconciliation_extra_shares {extra}");
+        }
+
+        header(
+            &mut out,
+            "component_up",
+            "1 if the component is up, 0 if it is down",
+            "gauge",
+        );
+        for (pool, component, up) in component_states {
+            let pool = match pool {
+                Some(pool) => format!(",pool=\"{}\"", escape(pool)),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                out,
+                "demand_cli_component_up{{component=\"{component}\"{pool}}} {}",
+                *up as u8
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP demand_cli_{name} {help}");
+    let _ = writeln!(out, "# TYPE demand_cli_{name} {kind}");
+}
+
+fn labels(id: u32, downstream: &DownstreamMetrics) -> String {
+    let worker = downstream
+        .authorized_names
+        .first()
+        .map(String::as_str)
+        .unwrap_or("");
+    let pool = match &downstream.pool {
+        Some(pool) => format!(",pool=\"{}\"", escape(pool)),
+        None => String::new(),
+    };
+    format!(
+        "{{downstream=\"{id}\",host=\"{}\"{pool},worker=\"{}\"}}",
+        escape(&downstream.host),
+        escape(worker)
+    )
+}
+
+/// Label of the pipeline of a metric, none outside of split mode.
+fn pool_label(pool: Option<&str>) -> String {
+    match pool {
+        Some(pool) => format!("{{pool=\"{}\"}}", escape(pool)),
+        None => String::new(),
+    }
+}
+
+/// Escapes a label value as required by the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Up/down state of each component, from `ProxyState`. When the hashrate is split the state of
+/// each pipeline is reported with its pool, and a component is reported down without the pool
+/// label if it is down in any of them.
+fn component_states() -> Vec<ComponentState> {
+    let scopes = proxy_state::scopes();
+    let mut states: Vec<ComponentState> = Vec::new();
+    let all_errors = ProxyState::get_errors()
+        .into_iter()
+        .chain(scopes.iter().flat_map(|(_, state)| state.errors()))
+        .collect::<Vec<_>>();
+    states.extend(
+        components_up(&all_errors)
+            .into_iter()
+            .map(|(component, up)| (None, component, up)),
+    );
+    for (pool, state) in scopes {
+        states.extend(
+            components_up(&state.errors())
+                .into_iter()
+                .map(|(component, up)| (Some(pool.clone()), component, up)),
+        );
+    }
+    states
+}
+
+fn components_up(errors: &[ProxyStates]) -> Vec<(&'static str, bool)> {
+    let is_down = |component: &str| {
+        errors.iter().any(|e| {
+            let name = match e {
+                ProxyStates::Pool(_) => "pool",
+                ProxyStates::Tp(_) => "tp",
+                ProxyStates::Jd(_) => "jd",
+                ProxyStates::ShareAccounter(_) => "share_accounter",
+                ProxyStates::InternalInconsistency(_) => "internal_consistency",
+                ProxyStates::Downstream(_) => "downstream",
+                ProxyStates::Upstream(_) => "upstream",
+                ProxyStates::Translator(_) => "translator",
+            };
+            name == component
+        })
+    };
+    [
+        "pool",
+        "tp",
+        "jd",
+        "share_accounter",
+        "translator",
+        "downstream",
+        "upstream",
+        "internal_consistency",
+    ]
+    .into_iter()
+    .map(|component| (component, !is_down(component)))
+    .collect()
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render() -> String {
+    METRICS.render(&component_states())
+}
+
+/// Key of the downstream `id` of the pipeline of the caller.
+fn key(id: u32) -> Key {
+    (proxy_state::scope(), id)
+}
+
+/// A SV1 downstream connected and got `id` as channel id.
+pub fn downstream_connected(id: u32, host: &str, estimated_hashrate: f32, difficulty: f32) {
+    let key = key(id);
+    METRICS.downstreams.insert(
+        key.clone(),
+        DownstreamMetrics {
+            pool: key.0.as_deref().map(str::to_string),
+            host: host.to_string(),
+            estimated_hashrate,
+            difficulty,
+            ..Default::default()
+        },
+    );
+}
+
+pub fn downstream_disconnected(id: u32) {
+    METRICS.downstreams.remove(&key(id));
+}
+
+pub fn downstream_authorized(id: u32, name: &str) {
+    METRICS.update_downstream(key(id), |d| d.authorized_names.push(name.to_string()));
+}
+
+/// Updates the values computed by the downstream difficulty management.
+pub fn downstream_difficulty(id: u32, estimated_hashrate: f32, difficulty: f32) {
+    METRICS.update_downstream(key(id), |d| {
+        d.estimated_hashrate = estimated_hashrate;
+        d.difficulty = difficulty;
+    });
+}
+
+pub fn share_received(downstream_id: u32) {
+    METRICS.update_downstream(key(downstream_id), |d| {
+        d.shares_received += 1;
+        d.last_share = Some(SystemTime::now());
+    });
+}
+
+pub fn share_validated(downstream_id: u32) {
+    METRICS.update_downstream(key(downstream_id), |d| d.shares_validated += 1);
+}
+
+/// A share found by `downstream_id` is sent to the pool with `sequence_number`, the pool ack is
+/// attributed to the downstream with it.
+pub fn share_forwarded(downstream_id: u32, sequence_number: u32) {
+    METRICS.share_forwarded(key(downstream_id), sequence_number)
+}
+
+/// The pool acknowledged the share sent with `sequence_number`, as attributed by
+/// [`crate::share_accounter`] from the job of the ack.
+pub fn share_acknowledged(sequence_number: u32) {
+    METRICS.share_acknowledged(key(sequence_number))
+}
+
+pub fn share_rejected(reason: &str) {
+    *METRICS.rejects.entry(reason.to_string()).or_default() += 1;
+}
+
+pub fn upstream_nominal_hashrate(hashrate: f32) {
+    METRICS
+        .upstream_nominal_hashrate
+        .insert(proxy_state::scope(), hashrate);
+}
+
+/// Latency of each stage of the last connection setup with `pool`.
+pub fn pool_latency(pool: SocketAddr, stages: Vec<(&'static str, Duration)>) {
+    METRICS.pool_latencies.insert(pool, stages);
+}
+
+/// Called when the pool hosts are resolved again, the addresses that are not in `pools` anymore
+/// are forgotten.
+pub fn pools_resolved(pools: &[SocketAddr]) {
+    METRICS
+        .pool_latencies
+        .retain(|pool, _| pools.contains(pool));
+}
+
+pub fn shares_lost(count: u64) {
+    METRICS.shares_lost.fetch_add(count, Ordering::Relaxed);
+}
+
+pub fn late_ack() {
+    METRICS.late_acks.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn unexpected_ack() {
+    METRICS.unexpected_acks.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn unknown_channel_message() {
+    METRICS
+        .unknown_channel_messages
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn shares_pending_ack(count: u64) {
+    METRICS.shares_pending_ack.store(count, Ordering::Relaxed);
+}
+
+pub fn block_found() {
+    METRICS.blocks_found.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Result of the last reconciliation of the share ledger with the pool window.
+pub fn reconciliation(missing: u64, extra: u64) {
+    *METRICS
+        .reconciliation
+        .lock()
+        .unwrap_or_else(|e| e.into_inner()) = Some((missing, extra));
+}
+
+/// Snapshot of the connected downstreams.
+pub fn downstreams() -> Vec<(u32, DownstreamMetrics)> {
+    METRICS.downstreams()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_acks_and_renders() {
+        let metrics = Metrics::default();
+        metrics.downstreams.insert(
+            (None, 1),
+            DownstreamMetrics {
+                host: "10.0.0.1".to_string(),
+                authorized_names: vec!["worker\"1".to_string()],
+                ..Default::default()
+            },
+        );
+        // Same channel id and sequence numbers on another pipeline
+        let pool: Option<Arc<str>> = Some("pool.example.com:2000".into());
+        metrics.downstreams.insert(
+            (pool.clone(), 1),
+            DownstreamMetrics {
+                pool: Some("pool.example.com:2000".to_string()),
+                host: "10.0.0.2".to_string(),
+                ..Default::default()
+            },
+        );
+        metrics.share_forwarded((None, 1), 0);
+        metrics.share_forwarded((None, 1), 1);
+        metrics.share_forwarded((pool.clone(), 1), 1);
+        metrics.share_acknowledged((None, 1));
+        // Unknown or repeated acks are ignored
+        metrics.share_acknowledged((None, 1));
+        metrics.share_acknowledged((None, 1000));
+        *metrics
+            .rejects
+            .entry("invalid_share".to_string())
+            .or_default() += 2;
+        metrics.pool_latencies.insert(
+            "127.0.0.1:2000".parse().unwrap(),
+            vec![("setup_connection", Duration::from_millis(250))],
+        );
+
+        metrics.upstream_nominal_hashrate.insert(pool.clone(), 1e12);
+        // Gone with its pipeline
+        let removed: Option<Arc<str>> = Some("removed.example.com:2000".into());
+        metrics.upstream_nominal_hashrate.insert(removed, 1e12);
+
+        let rendered = metrics.render(&[
+            (None, "pool", true),
+            (None, "tp", false),
+            (pool.clone(), "pool", false),
+        ]);
+        let labels = r#"{downstream="1",host="10.0.0.1",worker="worker\"1"}"#;
+        assert!(rendered.contains(&format!("demand_cli_shares_forwarded_total{labels} 2")));
+        assert!(rendered.contains(&format!("demand_cli_shares_acknowledged_total{labels} 1")));
+        let labels = r#"{downstream="1",host="10.0.0.2",pool="pool.example.com:2000",worker=""}"#;
+        assert!(rendered.contains(&format!("demand_cli_shares_forwarded_total{labels} 1")));
+        assert!(rendered.contains(&format!("demand_cli_shares_acknowledged_total{labels} 0")));
+        assert!(rendered.contains(r#"demand_cli_share_rejects_total{reason="invalid_share"} 2"#));
+        assert!(rendered.contains(
+            r#"demand_cli_pool_latency_seconds{pool="127.0.0.1:2000",stage="setup_connection"} 0.25"#
+        ));
+        assert!(rendered.contains(r#"demand_cli_component_up{component="tp"} 0"#));
+        assert!(rendered.contains(
+            r#"demand_cli_component_up{component="pool",pool="pool.example.com:2000"} 0"#
+        ));
+        assert!(rendered.contains(
+            r#"demand_cli_upstream_channel_nominal_hashrate{pool="pool.example.com:2000"} 1000000000000"#
+        ));
+        assert!(!rendered.contains("removed.example.com"));
+        assert_eq!(metrics.pending_acks.len(), 2);
+    }
+}
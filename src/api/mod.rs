@@ -0,0 +1,108 @@
+//! Read-only HTTP endpoint used to monitor the proxy.
+//!
+//! `GET /metrics` returns the Prometheus metrics, `GET /status` a JSON summary of what the
+//! proxy is doing, `GET /workers` the statistics of each worker and `GET /state/history` the
+//! last state transitions of the components with their reason. It is enabled with
+//! `--api-port`, and listens on 127.0.0.1 unless `--api-address` says otherwise: the endpoints
+//! are not authenticated.
+pub mod metrics;
+pub mod status;
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info, warn};
+
+/// Max size of the request head we read, the endpoints take no body.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Starts the HTTP server on `address`. It lives as long as the process.
+pub fn start(address: SocketAddr) {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Impossible to start the HTTP API on {address}: {e}");
+                return;
+            }
+        };
+        info!("HTTP API listening on {address}");
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if tokio::time::timeout(REQUEST_TIMEOUT, handle(stream))
+                            .await
+                            .is_err()
+                        {
+                            warn!("HTTP API request timed out");
+                        }
+                    });
+                }
+                Err(e) => warn!("HTTP API failed to accept a connection: {e}"),
+            }
+        }
+    });
+}
+
+fn workers() -> String {
+    serde_json::to_string(&crate::worker_stats::workers())
+        .unwrap_or_else(|e| format!("{{\"error\":\"{e}\"}}"))
+}
+
+fn state_history() -> String {
+    serde_json::to_string(&crate::proxy_state::history())
+        .unwrap_or_else(|e| format!("{{\"error\":\"{e}\"}}"))
+}
+
+async fn handle(stream: TcpStream) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    // Skip the headers
+    let mut read = request_line.len();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if line == "\r\n" || line == "\n" => break,
+            Ok(n) => {
+                read += n;
+                if read > MAX_REQUEST_LEN {
+                    return;
+                }
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(|p| p.split('?').next().unwrap_or(p));
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics::render())
+        }
+        (Some("GET"), Some("/status")) => ("200 OK", "application/json", status::render()),
+        (Some("GET"), Some("/workers")) => ("200 OK", "application/json", workers()),
+        (Some("GET"), Some("/state/history")) => ("200 OK", "application/json", state_history()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let mut stream = reader.into_inner();
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,285 @@
+//! JSON status returned by `GET /status`.
+//!
+//! The components push what the status needs here, as the router and the translator are owned
+//! by their tasks and can not be queried from the HTTP server. When the hashrate is split, what
+//! the components of a pipeline push goes to the status of that pipeline, see
+//! [`crate::proxy_state::Scope`].
+use std::{
+    net::SocketAddr,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{
+    proxy_state::{self, ProxyState},
+    router::Candidate,
+};
+
+lazy_static! {
+    static ref STATUS: RwLock<Status> = RwLock::new(Status::default());
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct Status {
+    current_pool: Option<PoolStatus>,
+    jd_mode: bool,
+    last_template: Option<TemplateStatus>,
+    last_job: Option<JobStatus>,
+    last_prev_hash: Option<PrevHashStatus>,
+    pool_probes: Vec<PoolProbeStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pipelines: Vec<PipelineStatus>,
+}
+
+/// A pipeline of the split mode, see [`crate::split`].
+#[derive(Debug, Clone, Default, Serialize)]
+struct PipelineStatus {
+    pool: String,
+    /// Address connected to, `None` while the pipeline is down
+    address: Option<String>,
+    /// Components of the pipeline that are down
+    errors: Vec<String>,
+    last_job: Option<JobStatus>,
+    last_prev_hash: Option<PrevHashStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PoolStatus {
+    host: String,
+    address: String,
+}
+
+/// Last latency probe of a pool by the router.
+#[derive(Debug, Clone, Serialize)]
+struct PoolProbeStatus {
+    address: String,
+    reachable: bool,
+    /// Smoothed latency, if the pool answered
+    latency_ms: Option<u64>,
+    /// Between 0 and 1, see [`crate::router::health`]
+    health: f64,
+    /// Latency of each stage measured, in the order they happened
+    stages_ms: Vec<(&'static str, u64)>,
+    probed_at: u64,
+}
+
+/// Last template received from the Template Provider, JD mode only.
+#[derive(Debug, Clone, Serialize)]
+struct TemplateStatus {
+    template_id: u64,
+    future: bool,
+    coinbase_tx_value_remaining: u64,
+    received_at: u64,
+}
+
+/// Last job received by the translator, from the pool or from the JD client.
+#[derive(Debug, Clone, Serialize)]
+struct JobStatus {
+    job_id: u32,
+    future: bool,
+    received_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PrevHashStatus {
+    prev_hash: String,
+    job_id: u32,
+    received_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct DownstreamStatus {
+    channel_id: u32,
+    /// Pool of the pipeline of the downstream, when the hashrate is split
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
+    ip: String,
+    authorized_names: Vec<String>,
+    difficulty: f32,
+    estimated_hashrate: f32,
+    last_share: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    #[serde(flatten)]
+    status: Status,
+    errors: Vec<String>,
+    downstreams: Vec<DownstreamStatus>,
+}
+
+fn update(f: impl FnOnce(&mut Status)) {
+    let mut status = STATUS.write().unwrap_or_else(|e| e.into_inner());
+    f(&mut status)
+}
+
+/// Updates the status of the pipeline of the caller, or the global one outside of split mode.
+fn update_pipeline(global: impl FnOnce(&mut Status), pipeline: impl FnOnce(&mut PipelineStatus)) {
+    let Some(pool) = proxy_state::scope() else {
+        return update(global);
+    };
+    update(|s| {
+        let index = match s.pipelines.iter().position(|p| *p.pool == *pool) {
+            Some(index) => index,
+            None => {
+                // Forget the pipelines that are gone
+                let scopes = proxy_state::scopes();
+                s.pipelines
+                    .retain(|p| scopes.iter().any(|(name, _)| **name == *p.pool));
+                s.pipelines.push(PipelineStatus {
+                    pool: pool.to_string(),
+                    ..Default::default()
+                });
+                s.pipelines.len() - 1
+            }
+        };
+        pipeline(&mut s.pipelines[index])
+    })
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Called when the proxy is initialized with a new upstream, and by the pipelines of the split
+/// mode when they connect or go down.
+pub fn proxy_initialized(current_pool: Option<Candidate>, jd_mode: bool) {
+    let address = current_pool
+        .as_ref()
+        .map(|candidate| candidate.address.to_string());
+    update_pipeline(
+        |s| {
+            s.current_pool = current_pool.map(|candidate| PoolStatus {
+                host: candidate.pool.host,
+                address: candidate.address.to_string(),
+            });
+            s.jd_mode = jd_mode;
+            if !jd_mode {
+                s.last_template = None;
+            }
+        },
+        |p| p.address = address,
+    )
+}
+
+pub fn new_template(template_id: u64, future: bool, coinbase_tx_value_remaining: u64) {
+    update(|s| {
+        s.last_template = Some(TemplateStatus {
+            template_id,
+            future,
+            coinbase_tx_value_remaining,
+            received_at: unix_time(SystemTime::now()),
+        })
+    })
+}
+
+pub fn new_job(job_id: u32, future: bool) {
+    let job = JobStatus {
+        job_id,
+        future,
+        received_at: unix_time(SystemTime::now()),
+    };
+    update_pipeline(
+        |s| s.last_job = Some(job.clone()),
+        |p| p.last_job = Some(job.clone()),
+    )
+}
+
+/// `prev_hash` as received on the wire, it is shown in the usual reversed byte order.
+pub fn new_prev_hash(prev_hash: &[u8], job_id: u32) {
+    let prev_hash = prev_hash.iter().rev().map(|b| format!("{b:02x}")).collect();
+    let prev_hash = PrevHashStatus {
+        prev_hash,
+        job_id,
+        received_at: unix_time(SystemTime::now()),
+    };
+    update_pipeline(
+        |s| s.last_prev_hash = Some(prev_hash.clone()),
+        |p| p.last_prev_hash = Some(prev_hash.clone()),
+    )
+}
+
+/// Called by the router after each probe of `pool`, `latency` is `None` if it did not answer.
+pub fn pool_probed(
+    pool: SocketAddr,
+    latency: Option<Duration>,
+    health: f64,
+    stages: Vec<(&'static str, Duration)>,
+) {
+    let probe = PoolProbeStatus {
+        address: pool.to_string(),
+        reachable: latency.is_some(),
+        latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        health,
+        stages_ms: stages
+            .into_iter()
+            .map(|(stage, latency)| (stage, latency.as_millis() as u64))
+            .collect(),
+        probed_at: unix_time(SystemTime::now()),
+    };
+    update(|s| {
+        match s
+            .pool_probes
+            .iter_mut()
+            .find(|p| p.address == probe.address)
+        {
+            Some(previous) => *previous = probe,
+            None => s.pool_probes.push(probe),
+        }
+        s.pool_probes.sort_by(|a, b| a.address.cmp(&b.address));
+    })
+}
+
+/// Called when the pool hosts are resolved again, the probes of the addresses that are not in
+/// `pools` anymore are dropped.
+pub fn pools_resolved(pools: &[SocketAddr]) {
+    let pools: Vec<String> = pools.iter().map(SocketAddr::to_string).collect();
+    update(|s| s.pool_probes.retain(|probe| pools.contains(&probe.address)))
+}
+
+/// Renders the status as JSON.
+pub fn render() -> String {
+    let mut status = STATUS.read().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut errors: Vec<String> = ProxyState::get_errors()
+        .iter()
+        .map(|e| format!("{e:?}"))
+        .collect();
+    // One for each pipeline alive, with the errors of its own state
+    let pushed = std::mem::take(&mut status.pipelines);
+    for (pool, state) in proxy_state::scopes() {
+        let mut pipeline = pushed
+            .iter()
+            .find(|p| *p.pool == *pool)
+            .cloned()
+            .unwrap_or_else(|| PipelineStatus {
+                pool: pool.to_string(),
+                ..Default::default()
+            });
+        pipeline.errors = state.errors().iter().map(|e| format!("{e:?}")).collect();
+        errors.extend(pipeline.errors.iter().map(|e| format!("{pool}: {e}")));
+        status.pipelines.push(pipeline);
+    }
+    let downstreams = super::metrics::downstreams()
+        .into_iter()
+        .map(|(channel_id, d)| DownstreamStatus {
+            channel_id,
+            pool: d.pool,
+            ip: d.host,
+            authorized_names: d.authorized_names,
+            difficulty: d.difficulty,
+            estimated_hashrate: d.estimated_hashrate,
+            last_share: d.last_share.map(unix_time),
+        })
+        .collect();
+    let response = StatusResponse {
+        status,
+        errors,
+        downstreams,
+    };
+    serde_json::to_string(&response).unwrap_or_else(|e| format!("{{\"error\":\"{e}\"}}"))
+}
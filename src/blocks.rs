@@ -0,0 +1,426 @@
+//! Blocks found by the miners.
+//!
+//! A share that meets the network target is a block. It can be detected twice: by the
+//! translator, that knows the worker that found it, and in JD mode by the mining downstream, that
+//! knows the template it was built on. Both detections are merged by block hash, and the record
+//! is appended as a JSON line to `block_store` each time it changes, the last line for a hash is
+//! the most complete.
+//!
+//! Once per block, after both detections had a chance to happen, the record is posted as JSON to
+//! `block_webhook` and written to the stdin of the `block_exec` command.
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    process::Stdio,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{
+    blockdata::{opcodes, script::Instruction},
+    consensus::serialize,
+    hashes::{sha256d, Hash},
+    BlockHash, BlockHeader, Transaction, TxMerkleNode,
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+};
+use tracing::{error, info, warn};
+
+use crate::config::Configuration;
+
+/// How long to wait for the other detection of a block before sending the notifications.
+const NOTIFY_DELAY: Duration = Duration::from_secs(2);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref BLOCKS: Mutex<HashMap<String, Block>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    /// Block hash, in the usual reversed hex
+    pub hash: String,
+    /// Serialized 80 bytes header, in hex
+    pub header: String,
+    /// Height committed to in the coinbase
+    pub height: Option<u64>,
+    /// Name the miner that found the block authorized with
+    pub worker: Option<String>,
+    /// Template the block was built on, in JD mode
+    pub template_id: Option<u64>,
+    /// Unix time the block was found at, in seconds
+    pub found_at: u64,
+}
+
+impl Block {
+    pub fn new(header: &BlockHeader, coinbase: &Transaction) -> Self {
+        Self {
+            hash: header.block_hash().to_string(),
+            header: serialize(header)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+            height: coinbase_height(coinbase),
+            worker: None,
+            template_id: None,
+            found_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Completes the record with what the other detection of the same block knows.
+    fn merge(&mut self, other: Block) {
+        self.height = self.height.or(other.height);
+        self.worker = self.worker.take().or(other.worker);
+        self.template_id = self.template_id.or(other.template_id);
+    }
+}
+
+/// Builds the header of a block from its coinbase and the merkle path of the other transactions.
+/// Returns `None` if `prev_hash` is not 32 bytes.
+pub fn header(
+    version: u32,
+    prev_hash: &[u8],
+    coinbase: &Transaction,
+    merkle_path: &[Vec<u8>],
+    time: u32,
+    bits: u32,
+    nonce: u32,
+) -> Option<BlockHeader> {
+    let prev_hash: [u8; 32] = prev_hash.try_into().ok()?;
+    let mut merkle_root = coinbase.txid().into_inner();
+    for node in merkle_path {
+        let mut concat = merkle_root.to_vec();
+        concat.extend_from_slice(node);
+        merkle_root = sha256d::Hash::hash(&concat).into_inner();
+    }
+    Some(BlockHeader {
+        version: version as i32,
+        prev_blockhash: BlockHash::from_inner(prev_hash),
+        merkle_root: TxMerkleNode::from_inner(merkle_root),
+        time,
+        bits,
+        nonce,
+    })
+}
+
+/// Returns true if the header hash meets the network target it commits to.
+pub fn meets_network_target(header: &BlockHeader) -> bool {
+    header.validate_pow(&header.target()).is_ok()
+}
+
+/// Height pushed first in the coinbase script (BIP34).
+fn coinbase_height(coinbase: &Transaction) -> Option<u64> {
+    let script_sig = &coinbase.input.first()?.script_sig;
+    match script_sig.instructions().next()? {
+        Ok(Instruction::PushBytes(bytes)) if !bytes.is_empty() && bytes.len() <= 8 => Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |height, byte| (height << 8) | *byte as u64),
+        ),
+        // Heights 1 to 16 are pushed with OP_1 to OP_16
+        Ok(Instruction::Op(op)) => {
+            let op = op.to_u8();
+            let first = opcodes::all::OP_PUSHNUM_1.to_u8();
+            let last = opcodes::all::OP_PUSHNUM_16.to_u8();
+            if (first..=last).contains(&op) {
+                Some((op - first + 1) as u64)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Records a block found and sends the notifications the first time it is seen.
+pub fn found(block: Block) {
+    let (block, first) = {
+        let mut blocks = BLOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        match blocks.get_mut(&block.hash) {
+            Some(known) => {
+                known.merge(block);
+                (known.clone(), false)
+            }
+            None => {
+                blocks.insert(block.hash.clone(), block.clone());
+                (block, true)
+            }
+        }
+    };
+    if first {
+        info!(
+            hash = %block.hash,
+            height = ?block.height,
+            worker = ?block.worker,
+            template_id = ?block.template_id,
+            "Block found"
+        );
+        crate::api::metrics::block_found();
+        tokio::spawn(notify(block.hash.clone()));
+    }
+    let path = Configuration::block_store();
+    if let Err(e) = append(&path, &block) {
+        error!(
+            "Can not record block {} in {}: {e}",
+            block.hash,
+            path.display()
+        );
+    }
+}
+
+fn append(path: &Path, block: &Block) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(block)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+async fn notify(hash: String) {
+    tokio::time::sleep(NOTIFY_DELAY).await;
+    let block = BLOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&hash)
+        .cloned();
+    if let Some(block) = block {
+        send_notifications(
+            &block,
+            Configuration::block_webhook(),
+            Configuration::block_exec(),
+        )
+        .await;
+    }
+}
+
+async fn send_notifications(block: &Block, webhook: Option<String>, exec: Option<String>) {
+    let body = match serde_json::to_string(block) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Can not serialize block {}: {e}", block.hash);
+            return;
+        }
+    };
+    let post = async {
+        let Some(url) = webhook else { return };
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            match post_json(&url, &body).await {
+                Ok(()) => {
+                    info!("Block {} posted to {url}", block.hash);
+                    return;
+                }
+                Err(e) => warn!(
+                    "Can not post block {} to {url} (attempt {attempt}/{WEBHOOK_ATTEMPTS}): {e}",
+                    block.hash
+                ),
+            }
+            if attempt < WEBHOOK_ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+        }
+    };
+    let run = async {
+        let Some(command) = exec else { return };
+        match run_exec(&command, block, &body).await {
+            Ok(()) => info!("Block {} notified to `{command}`", block.hash),
+            Err(e) => warn!("Can not notify block {} to `{command}`: {e}", block.hash),
+        }
+    };
+    tokio::join!(post, run);
+}
+
+/// Minimal HTTP/1.1 POST, only the status of the response is read.
+async fn post_json(url: &str, body: &str) -> Result<(), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are supported")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    };
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let response = tokio::time::timeout(WEBHOOK_TIMEOUT, async {
+        let mut stream = TcpStream::connect(&address).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        let mut buf = [0; 512];
+        while !response.windows(2).any(|w| w == b"\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(response)
+    })
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("unexpected response `{status_line}`")),
+    }
+}
+
+/// Runs `command` with the shell, the block is written as JSON to its stdin and its main fields
+/// are also set in the environment.
+async fn run_exec(command: &str, block: &Block, body: &str) -> Result<(), String> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    let mut child = cmd
+        .arg(command)
+        .env("BLOCK_HASH", &block.hash)
+        .env(
+            "BLOCK_HEIGHT",
+            block.height.map(|h| h.to_string()).unwrap_or_default(),
+        )
+        .env("BLOCK_WORKER", block.worker.clone().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command does not have to read it
+        if let Err(e) = stdin.write_all(body.as_bytes()).await {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(e.to_string());
+            }
+        }
+    }
+    let status = tokio::time::timeout(EXEC_TIMEOUT, child.wait())
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("exited with {status}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{blockdata::constants::genesis_block, Network};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn builds_the_header_of_a_block() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = &genesis.txdata[0];
+        let header = header(
+            genesis.header.version as u32,
+            &genesis.header.prev_blockhash.into_inner(),
+            coinbase,
+            &[],
+            genesis.header.time,
+            genesis.header.bits,
+            genesis.header.nonce,
+        )
+        .unwrap();
+        assert_eq!(header, genesis.header);
+        assert!(meets_network_target(&header));
+        let block = Block::new(&header, coinbase);
+        assert_eq!(
+            block.hash,
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(block.header.len(), 160);
+
+        let mut header = header;
+        header.nonce += 1;
+        assert!(!meets_network_target(&header));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn posts_the_block_to_the_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/blocks", listener.local_addr().unwrap());
+        // Stands in for the webhook, captures the request
+        let webhook = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let output = std::env::temp_dir().join(format!("demand-cli-block-{}", std::process::id()));
+        let block = Block {
+            hash: "00ab".to_string(),
+            height: Some(840_000),
+            worker: Some("worker.1".to_string()),
+            template_id: Some(7),
+            ..Default::default()
+        };
+        let exec = format!("cat > {}", output.display());
+
+        send_notifications(&block, Some(url), Some(exec)).await;
+
+        let request = webhook.await.unwrap();
+        assert!(request.starts_with("POST /blocks HTTP/1.1\r\n"));
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(serde_json::from_str::<Block>(body).unwrap(), block);
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Block>(written.trim()).unwrap(),
+            block
+        );
+        std::fs::remove_file(&output).unwrap();
+    }
+}
@@ -0,0 +1,990 @@
+//! Runtime configuration.
+//!
+//! Every knob can be set in a TOML file passed with `--config`. Values are resolved in this
+//! order: CLI flags, environment variables, the configuration file and finally the built-in
+//! defaults.
+//!
+//! Any number of upstream pools can be given with `[[pools]]` tables (or `--pool` on the CLI),
+//! the router picks among them. `token` and `auth_pub_key` at the top level are used for the
+//! pools that do not set their own. When no pool is given `pool_address` is used.
+//!
+//! `pool_policy` tells how the router chooses among the pools: `latency` (the default),
+//! `priority` or `weighted`, see [`crate::router::policy`]. With `split` the proxy mines on all
+//! the pools at once, their weights are the share of the miners each one gets, see
+//! [`crate::split`]. A pool's `priority` defaults to its position in the list, lowest first, and
+//! its `weight` to 1. Pools given with `--pool` can only be ordered. The pools are probed with
+//! `probe_mode`: `cheap` (the default) only sets up a connection, `full` also opens a channel
+//! and gets a JD token like the proxy does.
+//!
+//! Pool addresses are kept as `host:port`, a host that does not resolve is not a configuration
+//! error. The router resolves them at each reconnection and each check of the upstreams, every
+//! address returned is a candidate, so a pool behind a load balancer whose addresses change is
+//! followed.
+//!
+//! On SIGHUP the configuration is read again and applied without restarting the proxy when
+//! possible, see [`ConfigChanges`].
+//!
+//! ```toml
+//! token = "my-token"
+//! tp_address = "127.0.0.1:8442"
+//! listen_address = "0.0.0.0:32767"
+//! auth_pub_key = "9bQHWXsQ2J9TRFTaxRh3KjoxdyLRfWVEy25YHtKF8y8gotLoCZZ"
+//! downstream_hashrate = "100T"
+//! shares_per_minute = 10.0
+//! channel_diff_update_interval = 10
+//! max_len_down_msg = 10000
+//! min_extranonce2_size = 5
+//! loglevel = "info"
+//! nc_loglevel = "off"
+//! log_format = "json"
+//! log_file = "/var/log/demand-cli/proxy.log"
+//! log_max_size_mb = 100
+//! log_rotation = "daily"
+//! log_max_files = 7
+//! api_port = 9090
+//! api_address = "127.0.0.1"
+//! share_ledger = "/var/lib/demand-cli/shares.jsonl"
+//! reconcile_interval_secs = 600
+//! ack_timeout_secs = 60
+//! lost_shares_alert_threshold = 10
+//! block_store = "/var/lib/demand-cli/blocks.jsonl"
+//! block_webhook = "http://127.0.0.1:8080/blocks"
+//! block_exec = "/usr/local/bin/notify-block"
+//! pool_policy = "priority"
+//! fail_back_after_secs = 300
+//! probe_mode = "cheap"
+//!
+//! [[pools]]
+//! address = "mining.dmnd.work:2000"
+//! priority = 0
+//! weight = 4
+//!
+//! [[pools]]
+//! address = "staging.example.com:2000"
+//! priority = 1
+//! auth_pub_key = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+//! token = "staging-token"
+//! ```
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+use key_utils::Secp256k1PublicKey;
+use lazy_static::lazy_static;
+use noise_sv2::Initiator;
+use serde::Deserialize;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    logging::{LogFormat, LogRotation},
+    router::{policy::PoolPolicy, ProbeMode},
+    Args,
+};
+
+pub const DEFAULT_SV1_HASHPOWER: f32 = 100_000_000_000_000.0;
+pub const DEFAULT_SHARES_PER_MINUTE: f32 = 10.0;
+pub const DEFAULT_CHANNEL_DIFF_UPDATE_INTERVAL: u32 = 10;
+pub const DEFAULT_MAX_LEN_DOWN_MSG: u32 = 10000;
+pub const DEFAULT_MIN_EXTRANONCE2_SIZE: u16 = 5;
+pub const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:32767";
+/// The HTTP API shows worker names and miner addresses, it is local unless asked otherwise
+pub const DEFAULT_API_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 100;
+pub const DEFAULT_LOG_MAX_FILES: usize = 7;
+pub const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 600;
+pub const DEFAULT_ACK_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_LOST_SHARES_ALERT_THRESHOLD: usize = 10;
+pub const DEFAULT_BLOCK_STORE: &str = "blocks.jsonl";
+pub const DEFAULT_FAIL_BACK_AFTER_SECS: u64 = 300;
+const MAIN_POOL_ADDRESS: &str = "mining.dmnd.work:2000";
+const TEST_POOL_ADDRESS: &str =
+    "k8s-default-pool-de2d9b37ea-6bc40843aed871f2.elb.eu-central-1.amazonaws.com:2000";
+const MAIN_AUTH_PUB_KEY: &str = "9bQHWXsQ2J9TRFTaxRh3KjoxdyLRfWVEy25YHtKF8y8gotLoCZZ";
+const TEST_AUTH_PUB_KEY: &str = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72";
+
+lazy_static! {
+    static ref CONFIG: RwLock<Configuration> =
+        RwLock::new(Configuration::load(&crate::ARGS).unwrap_or_else(|e| {
+            // Logging is configured from this very struct, so it is not available yet
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1)
+        }));
+}
+
+/// Runs `f` on the current configuration.
+fn with_config<T>(f: impl FnOnce(&Configuration) -> T) -> T {
+    // The configuration is only ever replaced as a whole, so a poisoned lock still holds a
+    // consistent value.
+    let config = CONFIG.read().unwrap_or_else(|e| e.into_inner());
+    f(&config)
+}
+
+/// Content of the TOML configuration file. Every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    token: Option<String>,
+    tp_address: Option<String>,
+    listen_address: Option<String>,
+    test: Option<bool>,
+    pool_address: Option<String>,
+    auth_pub_key: Option<String>,
+    downstream_hashrate: Option<String>,
+    shares_per_minute: Option<f32>,
+    channel_diff_update_interval: Option<u32>,
+    max_len_down_msg: Option<u32>,
+    min_extranonce2_size: Option<u16>,
+    loglevel: Option<String>,
+    nc_loglevel: Option<String>,
+    log_format: Option<String>,
+    log_file: Option<PathBuf>,
+    log_max_size_mb: Option<u64>,
+    log_rotation: Option<String>,
+    log_max_files: Option<usize>,
+    api_port: Option<u16>,
+    api_address: Option<IpAddr>,
+    share_ledger: Option<PathBuf>,
+    reconcile_interval_secs: Option<u64>,
+    ack_timeout_secs: Option<u64>,
+    lost_shares_alert_threshold: Option<usize>,
+    block_store: Option<PathBuf>,
+    block_webhook: Option<String>,
+    block_exec: Option<String>,
+    pool_policy: Option<String>,
+    fail_back_after_secs: Option<u64>,
+    probe_mode: Option<String>,
+    pools: Vec<PoolEntry>,
+}
+
+/// A `[[pools]]` table, or a `--pool` CLI argument.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolEntry {
+    address: String,
+    auth_pub_key: Option<String>,
+    token: Option<String>,
+    /// Lower is preferred
+    priority: Option<u32>,
+    weight: Option<u32>,
+}
+
+impl PoolEntry {
+    /// Parses `<address>[,<auth_pub_key>[,<token>]]`
+    fn from_arg(arg: &str) -> Result<Self, String> {
+        let mut parts = arg.split(',').map(str::trim);
+        let address = match parts.next() {
+            Some(address) if !address.is_empty() => address.to_string(),
+            _ => {
+                return Err(format!(
+                    "Invalid pool '{arg}': expected <address>[,<auth_pub_key>[,<token>]]"
+                ))
+            }
+        };
+        let auth_pub_key = parts.next().filter(|k| !k.is_empty()).map(str::to_string);
+        let token = parts.next().filter(|t| !t.is_empty()).map(str::to_string);
+        if parts.next().is_some() {
+            return Err(format!(
+                "Invalid pool '{arg}': expected <address>[,<auth_pub_key>[,<token>]]"
+            ));
+        }
+        Ok(Self {
+            address,
+            auth_pub_key,
+            token,
+            priority: None,
+            weight: None,
+        })
+    }
+
+    /// `index` is the position of the pool in the list, its default priority.
+    fn resolve(
+        self,
+        index: usize,
+        default_auth_pub_key: &str,
+        default_token: Option<&str>,
+    ) -> Result<PoolConfig, String> {
+        // Only checked here, the router resolves the host when it selects a pool
+        match self.address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+            _ => {
+                return Err(format!(
+                    "Invalid pool address '{}': expected <host>:<port>",
+                    self.address
+                ))
+            }
+        }
+        let auth_pub_key = self.auth_pub_key.as_deref().unwrap_or(default_auth_pub_key);
+        let auth_pub_key: Secp256k1PublicKey = auth_pub_key
+            .parse()
+            .map_err(|_| format!("Invalid authority public key '{auth_pub_key}'"))?;
+        // The noise handshakes with the pool rely on it, they do not check it again
+        Initiator::from_raw_k(auth_pub_key.into_bytes()).map_err(|e| {
+            format!(
+                "Invalid authority public key for pool '{}': {e:?}",
+                self.address
+            )
+        })?;
+        let token = self
+            .token
+            .or(default_token.map(str::to_string))
+            .ok_or(format!(
+                "Missing token for pool '{}': use --token, the TOKEN environment variable or `token` in the config file",
+                self.address
+            ))?;
+        let weight = self.weight.unwrap_or(1);
+        if weight == 0 {
+            return Err(format!(
+                "Invalid weight for pool '{}': must be greater than 0",
+                self.address
+            ));
+        }
+        Ok(PoolConfig {
+            host: self.address,
+            auth_pub_key,
+            token,
+            priority: self.priority.unwrap_or(index as u32),
+            weight,
+        })
+    }
+}
+
+/// An upstream pool the router can connect to.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Address as configured, `<host>:<port>`. The router resolves it when it selects a pool,
+    /// see [`crate::router::resolve_pool`].
+    pub host: String,
+    pub auth_pub_key: Secp256k1PublicKey,
+    /// Token used to authenticate with this pool
+    pub token: String,
+    /// Rank of the pool with the `priority` policy, lower is preferred
+    pub priority: u32,
+    /// Share of the connections with the `weighted` policy
+    pub weight: u32,
+}
+
+impl PoolConfig {
+    /// Whether a connection to `self` is also a connection to `other`: only the way the router
+    /// ranks them differs.
+    pub fn same_upstream(&self, other: &Self) -> bool {
+        self.host == other.host
+            && self.token == other.token
+            && self.auth_pub_key.into_bytes() == other.auth_pub_key.into_bytes()
+    }
+}
+
+impl PartialEq for PoolConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.same_upstream(other) && self.priority == other.priority && self.weight == other.weight
+    }
+}
+
+/// What changed after a configuration reload.
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    /// The pool list changed, the router needs the new one.
+    pub pools: bool,
+    /// Changed settings that are picked up by the running proxy.
+    pub live: Vec<&'static str>,
+    /// Changed settings that are only read when the proxy is initialized.
+    pub restart: Vec<&'static str>,
+    /// Changed settings that need the process to be restarted.
+    pub ignored: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    fn new(old: &Configuration, new: &Configuration) -> Self {
+        let mut changes = Self {
+            pools: old.pools != new.pools,
+            ..Default::default()
+        };
+        // Read by the downstreams and the upstream channel at their next difficulty update, or
+        // when a miner connects
+        if old.shares_per_minute != new.shares_per_minute {
+            changes.live.push("shares_per_minute");
+        }
+        if old.channel_diff_update_interval != new.channel_diff_update_interval {
+            changes.live.push("channel_diff_update_interval");
+        }
+        if old.downstream_hashrate != new.downstream_hashrate {
+            changes.live.push("downstream_hashrate");
+        }
+        if old.max_len_down_msg != new.max_len_down_msg {
+            changes.live.push("max_len_down_msg");
+        }
+        if old.reconcile_interval_secs != new.reconcile_interval_secs {
+            changes.live.push("reconcile_interval_secs");
+        }
+        if old.ack_timeout_secs != new.ack_timeout_secs {
+            changes.live.push("ack_timeout_secs");
+        }
+        if old.lost_shares_alert_threshold != new.lost_shares_alert_threshold {
+            changes.live.push("lost_shares_alert_threshold");
+        }
+        // Read when a block is found
+        if old.block_webhook != new.block_webhook {
+            changes.live.push("block_webhook");
+        }
+        if old.block_exec != new.block_exec {
+            changes.live.push("block_exec");
+        }
+        // Read by the router at its next upstream check
+        if old.pool_policy != new.pool_policy {
+            changes.live.push("pool_policy");
+        }
+        if old.fail_back_after_secs != new.fail_back_after_secs {
+            changes.live.push("fail_back_after_secs");
+        }
+        if old.probe_mode != new.probe_mode {
+            changes.live.push("probe_mode");
+        }
+        if old.tp_address != new.tp_address {
+            changes.restart.push("tp_address");
+        }
+        if old.listen_address != new.listen_address {
+            changes.restart.push("listen_address");
+        }
+        if old.min_extranonce2_size != new.min_extranonce2_size {
+            changes.restart.push("min_extranonce2_size");
+        }
+        if old.loglevel != new.loglevel {
+            changes.ignored.push("loglevel");
+        }
+        if old.nc_loglevel != new.nc_loglevel {
+            changes.ignored.push("nc_loglevel");
+        }
+        if old.log_format != new.log_format
+            || old.log_file != new.log_file
+            || old.log_max_size_mb != new.log_max_size_mb
+            || old.log_rotation != new.log_rotation
+            || old.log_max_files != new.log_max_files
+        {
+            changes.ignored.push("log file settings");
+        }
+        if old.api_port != new.api_port {
+            changes.ignored.push("api_port");
+        }
+        if old.api_address != new.api_address {
+            changes.ignored.push("api_address");
+        }
+        if old.share_ledger != new.share_ledger {
+            changes.ignored.push("share_ledger");
+        }
+        if old.block_store != new.block_store {
+            changes.ignored.push("block_store");
+        }
+        changes
+    }
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can not read {}: {e}", path.display()))?;
+        toml::from_str(&content).map_err(|e| format!("Can not parse {}: {e}", path.display()))
+    }
+}
+
+/// Resolved configuration used by every component of the proxy.
+#[derive(Debug, Clone)]
+pub struct Configuration {
+    tp_address: Option<String>,
+    listen_address: String,
+    test: bool,
+    pools: Vec<PoolConfig>,
+    downstream_hashrate: Option<f32>,
+    shares_per_minute: f32,
+    channel_diff_update_interval: u32,
+    max_len_down_msg: u32,
+    min_extranonce2_size: u16,
+    loglevel: String,
+    nc_loglevel: String,
+    log_format: LogFormat,
+    log_file: Option<PathBuf>,
+    log_max_size_mb: u64,
+    log_rotation: LogRotation,
+    log_max_files: usize,
+    api_port: Option<u16>,
+    api_address: IpAddr,
+    share_ledger: Option<PathBuf>,
+    reconcile_interval_secs: u64,
+    ack_timeout_secs: u64,
+    lost_shares_alert_threshold: usize,
+    block_store: PathBuf,
+    block_webhook: Option<String>,
+    block_exec: Option<String>,
+    pool_policy: PoolPolicy,
+    fail_back_after_secs: u64,
+    probe_mode: ProbeMode,
+}
+
+impl Configuration {
+    fn load(args: &Args) -> Result<Self, String> {
+        let file = match &args.config {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+        Self::resolve(args, file, |key| std::env::var(key).ok())
+    }
+
+    /// Loads the configuration without making it the current one, to report errors.
+    pub fn check() -> Result<(), String> {
+        Self::load(&crate::ARGS).map(|_| ())
+    }
+
+    /// Reads the configuration again and replaces the current one. On error the current
+    /// configuration is kept.
+    pub fn reload() -> Result<ConfigChanges, String> {
+        let new = Self::load(&crate::ARGS)?;
+        let mut config = CONFIG.write().unwrap_or_else(|e| e.into_inner());
+        let changes = ConfigChanges::new(&config, &new);
+        *config = new;
+        Ok(changes)
+    }
+
+    /// Returns a receiver that gets a message every time the process receives a SIGHUP.
+    pub fn reload_signal() -> Receiver<()> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    tracing::error!("Impossible to listen for SIGHUP: {e}");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                // If a reload is already pending there is nothing to add
+                let _ = sender.try_send(());
+            }
+        });
+        #[cfg(not(unix))]
+        drop(sender);
+        receiver
+    }
+
+    /// Merges the CLI arguments, the environment (looked up with `env`) and the configuration
+    /// file, in this order of precedence.
+    fn resolve(
+        args: &Args,
+        file: ConfigFile,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let test = args.test.or(file.test).unwrap_or(false);
+
+        let tp_address = args
+            .tp_address
+            .clone()
+            .or_else(|| env("TP_ADDRESS"))
+            .or(file.tp_address);
+
+        let listen_address = args
+            .listen_address
+            .clone()
+            .or_else(|| env("SV1_DOWN_LISTEN_ADDR"))
+            .or(file.listen_address)
+            .unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string());
+        listen_address
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("Invalid listen address '{listen_address}'"))?;
+
+        let token = args.token.clone().or_else(|| env("TOKEN")).or(file.token);
+        let auth_pub_key = args
+            .auth_pub_key
+            .clone()
+            .or(file.auth_pub_key)
+            .unwrap_or_else(|| match test {
+                true => TEST_AUTH_PUB_KEY.to_string(),
+                false => MAIN_AUTH_PUB_KEY.to_string(),
+            });
+        let pool_entries = if !args.pools.is_empty() {
+            args.pools
+                .iter()
+                .map(|pool| PoolEntry::from_arg(pool))
+                .collect::<Result<Vec<_>, _>>()?
+        } else if let Some(address) = args.pool_address.clone() {
+            vec![PoolEntry::from_arg(&address)?]
+        } else if !file.pools.is_empty() {
+            file.pools
+        } else {
+            let address = file.pool_address.unwrap_or_else(|| match test {
+                true => TEST_POOL_ADDRESS.to_string(),
+                false => MAIN_POOL_ADDRESS.to_string(),
+            });
+            vec![PoolEntry::from_arg(&address)?]
+        };
+        let mut pools: Vec<PoolConfig> = Vec::with_capacity(pool_entries.len());
+        for (index, entry) in pool_entries.into_iter().enumerate() {
+            let pool = entry.resolve(index, &auth_pub_key, token.as_deref())?;
+            if pools.iter().any(|p| p.host == pool.host) {
+                return Err(format!("Pool {} is configured more than once", pool.host));
+            }
+            pools.push(pool);
+        }
+
+        let downstream_hashrate = match args.downstream_hashrate {
+            Some(hashrate) => Some(hashrate),
+            None => file
+                .downstream_hashrate
+                .as_deref()
+                .map(crate::parse_hashrate)
+                .transpose()?,
+        };
+
+        let shares_per_minute = args
+            .shares_per_minute
+            .or(file.shares_per_minute)
+            .unwrap_or(DEFAULT_SHARES_PER_MINUTE);
+        if shares_per_minute <= 0.0 || !shares_per_minute.is_finite() {
+            return Err(format!(
+                "shares_per_minute must be a positive number, got {shares_per_minute}"
+            ));
+        }
+
+        let log_format = args
+            .log_format
+            .clone()
+            .or(file.log_format)
+            .map(|format| format.parse())
+            .transpose()?
+            .unwrap_or_default();
+        let log_rotation = args
+            .log_rotation
+            .clone()
+            .or(file.log_rotation)
+            .map(|rotation| rotation.parse())
+            .transpose()?
+            .unwrap_or_default();
+        let log_max_size_mb = args
+            .log_max_size_mb
+            .or(file.log_max_size_mb)
+            .unwrap_or(DEFAULT_LOG_MAX_SIZE_MB);
+        if log_max_size_mb == 0 {
+            return Err("log_max_size_mb must be greater than 0".to_string());
+        }
+        let reconcile_interval_secs = args
+            .reconcile_interval_secs
+            .or(file.reconcile_interval_secs)
+            .unwrap_or(DEFAULT_RECONCILE_INTERVAL_SECS);
+        if reconcile_interval_secs == 0 {
+            return Err("reconcile_interval_secs must be greater than 0".to_string());
+        }
+        let ack_timeout_secs = args
+            .ack_timeout_secs
+            .or(file.ack_timeout_secs)
+            .unwrap_or(DEFAULT_ACK_TIMEOUT_SECS);
+        if ack_timeout_secs == 0 {
+            return Err("ack_timeout_secs must be greater than 0".to_string());
+        }
+        let block_webhook = args.block_webhook.clone().or(file.block_webhook);
+        if let Some(url) = &block_webhook {
+            if !url.starts_with("http://") {
+                return Err(format!("block_webhook must be an http:// URL, got {url}"));
+            }
+        }
+        let pool_policy = args
+            .pool_policy
+            .clone()
+            .or(file.pool_policy)
+            .map(|policy| policy.parse())
+            .transpose()?
+            .unwrap_or_default();
+        let probe_mode = args
+            .probe_mode
+            .clone()
+            .or(file.probe_mode)
+            .map(|mode| mode.parse())
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            tp_address,
+            listen_address,
+            test,
+            pools,
+            downstream_hashrate,
+            shares_per_minute,
+            channel_diff_update_interval: args
+                .channel_diff_update_interval
+                .or(file.channel_diff_update_interval)
+                .unwrap_or(DEFAULT_CHANNEL_DIFF_UPDATE_INTERVAL),
+            max_len_down_msg: args
+                .max_len_down_msg
+                .or(file.max_len_down_msg)
+                .unwrap_or(DEFAULT_MAX_LEN_DOWN_MSG),
+            min_extranonce2_size: args
+                .min_extranonce2_size
+                .or(file.min_extranonce2_size)
+                .unwrap_or(DEFAULT_MIN_EXTRANONCE2_SIZE),
+            loglevel: args
+                .loglevel
+                .clone()
+                .or(file.loglevel)
+                .unwrap_or("info".to_string()),
+            nc_loglevel: args
+                .noise_connection_log
+                .clone()
+                .or(file.nc_loglevel)
+                .unwrap_or("off".to_string()),
+            log_format,
+            log_file: args.log_file.clone().or(file.log_file),
+            log_max_size_mb,
+            log_rotation,
+            log_max_files: args
+                .log_max_files
+                .or(file.log_max_files)
+                .unwrap_or(DEFAULT_LOG_MAX_FILES),
+            api_port: args.api_port.or(file.api_port),
+            api_address: args
+                .api_address
+                .or(file.api_address)
+                .unwrap_or(DEFAULT_API_ADDRESS),
+            share_ledger: args.share_ledger.clone().or(file.share_ledger),
+            reconcile_interval_secs,
+            ack_timeout_secs,
+            lost_shares_alert_threshold: args
+                .lost_shares_alert_threshold
+                .or(file.lost_shares_alert_threshold)
+                .unwrap_or(DEFAULT_LOST_SHARES_ALERT_THRESHOLD),
+            block_store: args
+                .block_store
+                .clone()
+                .or(file.block_store)
+                .unwrap_or(PathBuf::from(DEFAULT_BLOCK_STORE)),
+            block_webhook,
+            block_exec: args.block_exec.clone().or(file.block_exec),
+            pool_policy,
+            fail_back_after_secs: args
+                .fail_back_after_secs
+                .or(file.fail_back_after_secs)
+                .unwrap_or(DEFAULT_FAIL_BACK_AFTER_SECS),
+            probe_mode,
+        })
+    }
+
+    /// Template Provider address, if the proxy should run in JD mode.
+    pub fn tp_address() -> Option<String> {
+        with_config(|c| c.tp_address.clone())
+    }
+
+    /// Address where the proxy listens for SV1 downstreams.
+    pub fn listen_address() -> String {
+        with_config(|c| c.listen_address.clone())
+    }
+
+    pub fn test() -> bool {
+        with_config(|c| c.test)
+    }
+
+    /// Upstream pools the router can choose from, never empty.
+    pub fn pools() -> Vec<PoolConfig> {
+        with_config(|c| c.pools.clone())
+    }
+
+    /// Returns true if the expected downstream hashrate has been set by the user.
+    pub fn has_downstream_hashrate() -> bool {
+        with_config(|c| c.downstream_hashrate.is_some())
+    }
+
+    /// Expected hashrate of each SV1 downstream in h/s.
+    pub fn downstream_hashrate() -> f32 {
+        with_config(|c| c.downstream_hashrate).unwrap_or(DEFAULT_SV1_HASHPOWER)
+    }
+
+    pub fn shares_per_minute() -> f32 {
+        with_config(|c| c.shares_per_minute)
+    }
+
+    pub fn channel_diff_update_interval() -> u32 {
+        with_config(|c| c.channel_diff_update_interval)
+    }
+
+    pub fn max_len_down_msg() -> u32 {
+        with_config(|c| c.max_len_down_msg)
+    }
+
+    pub fn min_extranonce2_size() -> u16 {
+        with_config(|c| c.min_extranonce2_size)
+    }
+
+    pub fn loglevel() -> String {
+        with_config(|c| c.loglevel.clone())
+    }
+
+    pub fn nc_loglevel() -> String {
+        with_config(|c| c.nc_loglevel.clone())
+    }
+
+    pub fn log_format() -> LogFormat {
+        with_config(|c| c.log_format)
+    }
+
+    /// File the logs are written to, in addition to stdout.
+    pub fn log_file() -> Option<PathBuf> {
+        with_config(|c| c.log_file.clone())
+    }
+
+    pub fn log_max_size_mb() -> u64 {
+        with_config(|c| c.log_max_size_mb)
+    }
+
+    pub fn log_rotation() -> LogRotation {
+        with_config(|c| c.log_rotation)
+    }
+
+    pub fn log_max_files() -> usize {
+        with_config(|c| c.log_max_files)
+    }
+
+    /// Port of the HTTP API, if enabled.
+    pub fn api_port() -> Option<u16> {
+        with_config(|c| c.api_port)
+    }
+
+    /// Address the HTTP API listens on.
+    pub fn api_address() -> IpAddr {
+        with_config(|c| c.api_address)
+    }
+
+    /// File every share sent to the pool is appended to, if enabled.
+    pub fn share_ledger() -> Option<PathBuf> {
+        with_config(|c| c.share_ledger.clone())
+    }
+
+    /// How often the shares of the ledger are compared with the pool's window.
+    pub fn reconcile_interval() -> Duration {
+        with_config(|c| Duration::from_secs(c.reconcile_interval_secs))
+    }
+
+    /// How long the pool has to acknowledge a share before it is counted as lost.
+    pub fn ack_timeout() -> Duration {
+        with_config(|c| Duration::from_secs(c.ack_timeout_secs))
+    }
+
+    /// Number of shares lost in 10 minutes that raises an alert, 0 disables it.
+    pub fn lost_shares_alert_threshold() -> usize {
+        with_config(|c| c.lost_shares_alert_threshold)
+    }
+
+    /// File the blocks found are recorded in.
+    pub fn block_store() -> PathBuf {
+        with_config(|c| c.block_store.clone())
+    }
+
+    /// URL a JSON description of each block found is posted to, if set.
+    pub fn block_webhook() -> Option<String> {
+        with_config(|c| c.block_webhook.clone())
+    }
+
+    /// Command run with a JSON description of each block found on stdin, if set.
+    pub fn block_exec() -> Option<String> {
+        with_config(|c| c.block_exec.clone())
+    }
+
+    /// How the router chooses among the pools.
+    pub fn pool_policy() -> PoolPolicy {
+        with_config(|c| c.pool_policy)
+    }
+
+    /// How long a pool of higher priority must be reachable before the proxy goes back to it.
+    pub fn fail_back_after() -> Duration {
+        with_config(|c| Duration::from_secs(c.fail_back_after_secs))
+    }
+
+    /// How the latency of the pools is measured.
+    pub fn probe_mode() -> ProbeMode {
+        with_config(|c| c.probe_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn parses_every_field_from_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            token = "file-token"
+            tp_address = "127.0.0.1:8442"
+            listen_address = "127.0.0.1:3333"
+            pool_address = "127.0.0.1:2000"
+            downstream_hashrate = "10T"
+            shares_per_minute = 6.0
+            channel_diff_update_interval = 30
+            max_len_down_msg = 500
+            min_extranonce2_size = 8
+            loglevel = "debug"
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["demand-cli"]);
+        let config = Configuration::resolve(&args, file, no_env).unwrap();
+
+        assert_eq!(config.tp_address.as_deref(), Some("127.0.0.1:8442"));
+        assert_eq!(config.listen_address, "127.0.0.1:3333");
+        assert_eq!(config.pools.len(), 1);
+        assert_eq!(config.pools[0].host, "127.0.0.1:2000");
+        assert_eq!(config.pools[0].token, "file-token");
+        assert_eq!(config.downstream_hashrate, Some(10e12));
+        assert_eq!(config.shares_per_minute, 6.0);
+        assert_eq!(config.channel_diff_update_interval, 30);
+        assert_eq!(config.max_len_down_msg, 500);
+        assert_eq!(config.min_extranonce2_size, 8);
+        assert_eq!(config.loglevel, "debug");
+        assert_eq!(config.nc_loglevel, "off");
+    }
+
+    #[test]
+    fn cli_and_env_override_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            token = "file-token"
+            tp_address = "127.0.0.1:8442"
+            pool_address = "127.0.0.1:2000"
+            shares_per_minute = 6.0
+            test = true
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["demand-cli", "--shares-per-minute", "20", "--test=false"]);
+        let env = |key: &str| match key {
+            "TOKEN" => Some("env-token".to_string()),
+            _ => None,
+        };
+        let config = Configuration::resolve(&args, file, env).unwrap();
+
+        assert_eq!(config.pools[0].token, "env-token");
+        assert_eq!(config.tp_address.as_deref(), Some("127.0.0.1:8442"));
+        assert_eq!(config.shares_per_minute, 20.0);
+        assert!(!config.test);
+    }
+
+    #[test]
+    fn parses_multiple_pools() {
+        let file: ConfigFile = toml::from_str(&format!(
+            r#"
+            token = "default-token"
+
+            [[pools]]
+            address = "127.0.0.1:2000"
+
+            [[pools]]
+            address = "127.0.0.2:2000"
+            auth_pub_key = "{TEST_AUTH_PUB_KEY}"
+            token = "staging-token"
+            priority = 0
+            weight = 3
+            "#
+        ))
+        .unwrap();
+        let args = Args::parse_from(["demand-cli", "--pool-policy", "priority"]);
+        let config = Configuration::resolve(&args, file, no_env).unwrap();
+
+        assert_eq!(config.pool_policy, PoolPolicy::Priority);
+        assert_eq!(config.fail_back_after_secs, DEFAULT_FAIL_BACK_AFTER_SECS);
+        assert_eq!(config.probe_mode, ProbeMode::Cheap);
+        assert_eq!(config.pools.len(), 2);
+        assert_eq!(config.pools[0].token, "default-token");
+        assert_eq!((config.pools[0].priority, config.pools[0].weight), (0, 1));
+        assert_eq!(config.pools[1].host, "127.0.0.2:2000");
+        assert_eq!(config.pools[1].token, "staging-token");
+        assert_eq!((config.pools[1].priority, config.pools[1].weight), (0, 3));
+
+        // Pools given on the CLI replace the ones in the file
+        let file: ConfigFile =
+            toml::from_str("[[pools]]\naddress = \"127.0.0.1:2000\"\ntoken = \"t\"").unwrap();
+        let args = Args::parse_from([
+            "demand-cli",
+            "--pool",
+            "127.0.0.3:2000,,cli-token",
+            "--pool",
+            &format!("127.0.0.4:2000,{TEST_AUTH_PUB_KEY},other-token"),
+        ]);
+        let config = Configuration::resolve(&args, file, no_env).unwrap();
+        assert_eq!(config.pools.len(), 2);
+        assert_eq!(config.pools[0].host, "127.0.0.3:2000");
+        assert_eq!(config.pools[0].token, "cli-token");
+        assert_eq!(config.pools[1].token, "other-token");
+        assert_eq!(config.pools[1].priority, 1);
+
+        // Hosts are not resolved with the configuration, only the port is checked
+        let args = Args::parse_from(["demand-cli", "--pool", "pool.invalid:2000,,t"]);
+        let config = Configuration::resolve(&args, ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.pools[0].host, "pool.invalid:2000");
+        let args = Args::parse_from(["demand-cli", "--pool", "pool.invalid,,t"]);
+        assert!(Configuration::resolve(&args, ConfigFile::default(), no_env).is_err());
+    }
+
+    #[test]
+    fn classifies_reload_changes() {
+        let args = Args::parse_from(["demand-cli", "--token", "t"]);
+        let old_file = "pool_address = \"127.0.0.1:2000\"\nshares_per_minute = 6.0";
+        let old = Configuration::resolve(&args, toml::from_str(old_file).unwrap(), no_env);
+        let old = old.unwrap();
+
+        let changes = ConfigChanges::new(&old, &old.clone());
+        assert!(!changes.pools && changes.live.is_empty() && changes.restart.is_empty());
+
+        let new_file = r#"
+            shares_per_minute = 12.0
+            tp_address = "127.0.0.1:8442"
+
+            [[pools]]
+            address = "127.0.0.1:2000"
+
+            [[pools]]
+            address = "127.0.0.2:2000"
+            "#;
+        let new = Configuration::resolve(&args, toml::from_str(new_file).unwrap(), no_env);
+        let changes = ConfigChanges::new(&old, &new.unwrap());
+        assert!(changes.pools);
+        assert_eq!(changes.live, vec!["shares_per_minute"]);
+        assert_eq!(changes.restart, vec!["tp_address"]);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let args = Args::parse_from(["demand-cli", "--pool-address", "127.0.0.1:2000"]);
+        assert!(Configuration::resolve(&args, ConfigFile::default(), no_env).is_err());
+
+        let args = Args::parse_from([
+            "demand-cli",
+            "--token",
+            "t",
+            "--pool",
+            "127.0.0.1:2000",
+            "--pool",
+            "127.0.0.1:2000",
+        ]);
+        assert!(Configuration::resolve(&args, ConfigFile::default(), no_env).is_err());
+
+        let args = Args::parse_from(["demand-cli", "--pool-address", "127.0.0.1:2000"]);
+        let file: ConfigFile = toml::from_str("token = \"t\"\nlisten_address = \"nope\"").unwrap();
+        assert!(Configuration::resolve(&args, file, no_env).is_err());
+
+        assert!(toml::from_str::<ConfigFile>("unknown_key = 1").is_err());
+
+        let file: ConfigFile = toml::from_str("token = \"t\"\nlog_format = \"xml\"").unwrap();
+        assert!(Configuration::resolve(&args, file, no_env).is_err());
+
+        let file: ConfigFile = toml::from_str("token = \"t\"\npool_policy = \"random\"").unwrap();
+        assert!(Configuration::resolve(&args, file, no_env).is_err());
+
+        let file: ConfigFile =
+            toml::from_str("[[pools]]\naddress = \"127.0.0.1:2000\"\ntoken = \"t\"\nweight = 0")
+                .unwrap();
+        let args = Args::parse_from(["demand-cli"]);
+        assert!(Configuration::resolve(&args, file, no_env).is_err());
+    }
+}
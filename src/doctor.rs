@@ -0,0 +1,251 @@
+//! `demand-cli doctor` runs each startup step on its own and prints a pass/fail report, so that
+//! a bad token, a firewall or a dead TP can be told apart.
+use std::{
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use codec_sv2::HandshakeRole;
+use demand_share_accounting_ext::parser::PoolExtMessages;
+use demand_sv2_connection::noise_connection_tokio::Connection;
+use noise_sv2::Initiator;
+use roles_logic_sv2::parsers::Mining;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    config::{Configuration, PoolConfig},
+    jd_client::template_receiver::setup_connection::SetupConnectionHandler as TpSetupConnection,
+    minin_pool_connection::{
+        get_mining_setup_connection_msg, mining_setup_connection, relay_down, relay_up,
+    },
+};
+
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs all the checks and returns the process exit code.
+pub async fn run() -> i32 {
+    let mut report = Report::default();
+
+    let configuration = report
+        .step("Load configuration", async {
+            Configuration::check().map(|_| ((), "ok".to_string()))
+        })
+        .await;
+    if configuration.is_none() {
+        return report.finish();
+    }
+
+    for pool in Configuration::pools() {
+        check_pool(&mut report, &pool).await;
+    }
+    match Configuration::tp_address() {
+        Some(tp_address) => check_tp(&mut report, &tp_address).await,
+        None => report.skip("Template Provider", "no tp_address configured"),
+    }
+    let listen_address = Configuration::listen_address();
+    report
+        .step(
+            &format!("Bind SV1 listen address {listen_address}"),
+            async {
+                TcpListener::bind(&listen_address)
+                    .await
+                    .map(|_| ((), "ok".to_string()))
+                    .map_err(|e| format!("{e}, is another process using the port?"))
+            },
+        )
+        .await;
+
+    report.finish()
+}
+
+async fn check_pool(report: &mut Report, pool: &PoolConfig) {
+    let host = &pool.host;
+    let address = report
+        .step(&format!("Resolve pool {host}"), async {
+            let addresses: Vec<_> = tokio::net::lookup_host(host.as_str())
+                .await
+                .map_err(|e| e.to_string())?
+                .collect();
+            let address = *addresses.first().ok_or("no address found")?;
+            let list: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+            Ok((address, list.join(", ")))
+        })
+        .await;
+    let Some(address) = address else {
+        report.skip(&format!("Pool {host}"), "pool address not resolved");
+        return;
+    };
+
+    let stream = report
+        .step(&format!("TCP connect to {address}"), async {
+            TcpStream::connect(address)
+                .await
+                .map(|stream| (stream, "ok".to_string()))
+                .map_err(|e| format!("{e}, is a firewall blocking the connection?"))
+        })
+        .await;
+    let Some(stream) = stream else {
+        report.skip(&format!("Pool {host}"), "pool not reachable");
+        return;
+    };
+
+    let connection = report
+        .step(&format!("Noise handshake with {host}"), async {
+            let initiator = Initiator::from_raw_k(pool.auth_pub_key.into_bytes())
+                .map_err(|e| format!("invalid authority public key: {e:?}"))?;
+            Connection::new(stream, HandshakeRole::Initiator(initiator))
+                .await
+                .map(|(receiver, sender, _, _)| ((receiver, sender), "ok".to_string()))
+                .map_err(|e| format!("{e:?}, is auth_pub_key the pool's authority key?"))
+        })
+        .await;
+    let Some((mut receiver, mut sender)) = connection else {
+        report.skip(&format!("Pool {host}"), "noise handshake failed");
+        return;
+    };
+
+    let setup = report
+        .step(&format!("SetupConnection with {host}"), async {
+            let setup_connection_msg = get_mining_setup_connection_msg(true, &pool.token);
+            mining_setup_connection(
+                &mut receiver,
+                &mut sender,
+                setup_connection_msg,
+                STEP_TIMEOUT,
+            )
+            .await
+            .map(|success| {
+                let message = format!("used version {}", success.used_version);
+                ((), message)
+            })
+            .map_err(|e| format!("{e}, is the token valid?"))
+        })
+        .await;
+    if setup.is_none() {
+        report.skip(&format!("Pool {host}"), "connection not set up");
+        return;
+    }
+
+    report
+        .step(&format!("Open test channel with {host}"), async {
+            let (send_to_down, mut recv_from_down) = tokio::sync::mpsc::channel(10);
+            let (send_from_down, recv_to_up) = tokio::sync::mpsc::channel(10);
+            let _relay_up = relay_up(recv_to_up, sender);
+            let _relay_down = relay_down(receiver, send_to_down);
+            let channel = PoolExtMessages::Mining(crate::router::open_channel());
+            send_from_down
+                .send(channel)
+                .await
+                .map_err(|_| "connection closed by the pool".to_string())?;
+            while let Some(message) = recv_from_down.recv().await {
+                match message {
+                    PoolExtMessages::Mining(Mining::OpenExtendedMiningChannelSuccess(m)) => {
+                        let message = format!(
+                            "channel {} opened, extranonce size {}",
+                            m.channel_id, m.extranonce_size
+                        );
+                        return Ok(((), message));
+                    }
+                    PoolExtMessages::Mining(Mining::OpenMiningChannelError(m)) => {
+                        let error_code = m.error_code.to_vec();
+                        let error_code = String::from_utf8_lossy(&error_code);
+                        return Err(format!("pool refused the channel: {error_code}"));
+                    }
+                    _ => continue,
+                }
+            }
+            Err("connection closed by the pool".to_string())
+        })
+        .await;
+}
+
+async fn check_tp(report: &mut Report, tp_address: &str) {
+    let address = report
+        .step(&format!("Parse TP address {tp_address}"), async {
+            tp_address
+                .parse::<SocketAddr>()
+                .map(|address| (address, "ok".to_string()))
+                .map_err(|_| "expected an address like 127.0.0.1:8442".to_string())
+        })
+        .await;
+    let Some(address) = address else {
+        report.skip("Template Provider", "invalid TP address");
+        return;
+    };
+
+    let stream = report
+        .step(&format!("TCP connect to TP {address}"), async {
+            TcpStream::connect(address)
+                .await
+                .map(|stream| (stream, "ok".to_string()))
+                .map_err(|e| format!("{e}, is the TP running?"))
+        })
+        .await;
+    let Some(stream) = stream else {
+        report.skip("Template Provider", "TP not reachable");
+        return;
+    };
+
+    report
+        .step(
+            &format!("Template distribution setup with {address}"),
+            async {
+                let initiator = Initiator::without_pk().map_err(|e| format!("{e:?}"))?;
+                let (mut receiver, mut sender, _, _) =
+                    Connection::new(stream, HandshakeRole::Initiator(initiator))
+                        .await
+                        .map_err(|e| format!("noise handshake failed: {e:?}"))?;
+                TpSetupConnection::setup(&mut receiver, &mut sender, address)
+                    .await
+                    .map(|_| ((), "ok".to_string()))
+                    .map_err(|e| format!("SetupConnection failed: {e}"))
+            },
+        )
+        .await;
+}
+
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    /// Runs a step with a timeout and prints its outcome. Returns the step output if it passed.
+    async fn step<T>(
+        &mut self,
+        name: &str,
+        step: impl Future<Output = Result<(T, String), String>>,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let result = tokio::time::timeout(STEP_TIMEOUT, step)
+            .await
+            .unwrap_or(Err(format!("timeout after {}s", STEP_TIMEOUT.as_secs())));
+        let elapsed = start.elapsed().as_millis();
+        match result {
+            Ok((output, message)) => {
+                println!("[PASS] {name}: {message} ({elapsed}ms)");
+                Some(output)
+            }
+            Err(e) => {
+                println!("[FAIL] {name}: {e} ({elapsed}ms)");
+                self.failed = true;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str, reason: &str) {
+        println!("[SKIP] {name}: {reason}");
+    }
+
+    fn finish(self) -> i32 {
+        if self.failed {
+            println!("Some checks failed");
+            1
+        } else {
+            println!("All checks passed");
+            0
+        }
+    }
+}
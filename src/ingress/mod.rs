@@ -1,3 +1,4 @@
+mod session;
 pub mod sv1_ingress;
 //pub mod sv2_up_connection;
 //pub mod task_manager;
@@ -0,0 +1,232 @@
+//! State of a miner session kept by the ingress, so that the session outlives the translator.
+//!
+//! The handshake requests of the miner (`mining.configure`, `mining.subscribe`,
+//! `mining.extranonce.subscribe` and `mining.authorize`) are recorded. When the translator
+//! restarts they are replayed to the new one, and its responses are not relayed: the miner
+//! already got them. If the new translator gives another extranonce1 or extranonce2 size the
+//! miner is sent `mining.set_extranonce` when it subscribed to it, otherwise it has to reconnect.
+//! The first `mining.notify` of the new translator is relayed with `clean_jobs` set, so that the
+//! miner drops the jobs of the previous one.
+use serde_json::{json, Value};
+
+/// Requests replayed to a new translator.
+const HANDSHAKE_METHODS: [&str; 4] = [
+    "mining.configure",
+    "mining.subscribe",
+    "mining.extranonce.subscribe",
+    "mining.authorize",
+];
+
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Handshake requests, in the order the miner sent them
+    handshake: Vec<String>,
+    subscribe_id: Option<Value>,
+    /// Extranonce1 and extranonce2 size the miner mines with
+    extranonce: Option<(String, u64)>,
+    extranonce_subscribed: bool,
+    /// Ids of the replayed requests not answered yet, and whether the answer is awaited
+    replaying: Vec<(Value, bool)>,
+    clean_next_notify: bool,
+    must_reconnect: bool,
+}
+
+impl Session {
+    /// Records the handshake requests among the messages of the miner.
+    pub fn on_miner_message(&mut self, line: &str) {
+        let Ok(message) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            return;
+        };
+        if !HANDSHAKE_METHODS.contains(&method) {
+            return;
+        }
+        match method {
+            "mining.subscribe" => self.subscribe_id = message.get("id").cloned(),
+            "mining.extranonce.subscribe" => self.extranonce_subscribed = true,
+            _ => (),
+        }
+        self.handshake.push(line.to_string());
+    }
+
+    /// Whether the session can be moved to a new translator: the miner got an extranonce.
+    pub fn can_reattach(&self) -> bool {
+        self.extranonce.is_some()
+    }
+
+    /// Requests to send to a new translator. Until they are answered the messages of the miner
+    /// must not be relayed.
+    pub fn replay(&mut self) -> Vec<String> {
+        self.replaying = self
+            .handshake
+            .iter()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|message| {
+                // The translator may not answer `mining.extranonce.subscribe`
+                let awaited = message.get("method").and_then(Value::as_str)
+                    != Some("mining.extranonce.subscribe");
+                Some((message.get("id")?.clone(), awaited))
+            })
+            .collect();
+        self.clean_next_notify = true;
+        self.handshake.clone()
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replaying.iter().any(|(_, awaited)| *awaited)
+    }
+
+    /// Set when the miner must be sent `client.reconnect`: the extranonce changed and it can
+    /// not be told.
+    pub fn must_reconnect(&self) -> bool {
+        self.must_reconnect
+    }
+
+    /// Returns the messages to send to the miner for a message of the translator.
+    pub fn on_translator_message(&mut self, line: String) -> Vec<String> {
+        let Ok(mut message) = serde_json::from_str::<Value>(&line) else {
+            return vec![line];
+        };
+        if self.must_reconnect {
+            return vec![];
+        }
+        let id = message.get("id").cloned().unwrap_or(Value::Null);
+        let is_response = message.get("method").is_none();
+
+        if is_response
+            && !id.is_null()
+            && self.replaying.iter().any(|(replayed, _)| *replayed == id)
+        {
+            self.replaying.retain(|(replayed, _)| *replayed != id);
+            if !self.is_replaying() {
+                // The miner may use the ids of the requests that were not answered again
+                self.replaying.clear();
+            }
+            if Some(&id) != self.subscribe_id.as_ref() {
+                return vec![];
+            }
+            let Some(extranonce) = subscribed_extranonce(&message) else {
+                return vec![];
+            };
+            if self.extranonce.as_ref() == Some(&extranonce) {
+                return vec![];
+            }
+            if !self.extranonce_subscribed {
+                self.must_reconnect = true;
+                return vec![];
+            }
+            let set_extranonce = json!({
+                "id": null,
+                "method": "mining.set_extranonce",
+                "params": [extranonce.0, extranonce.1],
+            });
+            self.extranonce = Some(extranonce);
+            return vec![set_extranonce.to_string()];
+        }
+
+        if is_response && self.extranonce.is_none() && Some(&id) == self.subscribe_id.as_ref() {
+            self.extranonce = subscribed_extranonce(&message);
+        }
+        let is_notify = message.get("method").and_then(Value::as_str) == Some("mining.notify");
+        if is_notify && self.clean_next_notify {
+            self.clean_next_notify = false;
+            if let Some(clean_jobs) = message
+                .get_mut("params")
+                .and_then(Value::as_array_mut)
+                .and_then(|params| params.get_mut(8))
+            {
+                *clean_jobs = Value::Bool(true);
+                return vec![message.to_string()];
+            }
+        }
+        vec![line]
+    }
+}
+
+/// Extranonce1 and extranonce2 size of a `mining.subscribe` response.
+fn subscribed_extranonce(response: &Value) -> Option<(String, u64)> {
+    let result = response.get("result")?.as_array()?;
+    let extranonce1 = result.get(1)?.as_str()?.to_string();
+    let extranonce2_size = result.get(2)?.as_u64()?;
+    Some((extranonce1, extranonce2_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBSCRIBE: &str = r#"{"id":1,"method":"mining.subscribe","params":["cgminer"]}"#;
+    const EXTRANONCE_SUBSCRIBE: &str =
+        r#"{"id":2,"method":"mining.extranonce.subscribe","params":[]}"#;
+    const AUTHORIZE: &str = r#"{"id":3,"method":"mining.authorize","params":["worker","x"]}"#;
+
+    fn subscribed(id: u64, extranonce1: &str) -> String {
+        json!({"id": id, "result": [[["mining.notify", "ae68"]], extranonce1, 8], "error": null})
+            .to_string()
+    }
+
+    fn notify(clean_jobs: bool) -> String {
+        json!({"id": null, "method": "mining.notify", "params": ["1", "00", "01", "02", [], "20000000", "1d00ffff", "5f5e1000", clean_jobs]})
+            .to_string()
+    }
+
+    #[test]
+    fn replays_the_handshake_and_sets_the_new_extranonce() {
+        let mut session = Session::default();
+        session.on_miner_message(SUBSCRIBE);
+        session.on_miner_message(EXTRANONCE_SUBSCRIBE);
+        session.on_miner_message(AUTHORIZE);
+        session.on_miner_message(r#"{"id":4,"method":"mining.submit","params":[]}"#);
+        assert!(!session.can_reattach());
+        let first = subscribed(1, "aa");
+        assert_eq!(session.on_translator_message(first.clone()), vec![first]);
+        assert!(session.can_reattach());
+
+        assert_eq!(
+            session.replay(),
+            vec![SUBSCRIBE, EXTRANONCE_SUBSCRIBE, AUTHORIZE]
+        );
+        assert!(session.is_replaying());
+        let set_extranonce = session.on_translator_message(subscribed(1, "bb"));
+        assert_eq!(
+            set_extranonce,
+            vec![r#"{"id":null,"method":"mining.set_extranonce","params":["bb",8]}"#]
+        );
+        let authorized = json!({"id": 3, "result": true, "error": null}).to_string();
+        assert!(session.on_translator_message(authorized).is_empty());
+        assert!(!session.is_replaying());
+
+        assert_eq!(
+            session.on_translator_message(notify(false)),
+            vec![notify(true)]
+        );
+        assert_eq!(
+            session.on_translator_message(notify(false)),
+            vec![notify(false)]
+        );
+        assert!(!session.must_reconnect());
+    }
+
+    #[test]
+    fn reconnects_when_the_extranonce_changed_and_can_not_be_set() {
+        let mut session = Session::default();
+        session.on_miner_message(SUBSCRIBE);
+        session.on_miner_message(AUTHORIZE);
+        session.on_translator_message(subscribed(1, "aa"));
+
+        session.replay();
+        assert!(session
+            .on_translator_message(subscribed(1, "aa"))
+            .is_empty());
+        assert!(!session.must_reconnect());
+
+        session.replay();
+        assert!(session
+            .on_translator_message(subscribed(1, "bb"))
+            .is_empty());
+        assert!(session.must_reconnect());
+        assert!(session.on_translator_message(notify(true)).is_empty());
+    }
+}
@@ -1,38 +1,147 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
 
-use crate::shared::{error::Sv1IngressError, utils::AbortOnDrop};
-use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+use crate::{
+    config::Configuration,
+    shared::{error::Sv1IngressError, utils::AbortOnDrop},
 };
+use dashmap::DashMap;
+use futures::{stream::SplitSink, SinkExt, StreamExt};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::{channel, Receiver, Sender, WeakSender},
+    time::Instant,
 };
 use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{error, info, warn};
 
-pub fn start_listen_for_downstream(
-    downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
+use super::session::Session;
+
+const CLIENT_RECONNECT: &str = r#"{"id":null,"method":"client.reconnect","params":[]}"#;
+/// How long a new translator has to answer the handshake replayed for a session.
+const REATTACH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Miners that have to reconnect are asked to within this delay, not all at once.
+const RECONNECT_JITTER: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    /// Senders to the connected miners, weak so that a connection still closes when the
+    /// translator drops its side.
+    static ref CONNECTIONS: DashMap<SocketAddr, WeakSender<String>> = DashMap::new();
+}
+
+/// Connection of a miner accepted by the listener, as the translator takes it.
+type Sv1Downstream = (Sender<String>, Receiver<String>, IpAddr);
+
+/// Run of a translator, held by what runs it and dropped when it is stopped. A session the
+/// translator drops is moved to the next translator if the run it was handed to is gone, and
+/// closed otherwise: the translator closed it on purpose.
+#[derive(Debug, Default)]
+pub struct TranslatorRun(Arc<()>);
+
+impl TranslatorRun {
+    pub fn handle(&self) -> RunHandle {
+        RunHandle(Arc::downgrade(&self.0))
+    }
+}
+
+/// Refers to a [`TranslatorRun`] without keeping it.
+#[derive(Debug, Clone)]
+pub struct RunHandle(Weak<()>);
+
+/// Where the run a session is handed to is recorded.
+#[derive(Debug, Clone)]
+pub struct RunSlot(Arc<Mutex<Weak<()>>>);
+
+impl RunSlot {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Weak::new())))
+    }
+
+    /// Called when the session is handed to the translator of `run`.
+    pub fn set(&self, run: &RunHandle) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = run.0.clone();
+    }
+
+    /// Whether the run the session was handed to is gone, or it was never handed to one.
+    fn restarted(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .upgrade()
+            .is_none()
+    }
+}
+
+/// A session of the listener to hand to a translator, see [`TranslatorRun`].
+pub struct Attach {
+    pub downstream: Sv1Downstream,
+    pub run: RunSlot,
+}
+
+/// Hands the sessions of `from` to the translator of `run`. The receiver is taken back by the
+/// next relay when the translator restarts.
+pub fn relay(
+    from: Arc<tokio::sync::Mutex<Receiver<Attach>>>,
+    to: Sender<Sv1Downstream>,
+    run: RunHandle,
 ) -> AbortOnDrop {
-    info!("Starting downstream listner");
-    tokio::task::spawn(async move {
-        let down_addr: String = crate::SV1_DOWN_LISTEN_ADDR.to_string();
-        let downstream_addr: SocketAddr = down_addr.parse().expect("Invalid listen address");
-        let downstream_listener = TcpListener::bind(downstream_addr)
-            .await
-            .expect("impossible to bind downstream");
+    tokio::spawn(async move {
+        let mut from = from.lock().await;
+        while let Some(attach) = from.recv().await {
+            attach.run.set(&run);
+            if to.send(attach.downstream).await.is_err() {
+                break;
+            }
+        }
+    })
+    .into()
+}
+
+/// Asks every connected miner to reconnect, returns how many have been notified.
+pub async fn reconnect_all() -> usize {
+    let senders: Vec<Sender<String>> = CONNECTIONS
+        .iter()
+        .filter_map(|connection| connection.value().upgrade())
+        .collect();
+    reconnect(senders).await
+}
+
+/// Asks the miners of `senders` to reconnect, returns how many have been notified.
+pub async fn reconnect(senders: Vec<Sender<String>>) -> usize {
+    let mut notified = 0;
+    for sender in senders {
+        if sender.send(CLIENT_RECONNECT.to_string()).await.is_ok() {
+            notified += 1;
+        }
+    }
+    notified
+}
+
+/// Listens on `address` for the miners, the listener is bound before returning so that an
+/// address that can not be used is reported to the caller.
+pub async fn start_listen_for_downstream(
+    address: &str,
+    downstreams: Sender<Attach>,
+) -> Result<AbortOnDrop, Sv1IngressError> {
+    info!("Starting downstream listner on {address}");
+    let downstream_listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| Sv1IngressError::Bind(address.to_string(), e))?;
+    Ok(tokio::task::spawn(async move {
         while let Ok((stream, addr)) = downstream_listener.accept().await {
             info!("Try to connect {:#?}", addr);
             Downstream::initialize(
                 stream,
-                crate::MAX_LEN_DOWN_MSG,
-                addr.ip(),
+                Configuration::max_len_down_msg(),
+                addr,
                 downstreams.clone(),
             );
         }
     })
-    .into()
+    .into())
 }
 struct Downstream {}
 
@@ -40,81 +149,156 @@ impl Downstream {
     pub fn initialize(
         stream: TcpStream,
         max_len_for_downstream_messages: u32,
-        address: IpAddr,
-        downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
+        address: SocketAddr,
+        downstreams: Sender<Attach>,
     ) {
         tokio::spawn(async move {
             info!("spawning downstream");
-            let (send_to_upstream, recv) = channel(10);
-            let (send, recv_from_upstream) = channel(10);
-            downstreams
-                .send((send, recv, address))
-                .await
-                .expect("Translator busy");
             let codec = LinesCodec::new_with_max_length(max_len_for_downstream_messages as usize);
             let framed = Framed::new(stream, codec);
-            Self::start(framed, recv_from_upstream, send_to_upstream).await
+            match Self::start(framed, address, downstreams).await {
+                Sv1IngressError::DownstreamDropped => info!("Miner {address} disconnected"),
+                Sv1IngressError::TranslatorDropped => info!("Closed connection of {address}"),
+                e @ Sv1IngressError::Bind(..) => error!("{e}"),
+            }
+            CONNECTIONS.remove(&address);
         });
     }
-    async fn start(
-        framed: Framed<TcpStream, LinesCodec>,
-        receiver: Receiver<String>,
-        sender: Sender<String>,
-    ) {
-        let (writer, reader) = framed.split();
-        let result = tokio::select! {
-            result1 = Self::receive_from_downstream_and_relay_up(reader, sender) => result1,
-            result2 = Self::receive_from_upstream_and_relay_down(writer, receiver) => result2,
+
+    /// Hands the connection to the translator, returns the channels to it and where the run of
+    /// the translator is recorded.
+    async fn attach(
+        address: SocketAddr,
+        downstreams: &Sender<Attach>,
+    ) -> Option<(Sender<String>, Receiver<String>, RunSlot)> {
+        let (send_to_upstream, recv) = channel(10);
+        let (send, recv_from_upstream) = channel(10);
+        CONNECTIONS.insert(address, send.downgrade());
+        let run = RunSlot::new();
+        let attach = Attach {
+            downstream: (send, recv, address.ip()),
+            run: run.clone(),
         };
-        // upstream disconnected make sure to clean everything before exit
-        match result {
-            Sv1IngressError::DownstreamDropped => (),
-            Sv1IngressError::TranslatorDropped => (),
-            Sv1IngressError::TaskFailed => (),
-        }
+        downstreams.send(attach).await.ok()?;
+        Some((send_to_upstream, recv_from_upstream, run))
     }
-    async fn receive_from_downstream_and_relay_up(
-        mut recv: SplitStream<Framed<TcpStream, LinesCodec>>,
-        send: Sender<String>,
+
+    /// Relays the messages between the miner and the translator. When the translator restarts
+    /// the session is moved to the new one, see [`Session`].
+    async fn start(
+        framed: Framed<TcpStream, LinesCodec>,
+        address: SocketAddr,
+        downstreams: Sender<Attach>,
     ) -> Sv1IngressError {
-        let task = tokio::spawn(async move {
-            while let Some(Ok(message)) = recv.next().await {
-                if send.send(message).await.is_err() {
-                    error!("Upstream dropped trying to send");
-                    return Sv1IngressError::TranslatorDropped;
+        let (mut writer, mut reader) = framed.split();
+        let mut session = Session::default();
+        let Some((mut sender, mut receiver, mut run)) = Self::attach(address, &downstreams).await
+        else {
+            error!("Translator busy");
+            return Sv1IngressError::TranslatorDropped;
+        };
+        let mut replay_deadline: Option<Instant> = None;
+        loop {
+            let translator_dropped = tokio::select! {
+                // The messages of the miner wait until the new translator answered the handshake
+                message = reader.next(), if !session.is_replaying() => {
+                    let Some(Ok(message)) = message else {
+                        warn!("Downstream dropped while trying to send message up");
+                        return Sv1IngressError::DownstreamDropped;
+                    };
+                    session.on_miner_message(&message);
+                    sender.send(message).await.is_err()
+                }
+                message = receiver.recv() => match message {
+                    Some(message) => {
+                        for message in session.on_translator_message(message) {
+                            let message = message.replace(['\n', '\r'], "");
+                            if writer.send(message).await.is_err() {
+                                warn!("Downstream dropped while trying to send message down");
+                                return Sv1IngressError::DownstreamDropped;
+                            };
+                        }
+                        if session.must_reconnect() {
+                            info!("Extranonce of {address} changed, asking it to reconnect");
+                            Self::reconnect(writer).await;
+                            return Sv1IngressError::TranslatorDropped;
+                        }
+                        if !session.is_replaying() {
+                            replay_deadline = None;
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                _ = tokio::time::sleep_until(replay_deadline.unwrap_or_else(Instant::now)),
+                    if replay_deadline.is_some() =>
+                {
+                    warn!("Translator did not answer the handshake of {address} in time");
+                    true
                 }
+            };
+            if !translator_dropped {
+                continue;
             }
-            warn!("Downstream dropped while trying to send message up");
-            Sv1IngressError::DownstreamDropped
-        })
-        .await;
-        match task {
-            Ok(err) => err,
-            Err(_) => Sv1IngressError::TaskFailed,
-        }
-    }
-    async fn receive_from_upstream_and_relay_down(
-        mut send: SplitSink<Framed<TcpStream, LinesCodec>, String>,
-        mut recv: Receiver<String>,
-    ) -> Sv1IngressError {
-        let task = tokio::spawn(async move {
-            while let Some(message) = recv.recv().await {
-                let message = message.replace(['\n', '\r'], "");
-                if send.send(message).await.is_err() {
-                    warn!("Downstream dropped while trying to send message down");
-                    return Sv1IngressError::DownstreamDropped;
+
+            // A session dropped while its translator still runs is closed on purpose
+            if !session.can_reattach() || session.is_replaying() || !run.restarted() {
+                if writer.close().await.is_err() {
+                    error!("Failed to close connection");
                 };
+                error!("Upstream dropped trying to receive");
+                return Sv1IngressError::TranslatorDropped;
             }
-            if send.close().await.is_err() {
-                error!("Failed to close connection");
+            info!("Translator restarted, moving the session of {address} to the new one");
+            let Some((new_sender, new_receiver, new_run)) =
+                Self::attach(address, &downstreams).await
+            else {
+                if writer.close().await.is_err() {
+                    error!("Failed to close connection");
+                };
+                return Sv1IngressError::TranslatorDropped;
             };
-            error!("Upstream dropped trying to receive");
-            Sv1IngressError::TranslatorDropped
-        })
-        .await;
-        match task {
-            Ok(err) => err,
-            Err(_) => Sv1IngressError::TaskFailed,
+            (sender, receiver, run) = (new_sender, new_receiver, new_run);
+            for request in session.replay() {
+                // A failure is seen when receiving
+                if sender.send(request).await.is_err() {
+                    break;
+                }
+            }
+            replay_deadline = Some(Instant::now() + REATTACH_TIMEOUT);
         }
     }
+
+    /// Asks the miner to reconnect after a random delay, so that the miners of a translator
+    /// that restarted do not all reconnect at once.
+    async fn reconnect(mut writer: SplitSink<Framed<TcpStream, LinesCodec>, String>) {
+        let jitter = rand::Rng::gen_range(
+            &mut rand::thread_rng(),
+            0..RECONNECT_JITTER.as_millis() as u64,
+        );
+        tokio::time::sleep(Duration::from_millis(jitter)).await;
+        if writer.send(CLIENT_RECONNECT.to_string()).await.is_err() {
+            warn!("Downstream dropped before being asked to reconnect");
+        }
+        if writer.close().await.is_err() {
+            error!("Failed to close connection");
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_sessions_only_once_their_translator_stopped() {
+        let slot = RunSlot::new();
+        // Not handed to a translator yet
+        assert!(slot.restarted());
+        let run = TranslatorRun::default();
+        slot.set(&run.handle());
+        assert!(!slot.restarted());
+        drop(run);
+        assert!(slot.restarted());
+    }
 }
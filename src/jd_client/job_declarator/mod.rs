@@ -81,6 +81,7 @@ impl JobDeclarator {
     pub async fn new(
         address: SocketAddr,
         authority_public_key: [u8; 32],
+        token: &str,
         up: Arc<Mutex<Upstream>>,
         should_log_when_connected: bool,
     ) -> Result<(Arc<Mutex<Self>>, AbortOnDrop), Error> {
@@ -91,7 +92,7 @@ impl JobDeclarator {
                 .await
                 .map_err(|_| Error::Unrecoverable)?;
 
-        SetupConnectionHandler::setup(&mut receiver, &mut sender, address).await?;
+        SetupConnectionHandler::setup(&mut receiver, &mut sender, address, token).await?;
 
         if should_log_when_connected {
             info!("JD CONNECTED");
@@ -298,7 +299,10 @@ impl JobDeclarator {
                     }),
                     None => {
                         error!("Failed to receive msg from Pool");
-                        ProxyState::update_pool_state(PoolState::Down);
+                        ProxyState::update_pool_state(
+                            PoolState::Down,
+                            "JobDeclarator: pool connection closed",
+                        );
                         break;
                     }
                 };
@@ -324,7 +328,10 @@ impl JobDeclarator {
                                 Ok(last_declare) => last_declare,
                                 Err(e) => {
                                     error!("{e}");
-                                    ProxyState::update_jd_state(JdState::Down);
+                                    ProxyState::update_jd_state(
+                                        JdState::Down,
+                                        format!("JobDeclarator: {e}"),
+                                    );
                                     break;
                                 }
                             };
@@ -351,7 +358,10 @@ impl JobDeclarator {
                                 );
                             }) {
                                 error!("{e}");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(
+                                    JdState::Down,
+                                    format!("JobDeclarator: mutex poisoned: {e}"),
+                                );
                                 break;
                             };
                         } else {
@@ -360,7 +370,10 @@ impl JobDeclarator {
                                     Ok(set_new_prev_hash) => set_new_prev_hash,
                                     Err(e) => {
                                         error!("{e}");
-                                        ProxyState::update_jd_state(JdState::Down);
+                                        ProxyState::update_jd_state(
+                                            JdState::Down,
+                                            format!("JobDeclarator: mutex poisoned: {e}"),
+                                        );
                                         break;
                                     }
                                 };
@@ -381,7 +394,7 @@ impl JobDeclarator {
                                     pool_outs,
                                     template.coinbase_tx_locktime,
                                     template.template_id
-                                    ).await {error!("Failed to set custom jobd: {e}"); ProxyState::update_jd_state(JdState::Down);break;},
+                                    ).await {error!("Failed to set custom jobd: {e}"); ProxyState::update_jd_state(JdState::Down, format!("JobDeclarator: SetCustomMiningJob failed: {e}"));break;},
                                 None => panic!("Invalid state we received a NewTemplate not future, without having received a set new prev hash")
                             }
                         }
@@ -398,20 +411,26 @@ impl JobDeclarator {
                             Ok(sender) => sender,
                             Err(e) => {
                                 error!("{e}");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(
+                                    JdState::Down,
+                                    format!("JobDeclarator: mutex poisoned: {e}"),
+                                );
                                 break;
                             }
                         };
                         if sender.send(sv2_frame.into()).await.is_err() {
                             error!("Job declarator failed to send message");
-                            ProxyState::update_jd_state(JdState::Down);
+                            ProxyState::update_jd_state(
+                                JdState::Down,
+                                "JobDeclarator: pool connection closed",
+                            );
                             break;
                         };
                     }
                     Ok(_) => unreachable!(),
                     Err(e) => {
                         error!("{e}");
-                        ProxyState::update_jd_state(JdState::Down);
+                        ProxyState::update_jd_state(JdState::Down, format!("JobDeclarator: {e}"));
                         break;
                     }
                 }
@@ -514,7 +533,10 @@ impl JobDeclarator {
                 Err(e) => {
                     error!("{e}");
                     //Poison lock
-                    ProxyState::update_jd_state(JdState::Down);
+                    ProxyState::update_jd_state(
+                        JdState::Down,
+                        format!("allocate_tokens: mutex poisoned: {e}"),
+                    );
                     return;
                 }
             };
@@ -526,7 +548,10 @@ impl JobDeclarator {
 
             if sender.send(frame.into()).await.is_err() {
                 error!("Job declarator failed to send message");
-                ProxyState::update_jd_state(JdState::Down);
+                ProxyState::update_jd_state(
+                    JdState::Down,
+                    "allocate_tokens: pool connection closed",
+                );
             }
         }
     }
@@ -16,7 +16,10 @@ pub type EitherFrame = StandardEitherFrame<Message>;
 pub struct SetupConnectionHandler {}
 
 impl SetupConnectionHandler {
-    fn get_setup_connection_message(proxy_address: SocketAddr) -> SetupConnection<'static> {
+    fn get_setup_connection_message(
+        proxy_address: SocketAddr,
+        token: &str,
+    ) -> SetupConnection<'static> {
         let endpoint_host = proxy_address
             .ip()
             .to_string()
@@ -26,7 +29,6 @@ impl SetupConnectionHandler {
         let vendor = String::new().try_into().expect("Internal error: this operation can not fail because empty string can always be converted into Inner");
         let hardware_version = String::new().try_into().expect("Internal error: this operation can not fail because empty string can always be converted into Inner");
         let firmware = String::new().try_into().expect("Internal error: this operation can not fail because empty string can always be converted into Inner");
-        let token = std::env::var("TOKEN").expect("Checked at initialization");
         let device_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
         let device_id = format!("{}::POOLED::{}", device_id, token)
             .to_string()
@@ -52,8 +54,9 @@ impl SetupConnectionHandler {
         receiver: &mut TReceiver<EitherFrame>,
         sender: &mut TSender<EitherFrame>,
         proxy_address: SocketAddr,
+        token: &str,
     ) -> Result<(), crate::jd_client::error::Error> {
-        let setup_connection = Self::get_setup_connection_message(proxy_address);
+        let setup_connection = Self::get_setup_connection_message(proxy_address, token);
 
         let sv2_frame: StdFrame = PoolMessages::Common(setup_connection.into()).try_into()?;
         let sv2_frame = sv2_frame.into();
@@ -1,5 +1,6 @@
 mod task_manager;
 use crate::{
+    blocks::{self, Block},
     proxy_state::{DownstreamType, JdState, ProxyState},
     shared::utils::AbortOnDrop,
 };
@@ -27,7 +28,7 @@ use tracing::{debug, error, warn};
 
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
 
-use bitcoin::{consensus::Decodable, TxOut};
+use bitcoin::{consensus::Decodable, Transaction, TxOut};
 
 pub type Message = MiningDeviceMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
@@ -48,8 +49,15 @@ pub struct DownstreamMiningNode {
     // used to retreive the job id of the share that we send upstream
     last_template_id: u64,
     jd: Option<Arc<Mutex<JobDeclarator>>>,
+    // prev hash and nbits of the current block, and merkle paths of the last templates, used to
+    // build the header of the blocks found
+    prev_hash: Option<(Vec<u8>, u32)>,
+    merkle_paths: VecDeque<(u64, Vec<Vec<u8>>)>,
 }
 
+/// Number of templates whose merkle path is kept.
+const MAX_MERKLE_PATHS: usize = 16;
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum DownstreamMiningNodeStatus {
@@ -111,7 +119,7 @@ impl DownstreamMiningNodeStatus {
 }
 
 use core::convert::TryInto;
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
 impl DownstreamMiningNode {
     #[allow(clippy::too_many_arguments)]
@@ -139,6 +147,43 @@ impl DownstreamMiningNode {
             // Is upated in the message handler that si called earlier in the main loop.
             last_template_id: 0,
             jd,
+            prev_hash: None,
+            merkle_paths: VecDeque::with_capacity(MAX_MERKLE_PATHS),
+        }
+    }
+
+    /// Records the block found by a share that meets the bitcoin target.
+    fn record_block(&self, share: &SubmitSharesExtended, template_id: u64, coinbase: &[u8]) {
+        let coinbase: Transaction = match bitcoin::consensus::deserialize(coinbase) {
+            Ok(coinbase) => coinbase,
+            Err(e) => {
+                error!("Block found but its coinbase can not be decoded: {e}");
+                return;
+            }
+        };
+        let merkle_path = self
+            .merkle_paths
+            .iter()
+            .find(|(id, _)| *id == template_id)
+            .map(|(_, path)| path);
+        let header = match (&self.prev_hash, merkle_path) {
+            (Some((prev_hash, bits)), Some(merkle_path)) => blocks::header(
+                share.version,
+                prev_hash,
+                &coinbase,
+                merkle_path,
+                share.ntime,
+                *bits,
+                share.nonce,
+            ),
+            _ => None,
+        };
+        match header {
+            Some(header) => blocks::found(Block {
+                template_id: Some(template_id),
+                ..Block::new(&header, &coinbase)
+            }),
+            None => error!("Block found on template {template_id} but its header is unknown"),
         }
     }
 
@@ -167,7 +212,10 @@ impl DownstreamMiningNode {
             while let Some(message) = receiver.recv().await {
                 if let Err(e) = DownstreamMiningNode::next(&self_mutex, message).await {
                     error!("Jd error can not receive message from downstream: {e:?}");
-                    ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream);
+                    ProxyState::update_downstream_state(
+                        DownstreamType::JdClientMiningDownstream,
+                        format!("DownstreamMiningNode: {e:?}"),
+                    );
                 };
             }
         });
@@ -193,6 +241,7 @@ impl DownstreamMiningNode {
                             error!("Jd can not get upstream");
                             ProxyState::update_downstream_state(
                                 DownstreamType::JdClientMiningDownstream,
+                                "set_channel_factory: no upstream",
                             );
                             return;
                         }
@@ -201,6 +250,7 @@ impl DownstreamMiningNode {
                         error!("Jd can not get upstream: {e}");
                         ProxyState::update_downstream_state(
                             DownstreamType::JdClientMiningDownstream,
+                            format!("set_channel_factory: mutex poisoned: {e}"),
                         );
                         return;
                     }
@@ -249,7 +299,10 @@ impl DownstreamMiningNode {
                     Some(incoming) => incoming,
                     None => {
                         error!("JDC dowstream try to releay an inexistent message");
-                        ProxyState::update_jd_state(JdState::Down);
+                        ProxyState::update_jd_state(
+                            JdState::Down,
+                            "match_send_to: no message to relay",
+                        );
                         return Err(JdClientError::Unrecoverable);
                     }
                 };
@@ -307,6 +360,7 @@ impl DownstreamMiningNode {
                         error!("Jd Unexpected message: {e:?}");
                         ProxyState::update_downstream_state(
                             DownstreamType::JdClientMiningDownstream,
+                            format!("match_send_to: {e:?}"),
                         );
                     }
                 }
@@ -353,6 +407,11 @@ impl DownstreamMiningNode {
         let to_send = {
             let pool_outputs = self_mutex
                 .safe_lock(|s| {
+                    if s.merkle_paths.len() == MAX_MERKLE_PATHS {
+                        s.merkle_paths.pop_front();
+                    }
+                    s.merkle_paths
+                        .push_back((new_template.template_id, new_template.merkle_path.to_vec()));
                     let channel = s.status.get_channel().map_err(JdClientError::RolesSv2Logic);
 
                     match channel {
@@ -414,6 +473,7 @@ impl DownstreamMiningNode {
         }
         let job_id = self_mutex
             .safe_lock(|s| {
+                s.prev_hash = Some((new_prev_hash.prev_hash.to_vec(), new_prev_hash.n_bits));
                 let channel = s.status.get_channel()?;
                 channel.on_new_prev_hash_from_tp(&new_prev_hash)
             })
@@ -607,6 +667,7 @@ impl
             )) => {
                 match share {
                     Share::Extended(share) => {
+                        self.record_block(&share, template_id, &coinbase);
                         let solution_sender = self.solution_sender.clone();
                         let solution = SubmitSolution {
                             template_id,
@@ -632,7 +693,9 @@ impl
                                         {
                                             error!("Jd Error on solution: {e:?}");
                                             // Set the proxy state to internal inconsistency
-                                            ProxyState::update_inconsistency(Some(1));
+                                            ProxyState::update_inconsistency(format!(
+                                                "on_solution: {e:?}"
+                                            ));
                                         }
                                     }
                                 });
@@ -231,7 +231,10 @@ impl Upstream {
                         None => {
                             error!("Upstream down");
                             // Update the proxy state to reflect the Tp is down
-                            ProxyState::update_tp_state(TpState::Down);
+                            ProxyState::update_tp_state(
+                                TpState::Down,
+                                "mining upstream: connection closed",
+                            );
                             break;
                         }
                     };
@@ -257,6 +260,7 @@ impl Upstream {
                                 // Update global proxy downstream state
                                 ProxyState::update_downstream_state(
                                     DownstreamType::JdClientMiningDownstream,
+                                    "mining upstream: downstream closed",
                                 );
                                 break;
                             };
@@ -265,7 +269,10 @@ impl Upstream {
                         Ok(_) => unreachable!(),
                         Err(e) => {
                             error!("{e:?}");
-                            ProxyState::update_upstream_state(UpstreamType::JDCMiningUpstream);
+                            ProxyState::update_upstream_state(
+                                UpstreamType::JDCMiningUpstream,
+                                format!("mining upstream: {e:?}"),
+                            );
                             break;
                         }
                     }
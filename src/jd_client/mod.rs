@@ -5,10 +5,9 @@ pub mod job_declarator;
 pub mod mining_downstream;
 pub mod mining_upstream;
 mod task_manager;
-mod template_receiver;
+pub mod template_receiver;
 
 use job_declarator::JobDeclarator;
-use key_utils::Secp256k1PublicKey;
 use mining_downstream::DownstreamMiningNode;
 use std::sync::atomic::AtomicBool;
 use task_manager::TaskManager;
@@ -38,7 +37,8 @@ use tracing::{error, info};
 ///    between all the contexts is not necessary.
 pub static IS_NEW_TEMPLATE_HANDLED: AtomicBool = AtomicBool::new(true);
 
-use crate::proxy_state::{DownstreamType, ProxyState, TpState};
+use crate::proxy_state::{DownstreamType, ProxyState};
+use crate::{config::Configuration, router::Candidate};
 use roles_logic_sv2::{parsers::Mining, utils::Mutex};
 use std::{
     net::{IpAddr, SocketAddr},
@@ -46,8 +46,6 @@ use std::{
     sync::Arc,
 };
 
-use std::net::ToSocketAddrs;
-
 use crate::shared::utils::AbortOnDrop;
 
 pub async fn start(
@@ -55,8 +53,9 @@ pub async fn start(
     sender: tokio::sync::mpsc::Sender<Mining<'static>>,
     up_receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
     up_sender: tokio::sync::mpsc::Sender<Mining<'static>>,
+    pool: Candidate,
 ) -> Option<AbortOnDrop> {
-    initialize_jd(receiver, sender, up_receiver, up_sender).await
+    initialize_jd(receiver, sender, up_receiver, up_sender, pool).await
 }
 
 async fn initialize_jd(
@@ -64,6 +63,7 @@ async fn initialize_jd(
     sender: tokio::sync::mpsc::Sender<Mining<'static>>,
     up_receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
     up_sender: tokio::sync::mpsc::Sender<Mining<'static>>,
+    pool: Candidate,
 ) -> Option<AbortOnDrop> {
     let task_manager = TaskManager::initialize();
     let abortable = match task_manager.safe_lock(|t| t.get_aborter()) {
@@ -105,21 +105,22 @@ async fn initialize_jd(
     let ip_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").to_string();
     let port_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").parse::<u16>().expect("This operation should not fail because a valid port_tp should always be converted to U16");
 
-    let auth_pub_k: Secp256k1PublicKey = crate::AUTH_PUB_KEY.parse().expect("Invalid public key");
-    let address = crate::POOL_ADDRESS
-        .to_socket_addrs()
-        .expect("The passed Pool Address is not valid")
-        .next()?;
-
-    let (jd, jd_abortable) =
-        match JobDeclarator::new(address, auth_pub_k.into_bytes(), upstream.clone(), true).await {
-            Ok(c) => c,
-            Err(e) => {
-                error!("Failed to intialize Jd: {e}");
-                drop(abortable);
-                return None;
-            }
-        };
+    let (jd, jd_abortable) = match JobDeclarator::new(
+        pool.address,
+        pool.pool.auth_pub_key.into_bytes(),
+        &pool.pool.token,
+        upstream.clone(),
+        true,
+    )
+    .await
+    {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to intialize Jd: {e}");
+            drop(abortable);
+            return None;
+        }
+    };
 
     if TaskManager::add_job_declarator_task(task_manager.clone(), jd_abortable)
         .await
@@ -146,7 +147,10 @@ async fn initialize_jd(
         Ok(abortable) => abortable,
         Err(e) => {
             error!("Can not start downstream mining node: {e}");
-            ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::JdClientMiningDownstream,
+                format!("initialize_jd: downstream mining node failed to start: {e}"),
+            );
             return None;
         }
     };
@@ -208,14 +212,8 @@ async fn initialize_jd(
         Err(_) => {
             info!("Dropping jd abortable");
             eprintln!("TP is unreachable, the proxy is in not in JD mode");
+            // The supervisor falls back to the pool jobs
             drop(abortable);
-            // Temporaily set TP_ADDRESS to None so that proxy can restart without it.
-            // that means we will start mining without jd
-            if crate::TP_ADDRESS.safe_lock(|tp| *tp = None).is_err() {
-                error!("TP_ADDRESS mutex corrupt");
-                return None;
-            };
-            tokio::spawn(retry_connection(tp_address));
             return None;
         }
     };
@@ -234,17 +232,43 @@ async fn initialize_jd(
     Some(abortable)
 }
 
+/// Mines on the pool jobs until the Template Provider is reachable again, it is checked after
+/// `retry_after` and then every 5 seconds. The supervisor is then asked to restart the mining
+/// subsystem with JD.
+pub fn fall_back_to_pool_jobs(retry_after: tokio::time::Duration) {
+    // Temporaily set TP_ADDRESS to None so that the mining subsystem restarts without it
+    let tp_address = match crate::TP_ADDRESS.safe_lock(|tp| tp.take()) {
+        Ok(Some(tp_address)) => tp_address,
+        // Already mining on the pool jobs
+        Ok(None) => return,
+        Err(e) => {
+            error!("TP_ADDRESS mutex corrupt: {e}");
+            return;
+        }
+    };
+    info!(
+        "Mining on the pool jobs, retrying the TP in {}s",
+        retry_after.as_secs()
+    );
+    tokio::spawn(retry_connection(tp_address, retry_after));
+}
+
 // Used when tp is down or connection was unsuccessful to retry connection.
-async fn retry_connection(address: String) {
+async fn retry_connection(address: String, retry_after: tokio::time::Duration) {
+    tokio::time::sleep(retry_after).await;
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
     loop {
         info!("TP Retrying connection....");
         interval.tick().await;
+        if Configuration::tp_address().as_ref() != Some(&address) {
+            info!("TP address changed in the configuration, stop retrying {address}");
+            break;
+        }
         if tokio::net::TcpStream::connect(address.clone())
             .await
             .is_ok()
         {
-            info!("Successfully reconnected to TP: Restarting Proxy...");
+            info!("Successfully reconnected to TP: Restarting mining with JD...");
             if crate::TP_ADDRESS
                 .safe_lock(|tp| *tp = Some(address))
                 .is_err()
@@ -252,10 +276,10 @@ async fn retry_connection(address: String) {
                 error!("TP_ADDRESS Mutex failed");
                 std::process::exit(1);
             };
-            // This force the proxy to restart. If we use Up the proxy just ignore it.
-            // So updating it to Down and setting the TP_ADDRESS to Some(address) will make the
-            // proxy restart with TP, the the TpState will be set to Up.
-            ProxyState::update_tp_state(TpState::Down);
+            crate::supervisor::restart(
+                crate::supervisor::Subsystem::Mining,
+                "retry_connection: Template Provider reachable again, restarting with JD",
+            );
             break;
         }
     }
@@ -26,7 +26,7 @@ use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 use tracing::{error, info, warn};
 
 mod message_handler;
-mod setup_connection;
+pub mod setup_connection;
 
 pub type SendTo = SendTo_<roles_logic_sv2::parsers::TemplateDistribution<'static>, ()>;
 pub type Message = PoolMessages<'static>;
@@ -133,14 +133,20 @@ impl TemplateRx {
             Err(e) => {
                 // Update global tp state to down
                 error!("{e}");
-                ProxyState::update_tp_state(TpState::Down);
+                ProxyState::update_tp_state(
+                    TpState::Down,
+                    format!("TemplateRx: mutex poisoned: {e}"),
+                );
                 return;
             }
         };
         if sender_to_tp.send(either_frame).await.is_err() {
             error!("Failed to send msg to tp");
             // Update global tp state to down
-            ProxyState::update_tp_state(TpState::Down);
+            ProxyState::update_tp_state(
+                TpState::Down,
+                "TemplateRx: Template Provider connection closed",
+            );
         }
     }
 
@@ -219,7 +225,10 @@ impl TemplateRx {
                             Ok(jd) => jd,
                             Err(_) => {
                                 error!("Job declarator mutex poisoned!");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(
+                                    JdState::Down,
+                                    "TemplateRx: job declarator mutex poisoned",
+                                );
                                 break;
                             }
                         };
@@ -250,7 +259,10 @@ impl TemplateRx {
                                     None => {
                                         error!("Msg header not found");
                                         // Update global tp state to down
-                                        ProxyState::update_tp_state(TpState::Down);
+                                        ProxyState::update_tp_state(
+                                            TpState::Down,
+                                            "TemplateRx: header missing",
+                                        );
                                         break;
                                     }
                                 };
@@ -268,6 +280,11 @@ impl TemplateRx {
                                             // Send the new template along with the token to the JD so that JD can
                                             // declare the mining job
                                             Some(TemplateDistribution::NewTemplate(m)) => {
+                                                crate::api::status::new_template(
+                                                    m.template_id,
+                                                    m.future_template,
+                                                    m.coinbase_tx_value_remaining,
+                                                );
                                                 // See coment on the definition of the global for memory
                                                 // ordering
                                                 super::IS_NEW_TEMPLATE_HANDLED.store(
@@ -284,7 +301,10 @@ impl TemplateRx {
                                                 {
                                                     error!("TemplateRx Mutex is corrupt");
                                                     // Update global tp state to down
-                                                    ProxyState::update_tp_state(TpState::Down);
+                                                    ProxyState::update_tp_state(
+                                                        TpState::Down,
+                                                        "TemplateRx: mutex poisoned",
+                                                    );
                                                     break;
                                                 };
 
@@ -305,6 +325,9 @@ impl TemplateRx {
                                                     // Update global downstream state to down
                                                     ProxyState::update_downstream_state(
                                                         DownstreamType::JdClientMiningDownstream,
+                                                        format!(
+                                                            "TemplateRx: NewTemplate failed: {e:?}"
+                                                        ),
                                                     );
                                                 };
                                             }
@@ -324,7 +347,7 @@ impl TemplateRx {
                                                 m.clone(),
                                             ).await {
                                                 error!("{e:?}");
-                                                ProxyState::update_jd_state(JdState::Down); break;
+                                                ProxyState::update_jd_state(JdState::Down, format!("TemplateRx: SetNewPrevHash failed: {e:?}")); break;
                                             };
                                                 }
                                                 if let Err(e) =
@@ -332,7 +355,7 @@ impl TemplateRx {
                                                 {
                                                     error!("SetNewPrevHash Error: {e:?}");
                                                     // Update global tp state to down
-                                                    ProxyState::update_tp_state(TpState::Down);
+                                                    ProxyState::update_tp_state(TpState::Down, format!("TemplateRx: SetNewPrevHash failed: {e:?}"));
                                                     break;
                                                 };
                                             }
@@ -355,7 +378,12 @@ impl TemplateRx {
                                                     Err(e) => {
                                                         // Update global tp state to down
                                                         error!("TemplateRx mutex poisoned: {e}");
-                                                        ProxyState::update_tp_state(TpState::Down);
+                                                        ProxyState::update_tp_state(
+                                                            TpState::Down,
+                                                            format!(
+                                                                "TemplateRx: mutex poisoned: {e}"
+                                                            ),
+                                                        );
                                                         break;
                                                     }
                                                 };
@@ -421,13 +449,19 @@ impl TemplateRx {
                             } else {
                                 error!("Failed to covert TP message to StdFrame");
                                 // Update global tp state to down
-                                ProxyState::update_tp_state(TpState::Down);
+                                ProxyState::update_tp_state(
+                                    TpState::Down,
+                                    "TemplateRx: invalid frame",
+                                );
                             }
                         }
 
                         None => {
                             error!("Failed to receive msg");
-                            ProxyState::update_tp_state(TpState::Down);
+                            ProxyState::update_tp_state(
+                                TpState::Down,
+                                "TemplateRx: Template Provider connection closed",
+                            );
                             break;
                         }
                     };
@@ -445,7 +479,10 @@ impl TemplateRx {
                     error!("{e:?}");
                     // TemplateRx mutex poisoned
                     // Update global tp state to down
-                    ProxyState::update_tp_state(TpState::Down);
+                    ProxyState::update_tp_state(
+                        TpState::Down,
+                        format!("TemplateRx: mutex poisoned: {e:?}"),
+                    );
                     return;
                 }
             };
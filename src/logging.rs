@@ -0,0 +1,287 @@
+//! Logging setup.
+//!
+//! Logs go to stdout and, with `--log-file`, to a file rotated when it gets bigger than
+//! `log_max_size_mb` or older than `log_rotation`. Rotated files are renamed `<file>.1`,
+//! `<file>.2`, ... and only the `log_max_files` most recent ones are kept.
+//!
+//! With `--log-format json` every record is a JSON object with the fields of the current spans
+//! (`connection_id`, `channel_id`, `job_id`, `pool`, ...), so that they can be shipped as is.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, Layer, Registry,
+};
+
+use crate::config::Configuration;
+
+/// Sets up the global subscriber from the configuration.
+pub fn init() {
+    let loglevel = Configuration::loglevel();
+    let log_level = match loglevel.to_lowercase().as_str() {
+        "trace" | "debug" | "info" | "warn" | "error" => loglevel,
+        _ => {
+            eprintln!("Invalid log level '{}'. Defaulting to 'info'.", loglevel);
+            "info".to_string()
+        }
+    };
+
+    let nc_loglevel = Configuration::nc_loglevel();
+    let noise_connection_log_level = match nc_loglevel.as_str() {
+        "trace" | "debug" | "info" | "warn" | "error" => nc_loglevel,
+        _ => {
+            eprintln!(
+                "Invalid log level for noise_connection '{}' Defaulting to 'off'.",
+                nc_loglevel
+            );
+            "off".to_string()
+        }
+    };
+
+    let json = Configuration::log_format() == LogFormat::Json;
+    let file = Configuration::log_file().and_then(|path| {
+        match RotatingFile::open(
+            path.clone(),
+            Configuration::log_max_size_mb() * 1024 * 1024,
+            Configuration::log_rotation().interval(),
+            Configuration::log_max_files(),
+        ) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("Can not open log file {}: {e}", path.display());
+                None
+            }
+        }
+    });
+
+    //Disable noise_connection error (for now) because:
+    // 1. It produce logs that are not very user friendly and also bloat the logs
+    // 2. The errors resulting from noise_connection are handled. E.g if unrecoverable error from noise connection occurs during Pool connection: We either retry connecting immediatley or we update Proxy state to Pool Down
+    let mut layers = vec![fmt_layer(io::stdout, json)];
+    if let Some(file) = file {
+        layers.push(fmt_layer(file, json));
+    }
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(tracing_subscriber::EnvFilter::new(format!(
+            "{},demand_sv2_connection::noise_connection_tokio={}",
+            log_level, noise_connection_log_level
+        )))
+        .init();
+}
+
+fn fmt_layer<W>(writer: W, json: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    if json {
+        layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed()
+    } else {
+        layer.boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Invalid log format '{s}': expected text or json")),
+        }
+    }
+}
+
+/// Time based rotation of the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl LogRotation {
+    fn interval(self) -> Option<Duration> {
+        match self {
+            Self::Hourly => Some(Duration::from_secs(60 * 60)),
+            Self::Daily => Some(Duration::from_secs(24 * 60 * 60)),
+            Self::Never => None,
+        }
+    }
+}
+
+impl std::str::FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "Invalid log rotation '{s}': expected hourly, daily or never"
+            )),
+        }
+    }
+}
+
+/// Log file rotated by size and age.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_age: Option<Duration>,
+    max_files: usize,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_age: Option<Duration>,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let current = Current::open(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            max_age,
+            max_files,
+            current: Mutex::new(current),
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Shifts `<file>.n` to `<file>.n+1`, dropping the oldest, and starts a new file.
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        current.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *current = Current::open(&self.path)?;
+        Ok(())
+    }
+
+    fn write_record(&self, buf: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let too_big = current.size > 0 && current.size + buf.len() as u64 > self.max_size;
+        let too_old = self
+            .max_age
+            .map(|max_age| current.opened_at.elapsed().unwrap_or_default() >= max_age)
+            .unwrap_or(false);
+        if too_big || too_old {
+            if let Err(e) = self.rotate(&mut current) {
+                // Keep writing to the current file rather than losing the logs. Can not log
+                // from here, the subscriber is the one calling us.
+                eprintln!("Failed to rotate log file {}: {e}", self.path.display());
+            }
+        }
+        current.file.write_all(buf)?;
+        current.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl Current {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            size: metadata.len(),
+            // Creation time is not available everywhere, modification time is close enough
+            // for a file we keep appending to
+            opened_at: metadata
+                .created()
+                .or_else(|_| metadata.modified())
+                .unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+}
+
+/// Writer handed to the fmt layer, each record is written with a single `write` call.
+struct RotatingWriter<'a>(&'a RotatingFile);
+
+impl Write for RotatingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_record(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = RotatingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RotatingWriter(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let dir = std::env::temp_dir().join(format!("demand-cli-logs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.log");
+
+        let file = RotatingFile::open(path.clone(), 10, None, 2).unwrap();
+        for record in ["first----\n", "second---\n", "third----\n", "fourth---\n"] {
+            file.make_writer().write_all(record.as_bytes()).unwrap();
+        }
+
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth---\n");
+        assert_eq!(read(file.rotated_path(1)), "third----\n");
+        assert_eq!(read(file.rotated_path(2)), "second---\n");
+        assert!(!file.rotated_path(3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
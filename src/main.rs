@@ -1,122 +1,164 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 #[cfg(not(target_os = "windows"))]
 use jemallocator::Jemalloc;
 use router::Router;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 #[cfg(not(target_os = "windows"))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-use crate::shared::utils::AbortOnDrop;
-use key_utils::Secp256k1PublicKey;
+use crate::shared::{error::Sv1IngressError, utils::AbortOnDrop};
+use config::Configuration;
+use ingress::sv1_ingress::TranslatorRun;
 use lazy_static::lazy_static;
 use proxy_state::{PoolState, ProxyState, TpState, TranslatorState};
-use std::{net::ToSocketAddrs, time::Duration};
+use router::policy::PoolPolicy;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use supervisor::{Backoff, Subsystem};
 use tokio::sync::mpsc::channel;
 use tracing::{error, info, warn};
 
+mod api;
+mod blocks;
+mod config;
+mod doctor;
 mod ingress;
 pub mod jd_client;
+mod logging;
 mod minin_pool_connection;
 mod proxy_state;
 mod router;
+mod sd_notify;
 mod share_accounter;
 mod shared;
+mod shutdown;
+mod split;
+mod supervisor;
 mod translator;
+mod worker_stats;
 
 const TRANSLATOR_BUFFER_SIZE: usize = 32;
 const MIN_EXTRANONCE_SIZE: u16 = 6;
-const MIN_EXTRANONCE2_SIZE: u16 = 5;
 const UPSTREAM_EXTRANONCE1_SIZE: usize = 15;
-const DEFAULT_SV1_HASHPOWER: f32 = 100_000_000_000_000.0;
-const SHARE_PER_MIN: f32 = 10.0;
-const CHANNEL_DIFF_UPDTATE_INTERVAL: u32 = 10;
-const MAX_LEN_DOWN_MSG: u32 = 10000;
-const MAIN_POOL_ADDRESS: &str = "mining.dmnd.work:2000";
-const TEST_POOL_ADDRESS: &str =
-    "k8s-default-pool-de2d9b37ea-6bc40843aed871f2.elb.eu-central-1.amazonaws.com:2000";
-const MAIN_AUTH_PUB_KEY: &str = "9bQHWXsQ2J9TRFTaxRh3KjoxdyLRfWVEy25YHtKF8y8gotLoCZZ";
-const TEST_AUTH_PUB_KEY: &str = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72";
-//const TP_ADDRESS: &str = "127.0.0.1:8442";
-const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:32767";
+const UPSTREAMS_LATENCY_CHECK_INTERVAL: Duration = Duration::from_secs(100);
 
 lazy_static! {
-    static ref SV1_DOWN_LISTEN_ADDR: String =
-        std::env::var("SV1_DOWN_LISTEN_ADDR").unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string());
     static ref TP_ADDRESS: roles_logic_sv2::utils::Mutex<Option<String>> =
-        roles_logic_sv2::utils::Mutex::new(std::env::var("TP_ADDRESS").ok());
-    static ref EXPECTED_SV1_HASHPOWER: f32 = Args::parse()
-        .downstream_hashrate
-        .unwrap_or(DEFAULT_SV1_HASHPOWER);
+        roles_logic_sv2::utils::Mutex::new(Configuration::tp_address());
 }
 
 lazy_static! {
     static ref ARGS: Args = Args::parse();
-    pub static ref POOL_ADDRESS: &'static str = if ARGS.test {
-        TEST_POOL_ADDRESS
-    } else {
-        MAIN_POOL_ADDRESS
-    };
-    pub static ref AUTH_PUB_KEY: &'static str = if ARGS.test {
-        TEST_AUTH_PUB_KEY
-    } else {
-        MAIN_AUTH_PUB_KEY
-    };
 }
 #[derive(Parser)]
 struct Args {
-    // Use test enpoint if test flag is provided
-    #[clap(long)]
-    test: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+    // Path to a TOML configuration file, CLI flags and env variables override its values
+    #[clap(long, short = 'c')]
+    config: Option<PathBuf>,
+    // Use test enpoint if test flag is provided, `--test=false` overrides the config file
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    test: Option<bool>,
     #[clap(long ="d", short ='d', value_parser = parse_hashrate)]
     downstream_hashrate: Option<f32>,
-    #[clap(long = "loglevel", short = 'l', default_value = "info")]
-    loglevel: String,
-    #[clap(long = "nc", short = 'n', default_value = "off")]
-    noise_connection_log: String,
+    #[clap(long = "loglevel", short = 'l')]
+    loglevel: Option<String>,
+    #[clap(long = "nc", short = 'n')]
+    noise_connection_log: Option<String>,
+    // Log record format: text or json
+    #[clap(long)]
+    log_format: Option<String>,
+    // Also write the logs to this file, rotated by size and age
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    #[clap(long)]
+    log_max_size_mb: Option<u64>,
+    // Time based rotation of the log file: hourly, daily or never
+    #[clap(long)]
+    log_rotation: Option<String>,
+    // Number of rotated log files kept
+    #[clap(long)]
+    log_max_files: Option<usize>,
+    #[clap(long)]
+    token: Option<String>,
+    #[clap(long)]
+    tp_address: Option<String>,
+    #[clap(long)]
+    listen_address: Option<String>,
+    #[clap(long)]
+    pool_address: Option<String>,
+    // Upstream pool as `<address>[,<auth_pub_key>[,<token>]]`, can be repeated
+    #[clap(long = "pool")]
+    pools: Vec<String>,
+    #[clap(long)]
+    auth_pub_key: Option<String>,
+    #[clap(long)]
+    shares_per_minute: Option<f32>,
+    #[clap(long)]
+    channel_diff_update_interval: Option<u32>,
+    #[clap(long)]
+    max_len_down_msg: Option<u32>,
+    #[clap(long)]
+    min_extranonce2_size: Option<u16>,
+    // Port of the HTTP API serving /metrics and /status, disabled if not set
+    #[clap(long)]
+    api_port: Option<u16>,
+    // Address the HTTP API listens on, 127.0.0.1 by default
+    #[clap(long)]
+    api_address: Option<std::net::IpAddr>,
+    // Append every share sent to the pool and its acknowledgement to this file
+    #[clap(long)]
+    share_ledger: Option<PathBuf>,
+    // Seconds between two reconciliations of the ledger with the pool's share accounting
+    #[clap(long)]
+    reconcile_interval_secs: Option<u64>,
+    // Seconds the pool has to acknowledge a share before it is counted as lost
+    #[clap(long)]
+    ack_timeout_secs: Option<u64>,
+    // Number of shares lost in 10 minutes that raises an alert, 0 disables it
+    #[clap(long)]
+    lost_shares_alert_threshold: Option<usize>,
+    // File the blocks found by the miners are recorded in
+    #[clap(long)]
+    block_store: Option<PathBuf>,
+    // http:// URL a JSON description of each block found is posted to
+    #[clap(long)]
+    block_webhook: Option<String>,
+    // Command run with a JSON description of each block found on stdin
+    #[clap(long)]
+    block_exec: Option<String>,
+    // How the upstream pool is chosen: latency, priority or weighted
+    #[clap(long)]
+    pool_policy: Option<String>,
+    // Seconds a pool of higher priority must be reachable before going back to it
+    #[clap(long)]
+    fail_back_after_secs: Option<u64>,
+    // How the pools are probed: cheap (connection only) or full (channel and JD token)
+    #[clap(long)]
+    probe_mode: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run each startup step on its own and print a pass/fail report
+    Doctor,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-
-    let log_level = match args.loglevel.to_lowercase().as_str() {
-        "trace" | "debug" | "info" | "warn" | "error" => args.loglevel,
-        _ => {
-            error!(
-                "Invalid log level '{}'. Defaulting to 'info'.",
-                args.loglevel
-            );
-            "info".to_string()
-        }
-    };
+    if let Some(Command::Doctor) = ARGS.command {
+        std::process::exit(doctor::run().await);
+    }
 
-    let noise_connection_log_level = match args.noise_connection_log.as_str() {
-        "trace" | "debug" | "info" | "warn" | "error" => args.noise_connection_log,
-        _ => {
-            error!(
-                "Invalid log level for noise_connection '{}' Defaulting to 'off'.",
-                args.noise_connection_log
-            );
-            "off".to_string()
-        }
-    };
+    logging::init();
 
-    //Disable noise_connection error (for now) because:
-    // 1. It produce logs that are not very user friendly and also bloat the logs
-    // 2. The errors resulting from noise_connection are handled. E.g if unrecoverable error from noise connection occurs during Pool connection: We either retry connecting immediatley or we update Proxy state to Pool Down
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_subscriber::EnvFilter::new(format!(
-            "{},demand_sv2_connection::noise_connection_tokio={}",
-            log_level, noise_connection_log_level
-        )))
-        .init();
-    std::env::var("TOKEN").expect("Missing TOKEN environment variable");
-
-    let hashpower = *EXPECTED_SV1_HASHPOWER;
-
-    if args.downstream_hashrate.is_some() {
+    let hashpower = Configuration::downstream_hashrate();
+
+    if Configuration::has_downstream_hashrate() {
         info!(
             "Using downstream hashrate: {}h/s",
             HashUnit::format_value(hashpower)
@@ -127,209 +169,614 @@ async fn main() {
             HashUnit::format_value(hashpower)
         );
     }
-    if args.test {
+    if Configuration::test() {
         info!("Connecting to test endpoint...");
     }
 
-    let auth_pub_k: Secp256k1PublicKey = AUTH_PUB_KEY.parse().expect("Invalid public key");
-    let address = POOL_ADDRESS
-        .to_socket_addrs()
-        .expect("Invalid pool address")
-        .next()
-        .expect("Invalid pool address");
+    if let Some(api_port) = Configuration::api_port() {
+        api::start(std::net::SocketAddr::new(
+            Configuration::api_address(),
+            api_port,
+        ));
+    }
 
-    // We will add upstream addresses here
-    let pool_addresses = vec![address];
+    let pools = Configuration::pools();
+    for pool in &pools {
+        info!("Upstream pool configured: {}", pool.host);
+    }
 
-    let mut router = router::Router::new(pool_addresses, auth_pub_k, None, None);
+    let mut router = router::Router::new(pools, None, None);
+    let mut reload_signal = Configuration::reload_signal();
+    shutdown::listen_for_signals();
     let epsilon = Duration::from_millis(10);
-    let best_upstream = router.select_pool_connect().await;
-    initialize_proxy(&mut router, best_upstream, epsilon).await;
+    if Configuration::pool_policy() == PoolPolicy::Split {
+        split::run(&mut router, &mut reload_signal).await;
+    } else {
+        let best_upstream = router.select_pool_connect().await;
+        initialize_proxy(&mut router, best_upstream, epsilon, &mut reload_signal).await;
+    }
     info!("exiting");
-    tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+    if !shutdown::is_requested() {
+        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+    }
+}
+
+/// Connection of a miner accepted by the SV1 listener.
+type Sv1Downstream = (
+    tokio::sync::mpsc::Sender<String>,
+    tokio::sync::mpsc::Receiver<String>,
+    std::net::IpAddr,
+);
+
+/// The SV1 listener, restarted on its own: miners can connect while the other subsystems
+/// restart, their connections wait in the channel until the translator is up.
+struct Ingress {
+    downstreams: tokio::sync::mpsc::Sender<ingress::sv1_ingress::Attach>,
+    abortable: Option<AbortOnDrop>,
+    /// Address the listener last listened on
+    address: Option<String>,
+    backoff: Backoff,
+    restart_at: Option<tokio::time::Instant>,
 }
 
+impl Ingress {
+    async fn start(downstreams: tokio::sync::mpsc::Sender<ingress::sv1_ingress::Attach>) -> Self {
+        let mut ingress = Self {
+            downstreams,
+            abortable: None,
+            address: None,
+            backoff: Backoff::new(Subsystem::Ingress),
+            restart_at: None,
+        };
+        ingress.restart().await;
+        ingress
+    }
+
+    /// Listens on the configured address. If it can not be used the listener goes back to the
+    /// previous address, and is started again later if that fails too.
+    async fn restart(&mut self) {
+        if self.abortable.take().is_some() {
+            // Needs a little to time to drop and release the listen address
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        let address = Configuration::listen_address();
+        let mut result = self.listen(&address).await;
+        if let (Err(e), Some(previous)) = (&result, self.address.clone().filter(|p| *p != address))
+        {
+            error!("SV1 listener: {e}, listening on {previous} again");
+            result = self.listen(&previous).await;
+        }
+        match result {
+            Ok(()) => {
+                self.restart_at = None;
+                self.backoff.started(Instant::now());
+            }
+            Err(e) => {
+                let delay = self.backoff.failed(Instant::now());
+                error!("SV1 listener: {e}, restarting it in {}s", delay.as_secs());
+                self.restart_at = Some(tokio::time::Instant::now() + delay);
+            }
+        }
+    }
+
+    async fn listen(&mut self, address: &str) -> Result<(), Sv1IngressError> {
+        let abortable =
+            ingress::sv1_ingress::start_listen_for_downstream(address, self.downstreams.clone())
+                .await?;
+        self.abortable = Some(abortable);
+        self.address = Some(address.to_string());
+        Ok(())
+    }
+
+    /// Stops accepting new miners.
+    fn stop(&mut self) {
+        self.abortable = None;
+        self.restart_at = None;
+    }
+
+    /// Restarts the listener when it stops, never resolves.
+    async fn supervise(&mut self) {
+        loop {
+            if let Some(abortable) = self.abortable.as_mut() {
+                abortable.finished().await;
+                self.abortable = None;
+                let delay = self.backoff.failed(Instant::now());
+                error!(
+                    "SV1 listener stopped, restarting it in {}s",
+                    delay.as_secs()
+                );
+                self.restart_at = Some(tokio::time::Instant::now() + delay);
+            }
+            match self.restart_at {
+                Some(restart_at) => {
+                    tokio::time::sleep_until(restart_at).await;
+                    self.restart().await;
+                }
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Runs the subsystems, see [`supervisor`] for how they are restarted.
 async fn initialize_proxy(
     router: &mut Router,
-    mut pool_addr: Option<std::net::SocketAddr>,
+    mut pool_addr: Option<router::Candidate>,
     epsilon: Duration,
+    reload_signal: &mut tokio::sync::mpsc::Receiver<()>,
 ) {
+    let (downs_sv1_tx, downs_sv1_rx) = channel(10);
+    let downs_sv1_rx = Arc::new(tokio::sync::Mutex::new(downs_sv1_rx));
+    let mut ingress = Ingress::start(downs_sv1_tx).await;
+    let mut pool_backoff = Backoff::new(Subsystem::Pool);
+    let mut mining_backoff = Backoff::new(Subsystem::Mining);
+    let mut jd_backoff = Backoff::new(Subsystem::Jd);
     loop {
+        // Components of the previous run are stopped, start from a clean state
+        ProxyState::update_proxy_state_up("initialize_proxy: starting the components");
+
         // Initial setup for the proxy
         let (send_to_pool, recv_from_pool, pool_connection_abortable) =
-            match router.connect_pool(pool_addr).await {
+            match router.connect_pool(pool_addr.clone()).await {
                 Ok(connection) => connection,
+                Err(_) if shutdown::is_requested() => return,
                 Err(_) => {
                     error!("No upstream available. Retrying...");
-                    let mut secs = 10;
+                    let mut secs = pool_backoff.failed(Instant::now()).as_secs().max(1);
                     while secs > 0 {
+                        sd_notify::watchdog("No upstream available, retrying");
                         tracing::warn!("Retrying in {} seconds...", secs);
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        tokio::select! {
+                            _ = tokio::time::sleep(Duration::from_secs(1)) => (),
+                            // No share to drain without an upstream
+                            _ = shutdown::requested() => {
+                                sd_notify::stopping();
+                                ingress.stop();
+                                let notified = ingress::sv1_ingress::reconnect_all().await;
+                                info!("Sent client.reconnect to {notified} miners");
+                                return;
+                            }
+                        }
                         secs -= 1;
                     }
                     // Restart loop, esentially restarting proxy
                     continue;
                 }
             };
+        pool_backoff.started(Instant::now());
 
-        let (downs_sv1_tx, downs_sv1_rx) = channel(10);
-        let sv1_ingress_abortable = ingress::sv1_ingress::start_listen_for_downstream(downs_sv1_tx);
-
-        let (translator_up_tx, mut translator_up_rx) = channel(10);
-        let translator_abortable = match translator::start(downs_sv1_rx, translator_up_tx).await {
-            Ok(abortable) => abortable,
-            Err(e) => {
-                error!("Impossible to initialize translator: {e}");
-                // Impossible to start the proxy so we restart proxy
-                ProxyState::update_translator_state(TranslatorState::Down);
-                ProxyState::update_tp_state(TpState::Down);
-                return;
-            }
-        };
-
-        let (from_jdc_to_share_accounter_send, from_jdc_to_share_accounter_recv) = channel(10);
-        let (from_share_accounter_to_jdc_send, from_share_accounter_to_jdc_recv) = channel(10);
-        let (jdc_to_translator_sender, jdc_from_translator_receiver, _) = translator_up_rx
-            .recv()
-            .await
-            .expect("Translator failed before initialization");
-
-        let jdc_abortable: Option<AbortOnDrop>;
-        let share_accounter_abortable;
-        let tp = match TP_ADDRESS.safe_lock(|tp| tp.clone()) {
-            Ok(tp) => tp,
-            Err(e) => {
-                error!("TP_ADDRESS Mutex Corrupted: {e}");
-                return;
-            }
-        };
-
-        if let Some(_tp_addr) = tp {
-            jdc_abortable = jd_client::start(
-                jdc_from_translator_receiver,
-                jdc_to_translator_sender,
-                from_share_accounter_to_jdc_recv,
-                from_jdc_to_share_accounter_send,
-            )
-            .await;
-            if jdc_abortable.is_none() {
-                ProxyState::update_tp_state(TpState::Down);
-            };
-            share_accounter_abortable = match share_accounter::start(
-                from_jdc_to_share_accounter_recv,
-                from_share_accounter_to_jdc_send,
-                recv_from_pool,
-                send_to_pool,
-            )
-            .await
-            {
-                Ok(abortable) => abortable,
-                Err(_) => {
-                    error!("Failed to start share_accounter");
-                    return;
-                }
-            }
-        } else {
-            jdc_abortable = None;
-
-            share_accounter_abortable = match share_accounter::start(
-                jdc_from_translator_receiver,
-                jdc_to_translator_sender,
-                recv_from_pool,
-                send_to_pool,
-            )
-            .await
-            {
+        let (share_accounter_downstreams, downstreams) = channel(1);
+        let share_accounter_abortable =
+            match share_accounter::start(downstreams, recv_from_pool, send_to_pool).await {
                 Ok(abortable) => abortable,
                 Err(_) => {
                     error!("Failed to start share_accounter");
                     return;
                 }
             };
-        };
-
-        // Collecting all abort handles
-        let mut abort_handles = vec![
+        let mut pool_handles = vec![
             (pool_connection_abortable, "pool_connection".to_string()),
-            (sv1_ingress_abortable, "sv1_ingress".to_string()),
-            (translator_abortable, "translator".to_string()),
             (share_accounter_abortable, "share_accounter".to_string()),
         ];
-        if let Some(jdc_handle) = jdc_abortable {
-            abort_handles.push((jdc_handle, "jdc".to_string()));
-        }
 
-        match monitor(router, abort_handles, epsilon).await {
-            Reconnect::NewUpstream(new_pool_addr) => {
-                ProxyState::update_proxy_state_up();
-                pool_addr = Some(new_pool_addr);
-                continue;
+        let reconnect = loop {
+            // Subscribing before starting the mining subsystem so that the monitor sees its
+            // components going down right away
+            let mut proxy_state = ProxyState::subscribe();
+            proxy_state.mark_changed();
+            let Some(mut mining) =
+                start_mining(router, &downs_sv1_rx, &share_accounter_downstreams).await
+            else {
+                return;
+            };
+            mining_backoff.started(Instant::now());
+            if mining.jd() {
+                jd_backoff.started(Instant::now());
             }
-            Reconnect::NoUpstream => {
-                ProxyState::update_proxy_state_up();
-                pool_addr = None;
-                continue;
+
+            let outcome = loop {
+                api::status::proxy_initialized(router.current_pool(), mining.jd());
+                // Pool connection and SV1 listener are up, systemd ignores the repeated READY
+                sd_notify::ready();
+                let outcome = monitor(
+                    router,
+                    &mut ingress,
+                    &mut pool_handles,
+                    &mut mining.handles,
+                    epsilon,
+                    reload_signal,
+                    proxy_state,
+                )
+                .await;
+                // The translator keeps running and mines on the pool jobs
+                if let Reconnect::Failed(Subsystem::Jd) = outcome {
+                    if mining.detach_jd(&share_accounter_downstreams).await {
+                        jd_client::fall_back_to_pool_jobs(jd_backoff.failed(Instant::now()));
+                        ProxyState::update_jd_subsystem_up(
+                            "initialize_proxy: mining on the pool jobs without JD",
+                        );
+                        proxy_state = ProxyState::subscribe();
+                        proxy_state.mark_changed();
+                        continue;
+                    }
+                }
+                break outcome;
+            };
+            drop(mining);
+            // Needs a little to time to drop
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+
+            let delay = match outcome {
+                Reconnect::Mining => Duration::ZERO,
+                // The translator could not be kept, mining on the pool jobs does not depend on
+                // the TP so restart right away
+                Reconnect::Failed(Subsystem::Jd) => {
+                    jd_client::fall_back_to_pool_jobs(jd_backoff.failed(Instant::now()));
+                    Duration::ZERO
+                }
+                Reconnect::Failed(_) => mining_backoff.failed(Instant::now()),
+                reconnect => break reconnect,
+            };
+            if !delay.is_zero() {
+                warn!(
+                    "Restarting the mining subsystem in {} seconds...",
+                    delay.as_secs()
+                );
             }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                // The next monitor drains the shares
+                _ = shutdown::requested() => (),
+            }
+            ProxyState::update_proxy_state_up("initialize_proxy: restarting the mining subsystem");
         };
+
+        drop(pool_handles);
+        // Needs a little to time to drop
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        match reconnect {
+            Reconnect::NewUpstream(new_pool_addr) => pool_addr = Some(new_pool_addr),
+            Reconnect::Shutdown => return,
+            _ => pool_addr = None,
+        }
     }
 }
 
+/// Mining subsystem started by [`start_mining`].
+struct MiningSubsystem {
+    /// Dropped before the handles, so that the miners the translator drops when it stops are
+    /// moved to the next one
+    _run: TranslatorRun,
+    handles: Vec<(AbortOnDrop, String)>,
+    /// Ends of the translator channel relayed to the JD client, the translator is attached to the
+    /// share accounter with them when the JD client fails
+    translator: Option<(
+        Arc<
+            tokio::sync::Mutex<
+                tokio::sync::mpsc::Receiver<roles_logic_sv2::parsers::Mining<'static>>,
+            >,
+        >,
+        tokio::sync::mpsc::Sender<roles_logic_sv2::parsers::Mining<'static>>,
+    )>,
+}
+
+impl MiningSubsystem {
+    fn jd(&self) -> bool {
+        self.handles.iter().any(|(_, name)| name == "jdc")
+    }
+
+    /// Stops the JD client and attaches the translator to the share accounter, on the channels
+    /// it opened through the JD client. Returns false when JD is not used.
+    async fn detach_jd(
+        &mut self,
+        share_accounter: &tokio::sync::mpsc::Sender<share_accounter::Downstream>,
+    ) -> bool {
+        let Some((from_translator, to_translator)) = self.translator.take() else {
+            return false;
+        };
+        // Releases the ends of the translator channel held by the JD client relays
+        self.handles.retain(|(_, name)| !name.starts_with("jdc"));
+        let (relay_tx, relay_rx) = channel(10);
+        self.handles.push((
+            supervisor::relay(from_translator, relay_tx),
+            "translator_relay".to_string(),
+        ));
+        let downstream = share_accounter::Downstream {
+            receiver: relay_rx,
+            sender: to_translator,
+            takes_over_channels: true,
+        };
+        if share_accounter.send(downstream).await.is_err() {
+            // The monitor restarts the pool subsystem when it sees the share accounter finished
+            error!("Share accounter stopped before the translator was attached");
+        }
+        info!("JD client stopped, the translator mines on the pool jobs");
+        true
+    }
+}
+
+/// Starts the mining subsystem on top of the share accounter: the translator, and the JD
+/// client when a Template Provider is used.
+async fn start_mining(
+    router: &Router,
+    downs_sv1_rx: &Arc<
+        tokio::sync::Mutex<tokio::sync::mpsc::Receiver<ingress::sv1_ingress::Attach>>,
+    >,
+    share_accounter: &tokio::sync::mpsc::Sender<share_accounter::Downstream>,
+) -> Option<MiningSubsystem> {
+    let (translator_downs_tx, translator_downs_rx) = channel(10);
+    let run = TranslatorRun::default();
+    let sv1_relay_abortable =
+        ingress::sv1_ingress::relay(downs_sv1_rx.clone(), translator_downs_tx, run.handle());
+
+    let (translator_up_tx, mut translator_up_rx) = channel(10);
+    let translator_abortable = match translator::start(translator_downs_rx, translator_up_tx).await
+    {
+        Ok(abortable) => abortable,
+        Err(e) => {
+            error!("Impossible to initialize translator: {e}");
+            // Impossible to start the proxy so we restart proxy
+            ProxyState::update_translator_state(
+                TranslatorState::Down,
+                format!("initialize_proxy: translator failed to start: {e}"),
+            );
+            ProxyState::update_tp_state(
+                TpState::Down,
+                format!("initialize_proxy: translator failed to start: {e}"),
+            );
+            return None;
+        }
+    };
+
+    let (jdc_to_translator_sender, jdc_from_translator_receiver, _) = translator_up_rx
+        .recv()
+        .await
+        .expect("Translator failed before initialization");
+
+    let tp = match TP_ADDRESS.safe_lock(|tp| tp.clone()) {
+        Ok(tp) => tp,
+        Err(e) => {
+            error!("TP_ADDRESS Mutex Corrupted: {e}");
+            return None;
+        }
+    };
+
+    let current_pool = router
+        .current_pool()
+        .expect("Router always sets the current pool when connected");
+    let mut mining = MiningSubsystem {
+        _run: run,
+        handles: vec![
+            (sv1_relay_abortable, "sv1_relay".to_string()),
+            (translator_abortable, "translator".to_string()),
+        ],
+        translator: None,
+    };
+    let downstream = if let Some(_tp_addr) = tp {
+        let (from_jdc_to_share_accounter_send, from_jdc_to_share_accounter_recv) = channel(10);
+        let (from_share_accounter_to_jdc_send, from_share_accounter_to_jdc_recv) = channel(10);
+        // The translator channel is relayed to the JD client, so that it outlives it
+        let (to_jdc, jdc_receiver) = channel(10);
+        let (jdc_sender, from_jdc) = channel(10);
+        let from_translator = Arc::new(tokio::sync::Mutex::new(jdc_from_translator_receiver));
+        match jd_client::start(
+            jdc_receiver,
+            jdc_sender,
+            from_share_accounter_to_jdc_recv,
+            from_jdc_to_share_accounter_send,
+            current_pool,
+        )
+        .await
+        {
+            Some(jdc_abortable) => {
+                mining.handles.extend([
+                    (jdc_abortable, "jdc".to_string()),
+                    (
+                        supervisor::relay(from_translator.clone(), to_jdc),
+                        "jdc_relay_up".to_string(),
+                    ),
+                    (
+                        supervisor::relay(
+                            Arc::new(tokio::sync::Mutex::new(from_jdc)),
+                            jdc_to_translator_sender.clone(),
+                        ),
+                        "jdc_relay_down".to_string(),
+                    ),
+                ]);
+            }
+            None => ProxyState::update_tp_state(
+                TpState::Down,
+                "initialize_proxy: JD client failed to start",
+            ),
+        }
+        mining.translator = Some((from_translator, jdc_to_translator_sender));
+        share_accounter::Downstream::new(
+            from_jdc_to_share_accounter_recv,
+            from_share_accounter_to_jdc_send,
+        )
+    } else {
+        share_accounter::Downstream::new(jdc_from_translator_receiver, jdc_to_translator_sender)
+    };
+    if share_accounter.send(downstream).await.is_err() {
+        // The monitor restarts the pool subsystem when it sees the share accounter finished
+        error!("Share accounter stopped before the mining subsystem started");
+    }
+    Some(mining)
+}
+
+/// Waits for the first of the monitored tasks to finish and returns its name.
+async fn task_finished(abort_handles: &mut [(AbortOnDrop, String)]) -> String {
+    let finished = abort_handles.iter_mut().map(|(handle, name)| {
+        Box::pin(async move {
+            handle.finished().await;
+            name.clone()
+        })
+    });
+    futures::future::select_all(finished).await.0
+}
+
 async fn monitor(
     router: &mut Router,
-    abort_handles: Vec<(AbortOnDrop, std::string::String)>,
+    ingress: &mut Ingress,
+    pool_handles: &mut Vec<(AbortOnDrop, String)>,
+    mining_handles: &mut [(AbortOnDrop, String)],
     epsilon: Duration,
+    reload_signal: &mut tokio::sync::mpsc::Receiver<()>,
+    mut proxy_state: tokio::sync::watch::Receiver<ProxyState>,
 ) -> Reconnect {
-    let mut should_check_upstreams_latency = 0;
-    loop {
-        // Check if a better upstream exist every 100 seconds
-        if should_check_upstreams_latency == 10 * 100 {
-            should_check_upstreams_latency = 0;
-            if let Some(new_upstream) = router.monitor_upstream(epsilon).await {
-                info!("Faster upstream detected. Reinitializing proxy...");
-                drop(abort_handles);
-                // Needs a little to time to drop
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-                return Reconnect::NewUpstream(new_upstream);
+    // Check if a better upstream exist every UPSTREAMS_LATENCY_CHECK_INTERVAL
+    let mut upstreams_latency_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + UPSTREAMS_LATENCY_CHECK_INTERVAL,
+        UPSTREAMS_LATENCY_CHECK_INTERVAL,
+    );
+    let mut watchdog = tokio::time::interval(Duration::from_secs(1));
+    let reconnect = loop {
+        tokio::select! {
+            _ = shutdown::requested() => {
+                sd_notify::stopping();
+                // Stop accepting new miners, the connected ones are asked to reconnect elsewhere
+                ingress.stop();
+                let notified = ingress::sv1_ingress::reconnect_all().await;
+                info!("Sent client.reconnect to {notified} miners");
+                // Keep the pool connection up until the in-flight shares are acknowledged
+                shutdown::drain(1).await;
+                pool_handles.clear();
+                break Reconnect::Shutdown;
             }
-        }
-
-        // Monitor finished tasks
-        if let Some((_handle, name)) = abort_handles
-            .iter()
-            .find(|(handle, _name)| handle.is_finished())
-        {
-            error!("Task {:?} finished, Closing connection", name);
-            for (handle, _name) in abort_handles {
-                drop(handle);
+            Some(()) = reload_signal.recv() => {
+                if let Some(reconnect) = reload_configuration(router) {
+                    break reconnect;
+                }
             }
-
-            // Check if the proxy state is down, and if so, reinitialize the proxy.
-            let is_proxy_down = ProxyState::is_proxy_down();
-            if is_proxy_down.0 {
-                error!(
-                    "Status: {:?}. Reinitializing proxy...",
-                    is_proxy_down.1.unwrap_or("Proxy".to_string())
-                );
-                return Reconnect::NoUpstream;
-            } else {
-                return Reconnect::NoUpstream;
+            _ = upstreams_latency_check.tick() => {
+                if let Some(new_upstream) = router.monitor_upstream(epsilon).await {
+                    info!("Better upstream detected. Reinitializing proxy...");
+                    break Reconnect::NewUpstream(new_upstream);
+                }
+            }
+            _ = ingress.supervise() => (),
+            name = task_finished(pool_handles.as_mut_slice()) => {
+                error!("Task {:?} finished, Closing connection", name);
+                if let (true, Some(down)) = ProxyState::is_proxy_down() {
+                    error!("Status: {:?}. Reinitializing proxy...", down);
+                }
+                router::health::disconnected();
+                break Reconnect::NoUpstream;
+            }
+            name = task_finished(mining_handles) => {
+                error!("Task {:?} finished, restarting the mining subsystem", name);
+                break Reconnect::Failed(if name.starts_with("jdc") {
+                    Subsystem::Jd
+                } else {
+                    Subsystem::Mining
+                });
+            }
+            Ok(()) = proxy_state.changed() => {
+                let (is_proxy_down, errors) = {
+                    let state = proxy_state.borrow_and_update();
+                    (state.is_down(), state.errors())
+                };
+                sd_notify::status(&sd_notify::status_from_proxy_state(is_proxy_down.clone()));
+                if let Some(subsystem) = Subsystem::failed(&errors) {
+                    error!(
+                        "{:?} is DOWN. Restarting the {subsystem} subsystem...",
+                        is_proxy_down.1.unwrap_or("Proxy".to_string())
+                    );
+                    break match subsystem {
+                        Subsystem::Pool => {
+                            router::health::disconnected();
+                            Reconnect::NoUpstream
+                        }
+                        subsystem => Reconnect::Failed(subsystem),
+                    };
+                }
+            }
+            (subsystem, reason) = supervisor::restart_requested() => {
+                info!("Restarting the {subsystem} subsystem: {reason}");
+                match subsystem {
+                    Subsystem::Ingress => ingress.restart().await,
+                    Subsystem::Pool => break Reconnect::NoUpstream,
+                    Subsystem::Mining | Subsystem::Jd => break Reconnect::Mining,
+                }
+            }
+            _ = watchdog.tick() => {
+                sd_notify::watchdog(&sd_notify::status_from_proxy_state(
+                    ProxyState::is_proxy_down(),
+                ));
             }
         }
+    };
+    reconnect
+}
 
-        // Check if the proxy state is down, and if so, reinitialize the proxy.
-        let is_proxy_down = ProxyState::is_proxy_down();
-        if is_proxy_down.0 {
-            error!(
-                "{:?} is DOWN. Reinitializing proxy...",
-                is_proxy_down.1.unwrap_or("Proxy".to_string())
-            );
-            drop(abort_handles); // Drop all abort handles
-            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await; // Needs a little to time to drop
-            return Reconnect::NoUpstream;
+/// Reloads the configuration and applies what can be applied to the running proxy. Returns
+/// `Some` if the pool or the mining subsystem must be restarted for the new configuration to
+/// take effect, the SV1 listener is restarted on its own.
+fn reload_configuration(router: &mut Router) -> Option<Reconnect> {
+    info!("Reloading configuration");
+    let changes = match Configuration::reload() {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("Invalid configuration, keeping the current one: {e}");
+            return None;
         }
+    };
+    let mut live = changes.live;
+    let mut ignored = changes.ignored;
+    // Splitting the hashrate needs the process to start in split mode
+    if Configuration::pool_policy() == PoolPolicy::Split && live.contains(&"pool_policy") {
+        live.retain(|setting| *setting != "pool_policy");
+        ignored.push("pool_policy");
+    }
+    if !live.is_empty() {
+        info!("Configuration applied: {}", live.join(", "));
+    }
+    if !ignored.is_empty() {
+        warn!(
+            "Configuration changes need a restart of the process: {}",
+            ignored.join(", ")
+        );
+    }
 
-        should_check_upstreams_latency += 1;
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let mut keep_current_pool = true;
+    if changes.pools {
+        info!("Upstream pools updated");
+        keep_current_pool = router.update_pools(Configuration::pools());
+    }
+    if changes.restart.contains(&"tp_address")
+        && TP_ADDRESS
+            .safe_lock(|tp| *tp = Configuration::tp_address())
+            .is_err()
+    {
+        error!("TP_ADDRESS Mutex Corrupted");
+    }
+    if changes.restart.contains(&"listen_address") {
+        supervisor::restart(
+            Subsystem::Ingress,
+            "reload_configuration: listen_address changed",
+        );
+    }
+    // The other settings are used by the translator and the JD client
+    let mining_changes: Vec<&str> = changes
+        .restart
+        .iter()
+        .copied()
+        .filter(|setting| *setting != "listen_address")
+        .collect();
+
+    match router.current_pool() {
+        _ if !keep_current_pool => {
+            info!("Current upstream removed or changed. Reinitializing proxy...");
+            Some(Reconnect::NoUpstream)
+        }
+        Some(_) if !mining_changes.is_empty() => {
+            info!(
+                "Restarting the mining subsystem to apply: {}",
+                mining_changes.join(", ")
+            );
+            Some(Reconnect::Mining)
+        }
+        _ => None,
     }
 }
 
@@ -367,8 +814,11 @@ fn parse_hashrate(hashrate_str: &str) -> Result<f32, String> {
 }
 
 pub enum Reconnect {
-    NewUpstream(std::net::SocketAddr), // Reconnecting with a new upstream
-    NoUpstream,                        // Reconnecting without upstream
+    NewUpstream(router::Candidate), // Reconnecting with a new upstream
+    NoUpstream,                     // Reconnecting without upstream
+    Mining,                         // Restarting the mining subsystem on the same upstream
+    Failed(Subsystem),              // Restarting a failed subsystem under the pool one
+    Shutdown,                       // Graceful shutdown completed
 }
 
 enum HashUnit {
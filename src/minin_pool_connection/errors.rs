@@ -5,6 +5,8 @@ pub enum Error {
     BinarySv2(binary_sv2::Error),
     /// Errors on bad noise handshake.
     SV2Connection(demand_sv2_connection::Error),
+    /// Errors on an authority public key the noise handshake can not use.
+    InvalidAuthorityKey(noise_sv2::Error),
     /// Errors from `framing_sv2` crate.
     FramingSv2(framing_sv2::Error),
     /// Errors on bad `TcpStream` connection.
@@ -25,6 +27,7 @@ impl fmt::Display for Error {
         match self {
             BinarySv2(ref e) => write!(f, "Binary SV2 error: `{:?}`", e),
             SV2Connection(ref e) => write!(f, "Demand SV2 connectiom  error: `{:?}", e),
+            InvalidAuthorityKey(ref e) => write!(f, "Invalid authority public key: `{:?}`", e),
             FramingSv2(ref e) => write!(f, "Framing SV2 error: `{:?}`", e),
             Io(ref e) => write!(f, "I/O error: `{:?}", e),
             RolesSv2Logic(ref e) => write!(f, "Roles SV2 Logic Error: `{:?}`", e),
@@ -16,7 +16,7 @@ use roles_logic_sv2::{
 };
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{error, info};
+use tracing::{error, info, Instrument};
 
 use crate::{proxy_state::ProxyState, shared::utils::AbortOnDrop, PoolState};
 use task_manager::TaskManager;
@@ -30,6 +30,7 @@ const DEFAULT_TIMER: std::time::Duration = std::time::Duration::from_secs(5);
 pub async fn connect_pool(
     address: SocketAddr,
     authority_public_key: Secp256k1PublicKey,
+    token: &str,
     setup_connection_msg: Option<SetupConnection<'static>>,
     timer: Option<std::time::Duration>,
 ) -> Result<
@@ -53,8 +54,10 @@ pub async fn connect_pool(
         }
     };
 
-    let initiator =
-        Initiator::from_raw_k(authority_public_key.into_bytes()).expect("Invalid authority key");
+    let initiator = Initiator::from_raw_k(authority_public_key.into_bytes()).map_err(|e| {
+        error!("Invalid authority public key");
+        Error::InvalidAuthorityKey(e)
+    })?;
 
     info!(
         "PROXY SERVER - ACCEPTING FROM UPSTREAM: {}",
@@ -70,7 +73,7 @@ pub async fn connect_pool(
                 Error::SV2Connection(e)
             })?;
     let setup_connection_msg =
-        setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true));
+        setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true, token));
     match mining_setup_connection(
         &mut receiver,
         &mut sender,
@@ -107,21 +110,24 @@ pub fn relay_up(
     mut recv: Receiver<PoolExtMessages<'static>>,
     send: Sender<EitherFrame>,
 ) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
+    let task = crate::proxy_state::spawn(Instrument::in_current_span(async move {
         while let Some(msg) = recv.recv().await {
             let std_frame: Result<StdFrame, _> = msg.try_into();
             if let Ok(std_frame) = std_frame {
                 let either_frame: EitherFrame = std_frame.into();
                 if send.send(either_frame).await.is_err() {
                     error!("Mining upstream failed");
-                    ProxyState::update_pool_state(PoolState::Down);
+                    ProxyState::update_pool_state(
+                        PoolState::Down,
+                        "relay_up: pool connection closed",
+                    );
                     break;
                 };
             } else {
                 panic!("Internal Mining downstream try to send invalid message");
             }
         }
-    });
+    }));
     task.into()
 }
 
@@ -129,7 +135,7 @@ pub fn relay_down(
     mut recv: Receiver<EitherFrame>,
     send: Sender<PoolExtMessages<'static>>,
 ) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
+    let task = crate::proxy_state::spawn(Instrument::in_current_span(async move {
         while let Some(msg) = recv.recv().await {
             let msg: Result<StdFrame, ()> = msg.try_into().map_err(|_| ());
             if let Ok(mut msg) = msg {
@@ -145,7 +151,9 @@ pub fn relay_down(
                             error!("Internal Mining downstream not available");
 
                             // Update Proxy state to reflect Internal inconsistency
-                            ProxyState::update_inconsistency(Some(1));
+                            ProxyState::update_inconsistency(
+                                "relay_down: mining downstream closed",
+                            );
                         }
                     } else {
                         error!("Mining Upstream send non Mining message. Disconnecting");
@@ -161,8 +169,8 @@ pub fn relay_down(
             }
         }
         error!("Failed to receive msg from Pool");
-        ProxyState::update_pool_state(PoolState::Down);
-    });
+        ProxyState::update_pool_state(PoolState::Down, "relay_down: pool connection closed");
+    }));
     task.into()
 }
 
@@ -210,7 +218,10 @@ pub async fn mining_setup_connection(
     }
 }
 
-pub fn get_mining_setup_connection_msg(work_selection: bool) -> SetupConnection<'static> {
+pub fn get_mining_setup_connection_msg(
+    work_selection: bool,
+    token: &str,
+) -> SetupConnection<'static> {
     let endpoint_host = "0.0.0.0".to_string().into_bytes().try_into().expect("Internal error: this operation can not fail because the string 0.0.0.0 can always be converted into Inner");
     let vendor = String::new().try_into().expect("Internal error: this operation can not fail because an empty string can always be converted into Inner");
     let hardware_version = String::new().try_into().expect("Internal error: this operation can not fail because an empty string can always be converted into Inner");
@@ -219,7 +230,6 @@ pub fn get_mining_setup_connection_msg(work_selection: bool) -> SetupConnection<
         false => 0b0000_0000_0000_0000_0000_0000_0000_0100,
         true => 0b0000_0000_0000_0000_0000_0000_0000_0110,
     };
-    let token = std::env::var("TOKEN").expect("Checked at initialization");
     let device_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
     let device_id = format!("{}::POOLED::{}", device_id, token)
         .to_string()
@@ -1,11 +1,160 @@
-use std::sync::Arc;
+//! Global state of the proxy components.
+//!
+//! Components publish their transitions with the `update_*` functions. The state lives in a
+//! watch channel: readers get the current value without contending with the writers, and
+//! [`ProxyState::subscribe`] lets the monitor react as soon as a component goes down.
+//!
+//! Every transition is recorded with its reason, the last ones are served by the API so that
+//! the cause of a restart can be found afterwards.
+//!
+//! When the hashrate is split each pipeline runs its components in a [`Scope`]: the updates of
+//! the components, and the reads of the supervisor, go to the state of the scope instead of the
+//! global one. The components spawn their tasks with [`spawn`] so that they stay in the scope
+//! they were started in.
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use lazy_static::lazy_static;
-use roles_logic_sv2::utils::Mutex;
-use tracing::{error, info};
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::info;
+
+/// Number of transitions kept in memory.
+const HISTORY_LEN: usize = 100;
 
 lazy_static! {
-    static ref PROXY_STATE: Arc<Mutex<ProxyState>> = Arc::new(Mutex::new(ProxyState::new()));
+    static ref PROXY_STATE: watch::Sender<ProxyState> = watch::Sender::new(ProxyState::new());
+    static ref HISTORY: Mutex<History> = Mutex::new(History::default());
+    /// Every scope created, gone once all the clones of a scope are dropped
+    static ref SCOPES: Mutex<Vec<ScopeEntry>> = Mutex::new(Vec::new());
+}
+
+/// Name and state of a scope, weak so that the scope is dropped with the components in it.
+type ScopeEntry = (Arc<str>, Weak<watch::Sender<ProxyState>>);
+
+tokio::task_local! {
+    static SCOPE: Scope;
+}
+
+/// State of the components run in it, separate from the global one.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    /// Recorded with the transitions
+    name: Arc<str>,
+    state: Arc<watch::Sender<ProxyState>>,
+}
+
+impl Scope {
+    pub fn new(name: &str) -> Self {
+        let scope = Self {
+            name: name.into(),
+            state: Arc::new(watch::Sender::new(ProxyState::new())),
+        };
+        let mut scopes = SCOPES.lock().unwrap_or_else(|e| e.into_inner());
+        scopes.retain(|(_, state)| state.strong_count() > 0);
+        scopes.push((scope.name.clone(), Arc::downgrade(&scope.state)));
+        scope
+    }
+
+    /// Runs `future` in the scope.
+    pub async fn run<F: Future>(&self, future: F) -> F::Output {
+        SCOPE.scope(self.clone(), future).await
+    }
+}
+
+/// Spawns `future` in the scope of the caller, if it runs in one.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match SCOPE.try_with(Scope::clone) {
+        Ok(scope) => tokio::spawn(SCOPE.scope(scope, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+/// Name of the scope of the caller, `None` outside of any.
+pub fn scope() -> Option<Arc<str>> {
+    SCOPE.try_with(|scope| scope.name.clone()).ok()
+}
+
+/// Name and state of every scope alive, in the order they were created. The API reports them
+/// next to the global state.
+pub fn scopes() -> Vec<(Arc<str>, ProxyState)> {
+    let scopes = SCOPES.lock().unwrap_or_else(|e| e.into_inner());
+    scopes
+        .iter()
+        .filter_map(|(name, state)| Some((name.clone(), state.upgrade()?.borrow().clone())))
+        .collect()
+}
+
+/// Runs `f` on the state of the current scope, or on the global state.
+fn with_state<T>(f: impl FnOnce(&watch::Sender<ProxyState>, Option<&str>) -> T) -> T {
+    let scope = SCOPE.try_with(Scope::clone).ok();
+    match &scope {
+        Some(scope) => f(&scope.state, Some(&scope.name)),
+        None => f(&PROXY_STATE, None),
+    }
+}
+
+/// A change of state of a component.
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    /// Number of transitions since the proxy started
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub timestamp_ms: u64,
+    pub component: &'static str,
+    /// Scope of the component, `None` for the global state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    pub state: String,
+    /// What caused the transition, as `<task>: <what happened>`
+    pub reason: String,
+}
+
+/// Last `HISTORY_LEN` transitions, oldest first.
+#[derive(Debug, Default)]
+struct History {
+    next_seq: u64,
+    transitions: VecDeque<Transition>,
+}
+
+impl History {
+    fn record(
+        &mut self,
+        component: &'static str,
+        scope: Option<&str>,
+        state: String,
+        reason: String,
+    ) {
+        if self.transitions.len() == HISTORY_LEN {
+            self.transitions.pop_front();
+        }
+        self.next_seq += 1;
+        self.transitions.push_back(Transition {
+            seq: self.next_seq,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            component,
+            scope: scope.map(str::to_string),
+            state,
+            reason,
+        });
+    }
+}
+
+/// Last transitions of the components, oldest first.
+pub fn history() -> Vec<Transition> {
+    let history = HISTORY.lock().unwrap_or_else(|e| e.into_inner());
+    history.transitions.iter().cloned().collect()
 }
 
 /// Main enum representing the overall state of the proxy
@@ -85,7 +234,7 @@ pub enum UpstreamType {
 }
 
 /// Represents global proxy state
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ProxyState {
     pub pool: PoolState,
     pub tp: TpState,
@@ -111,136 +260,126 @@ impl ProxyState {
         }
     }
 
-    pub fn update_pool_state(pool_state: PoolState) {
-        info!("Updating PoolState state to {:?}", pool_state);
-        if PROXY_STATE
-            .safe_lock(|state| {
-                state.pool = pool_state;
-                // // state.update_proxy_state();
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+    pub fn update_pool_state(pool_state: PoolState, reason: impl Into<String>) {
+        update("pool", format!("{pool_state:?}"), reason.into(), |state| {
+            state.pool = pool_state;
+        });
     }
 
-    pub fn update_tp_state(tp_state: TpState) {
-        info!("Updating TpState state to {:?}", tp_state);
-        if PROXY_STATE
-            .safe_lock(|state| {
-                state.tp = tp_state;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+    pub fn update_tp_state(tp_state: TpState, reason: impl Into<String>) {
+        update("tp", format!("{tp_state:?}"), reason.into(), |state| {
+            state.tp = tp_state;
+        });
     }
 
-    pub fn update_jd_state(jd_state: JdState) {
-        info!("Updating JdState state to {:?}", jd_state);
-        if PROXY_STATE
-            .safe_lock(|state| {
-                state.jd = jd_state;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+    pub fn update_jd_state(jd_state: JdState, reason: impl Into<String>) {
+        update("jd", format!("{jd_state:?}"), reason.into(), |state| {
+            state.jd = jd_state;
+        });
     }
 
-    pub fn update_translator_state(translator_state: TranslatorState) {
-        info!("Updating Translator state to {:?}", translator_state);
-        if PROXY_STATE
-            .safe_lock(|state| {
+    pub fn update_translator_state(translator_state: TranslatorState, reason: impl Into<String>) {
+        update(
+            "translator",
+            format!("{translator_state:?}"),
+            reason.into(),
+            |state| {
                 state.translator = translator_state;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+            },
+        );
     }
 
-    pub fn update_share_accounter_state(share_accounter_state: ShareAccounterState) {
-        info!(
-            "Updating ShareAccounterState state to {:?}",
-            share_accounter_state
-        );
-        if PROXY_STATE
-            .safe_lock(|state| {
+    pub fn update_share_accounter_state(
+        share_accounter_state: ShareAccounterState,
+        reason: impl Into<String>,
+    ) {
+        update(
+            "share_accounter",
+            format!("{share_accounter_state:?}"),
+            reason.into(),
+            |state| {
                 state.share_accounter = share_accounter_state;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+            },
+        );
     }
 
-    pub fn update_inconsistency(code: Option<u32>) {
-        info!("Updating Internal Inconsistency state to {:?}", code);
-        if PROXY_STATE
-            .safe_lock(|state| {
-                state.inconsistency = code;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+    /// Reports an internal inconsistency, the proxy is restarted. The state counts the
+    /// inconsistencies reported since the last restart.
+    pub fn update_inconsistency(reason: impl Into<String>) {
+        update(
+            "inconsistency",
+            "Down".to_string(),
+            reason.into(),
+            |state| {
+                state.inconsistency = Some(state.inconsistency.unwrap_or(0) + 1);
+            },
+        );
     }
 
-    pub fn update_downstream_state(downstream_type: DownstreamType) {
-        info!("Updating Downstream state to {:?}", downstream_type);
-        if PROXY_STATE
-            .safe_lock(|state| {
+    pub fn update_downstream_state(downstream_type: DownstreamType, reason: impl Into<String>) {
+        update(
+            "downstream",
+            format!("Down({downstream_type:?})"),
+            reason.into(),
+            |state| {
                 state.downstream = DownstreamState::Down(vec![downstream_type]);
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+            },
+        );
     }
 
-    pub fn update_upstream_state(upstream_type: UpstreamType) {
-        info!("Updating Upstream state to {:?}", upstream_type);
-        if PROXY_STATE
-            .safe_lock(|state| {
+    pub fn update_upstream_state(upstream_type: UpstreamType, reason: impl Into<String>) {
+        update(
+            "upstream",
+            format!("Down({upstream_type:?})"),
+            reason.into(),
+            |state| {
                 state.upstream = UpstreamState::Down(vec![upstream_type]);
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+            },
+        );
     }
 
-    pub fn update_proxy_state_up() {
-        if PROXY_STATE
-            .safe_lock(|state| {
-                state.pool = PoolState::Up;
-                state.jd = JdState::Up;
-                state.translator = TranslatorState::Up;
-                state.tp = TpState::Up;
-                state.share_accounter = ShareAccounterState::Up;
-                state.upstream = UpstreamState::Up;
-                state.downstream = DownstreamState::Up;
-                state.inconsistency = None;
-            })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        }
+    /// Clears the failures of the JD client and the Template Provider, once the translator mines
+    /// on the pool jobs without them.
+    pub fn update_jd_subsystem_up(reason: impl Into<String>) {
+        update("jd", "Up".to_string(), reason.into(), |state| {
+            state.tp = TpState::Up;
+            state.jd = JdState::Up;
+            if let DownstreamState::Down(down) = &state.downstream {
+                if down.contains(&DownstreamType::JdClientMiningDownstream) {
+                    state.downstream = DownstreamState::Up;
+                }
+            }
+            if let UpstreamState::Down(up) = &state.upstream {
+                if up.contains(&UpstreamType::JDCMiningUpstream) {
+                    state.upstream = UpstreamState::Up;
+                }
+            }
+        });
+    }
+
+    pub fn update_proxy_state_up(reason: impl Into<String>) {
+        update("proxy", "Up".to_string(), reason.into(), |state| {
+            *state = ProxyState::new();
+        });
+    }
+
+    /// Receiver notified of every state change.
+    pub fn subscribe() -> watch::Receiver<ProxyState> {
+        with_state(|state, _| state.subscribe())
     }
 
     pub fn is_proxy_down() -> (bool, Option<String>) {
-        let errors = Self::get_errors();
-        if errors.is_ok() && errors.as_ref().unwrap().is_empty() {
+        with_state(|state, _| state.borrow().is_down())
+    }
+
+    pub fn get_errors() -> Vec<ProxyStates> {
+        with_state(|state, _| state.borrow().errors())
+    }
+
+    /// Returns true and the components that are down, if any.
+    pub fn is_down(&self) -> (bool, Option<String>) {
+        let errors = self.errors();
+        if errors.is_empty() {
             (false, None)
         } else {
             let error_descriptions: Vec<String> =
@@ -249,41 +388,156 @@ impl ProxyState {
         }
     }
 
-    pub fn get_errors() -> Result<Vec<ProxyStates>, ()> {
+    pub fn errors(&self) -> Vec<ProxyStates> {
         let mut errors = Vec::new();
-        if PROXY_STATE
-            .safe_lock(|state| {
-                if state.pool == PoolState::Down {
-                    errors.push(ProxyStates::Pool(state.pool));
-                }
-                if state.tp == TpState::Down {
-                    errors.push(ProxyStates::Tp(state.tp));
-                }
-                if state.jd == JdState::Down {
-                    errors.push(ProxyStates::Jd(state.jd));
-                }
-                if state.share_accounter == ShareAccounterState::Down {
-                    errors.push(ProxyStates::ShareAccounter(state.share_accounter));
-                }
-                if state.translator == TranslatorState::Down {
-                    errors.push(ProxyStates::Translator(state.translator));
-                }
-                if let Some(inconsistency) = state.inconsistency {
-                    errors.push(ProxyStates::InternalInconsistency(inconsistency));
-                }
-                if matches!(state.downstream, DownstreamState::Down(_)) {
-                    errors.push(ProxyStates::Downstream(state.downstream.clone()));
-                }
-                if matches!(state.upstream, UpstreamState::Down(_)) {
-                    errors.push(ProxyStates::Upstream(state.upstream.clone()));
+        if self.pool == PoolState::Down {
+            errors.push(ProxyStates::Pool(self.pool));
+        }
+        if self.tp == TpState::Down {
+            errors.push(ProxyStates::Tp(self.tp));
+        }
+        if self.jd == JdState::Down {
+            errors.push(ProxyStates::Jd(self.jd));
+        }
+        if self.share_accounter == ShareAccounterState::Down {
+            errors.push(ProxyStates::ShareAccounter(self.share_accounter));
+        }
+        if self.translator == TranslatorState::Down {
+            errors.push(ProxyStates::Translator(self.translator));
+        }
+        if let Some(inconsistency) = self.inconsistency {
+            errors.push(ProxyStates::InternalInconsistency(inconsistency));
+        }
+        if matches!(self.downstream, DownstreamState::Down(_)) {
+            errors.push(ProxyStates::Downstream(self.downstream.clone()));
+        }
+        if matches!(self.upstream, UpstreamState::Down(_)) {
+            errors.push(ProxyStates::Upstream(self.upstream.clone()));
+        }
+        errors
+    }
+}
+
+/// Applies `f` to the state. If it changed the transition is recorded and the subscribers are
+/// notified.
+fn update(
+    component: &'static str,
+    new_state: String,
+    reason: String,
+    f: impl FnOnce(&mut ProxyState),
+) {
+    with_state(|state, scope| {
+        let changed = state.send_if_modified(|state| {
+            let before = state.clone();
+            f(state);
+            *state != before
+        });
+        if changed {
+            match scope {
+                Some(scope) => {
+                    info!("Updating {component} state of {scope} to {new_state}: {reason}")
                 }
+                None => info!("Updating {component} state to {new_state}: {reason}"),
+            }
+            let mut history = HISTORY.lock().unwrap_or_else(|e| e.into_inner());
+            history.record(component, scope, new_state, reason);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn notifies_and_records_changes_only() {
+        // In a scope of its own, the global state is shared with the other tests
+        let scope = Scope::new("notifies.example.com:2000");
+        scope
+            .run(async {
+                let mut state = ProxyState::subscribe();
+                ProxyState::update_pool_state(PoolState::Down, "relay_down: pool closed socket");
+                assert!(state.has_changed().unwrap());
+                assert_eq!(
+                    state.borrow_and_update().is_down(),
+                    (true, Some("Pool(Down)".to_string()))
+                );
+
+                // Same state again
+                ProxyState::update_pool_state(PoolState::Down, "relay_up: pool closed socket");
+                assert!(!state.has_changed().unwrap());
+
+                ProxyState::update_inconsistency("test: first");
+                ProxyState::update_inconsistency("test: second");
+                assert_eq!(state.borrow_and_update().inconsistency, Some(2));
+
+                ProxyState::update_proxy_state_up("test: restart");
+                assert!(state.has_changed().unwrap());
+                assert_eq!(ProxyState::is_proxy_down(), (false, None));
             })
-            .is_err()
-        {
-            error!("Global Proxy Mutex Corrupted");
-            std::process::exit(1);
-        } else {
-            Ok(errors)
+            .await;
+
+        let history: Vec<_> = history()
+            .into_iter()
+            .filter(|t| t.scope.as_deref() == Some("notifies.example.com:2000"))
+            .collect();
+        let reasons: Vec<_> = history.iter().map(|t| t.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            [
+                "relay_down: pool closed socket",
+                "test: first",
+                "test: second",
+                "test: restart"
+            ]
+        );
+        assert_eq!(history[0].component, "pool");
+        assert_eq!(history[0].state, "Down");
+    }
+
+    #[test]
+    fn keeps_the_last_transitions() {
+        let mut history = History::default();
+        for i in 0..=HISTORY_LEN {
+            history.record("pool", None, "Down".to_string(), format!("test: {i}"));
         }
+        assert_eq!(history.transitions.len(), HISTORY_LEN);
+        assert_eq!(history.transitions[0].reason, "test: 1");
+        assert!(history
+            .transitions
+            .iter()
+            .zip(2..)
+            .all(|(t, seq)| t.seq == seq));
+    }
+
+    #[tokio::test]
+    async fn scopes_have_their_own_state() {
+        let scope = Scope::new("pool.example.com:2000");
+        let state = scope.run(async { ProxyState::subscribe() }).await;
+        scope
+            .run(async {
+                // Tasks spawned in the scope stay in it
+                spawn(async {
+                    ProxyState::update_translator_state(TranslatorState::Down, "test: scoped")
+                })
+                .await
+                .unwrap();
+            })
+            .await;
+        assert_eq!(state.borrow().translator, TranslatorState::Down);
+        assert!(scope.run(async { ProxyState::is_proxy_down().0 }).await);
+        assert_ne!(PROXY_STATE.borrow().translator, TranslatorState::Down);
+        let transition = history()
+            .into_iter()
+            .rfind(|t| t.reason == "test: scoped")
+            .unwrap();
+        assert_eq!(transition.scope.as_deref(), Some("pool.example.com:2000"));
+        assert!(scopes()
+            .iter()
+            .any(|(name, state)| &**name == "pool.example.com:2000" && state.is_down().0));
+        drop(scope);
+        assert!(!scopes()
+            .iter()
+            .any(|(name, _)| &**name == "pool.example.com:2000"));
     }
 }
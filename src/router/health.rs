@@ -0,0 +1,280 @@
+//! Health of the upstreams, beyond their latency.
+//!
+//! The components record what they see of the pool the proxy is connected to: disconnections,
+//! shares rejected or acknowledged with `ShareOk`, shares never acknowledged and jobs received.
+//! The router records the setups that failed, when connecting or probing. The health of a pool
+//! is a number between 0 and 1 computed from the events of the last [`HEALTH_WINDOW`], counted
+//! per minute. The router ranks the pools on it before their latency, see
+//! [`super::policy::Score`].
+//!
+//! The events are attributed to the pool of the last [`connected`], so they are not recorded
+//! when the hashrate is split across the pools.
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+/// Events older than this do not count anymore
+const HEALTH_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// Health kept at each disconnection and each failed setup in the window
+const FAILURE_FACTOR: f64 = 0.9;
+/// Shares assumed answered when computing the reject and loss rates, so that one reject out of
+/// the first two shares does not halve the health
+const RATE_PRIOR_SHARES: f64 = 10.0;
+/// Without a job for this long the pool is considered stale. Pools send new jobs as their
+/// templates change, not only on new blocks.
+const JOB_STALE_AFTER: Duration = Duration::from_secs(20 * 60);
+/// Health kept by a stale pool
+const STALE_FACTOR: f64 = 0.5;
+/// The events are counted per minute, a bucket expires with the first of its events
+const BUCKET_SPAN: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref HEALTH: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Pool the events of the components are attributed to
+    current: Option<SocketAddr>,
+    pools: HashMap<SocketAddr, PoolHealth>,
+}
+
+impl Registry {
+    fn connected(&mut self, pool: SocketAddr, now: Instant) {
+        if let Some(previous) = self.current.take() {
+            if let Some(previous) = self.pools.get_mut(&previous) {
+                previous.connected_at = None;
+                previous.last_job = None;
+            }
+        }
+        let health = self.pools.entry(pool).or_default();
+        health.connected_at = Some(now);
+        health.last_job = None;
+        self.current = Some(pool);
+    }
+
+    fn record(&mut self, pool: SocketAddr, event: Event, count: u32, now: Instant) {
+        self.pools
+            .entry(pool)
+            .or_default()
+            .record(event, count, now);
+    }
+
+    fn record_current(&mut self, event: Event, count: u32, now: Instant) {
+        if let Some(current) = self.current {
+            self.record(current, event, count, now);
+        }
+    }
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    // The counters are only added to, a poisoned lock still holds consistent ones
+    HEALTH.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Event {
+    Disconnected,
+    SetupFailed,
+    ShareAcknowledged,
+    ShareRejected,
+    ShareLost,
+}
+
+/// Number of [`Event`] kinds, the counters of a bucket are indexed by the event
+const EVENT_KINDS: usize = 5;
+
+/// Events of a pool during one minute, counted by kind.
+#[derive(Debug)]
+struct Bucket {
+    started_at: Instant,
+    counts: [u32; EVENT_KINDS],
+}
+
+/// Events of a pool over the last [`HEALTH_WINDOW`], one bucket per minute with events.
+#[derive(Debug, Default)]
+struct PoolHealth {
+    buckets: VecDeque<Bucket>,
+    /// Since when the proxy is connected to the pool, `None` when it is not
+    connected_at: Option<Instant>,
+    last_job: Option<Instant>,
+}
+
+impl PoolHealth {
+    fn record(&mut self, event: Event, count: u32, now: Instant) {
+        self.expire(now);
+        let bucket = match self.buckets.back_mut() {
+            Some(bucket) if now.saturating_duration_since(bucket.started_at) < BUCKET_SPAN => {
+                bucket
+            }
+            _ => {
+                self.buckets.push_back(Bucket {
+                    started_at: now,
+                    counts: [0; EVENT_KINDS],
+                });
+                self.buckets.back_mut().expect("bucket just pushed")
+            }
+        };
+        let counter = &mut bucket.counts[event as usize];
+        *counter = counter.saturating_add(count);
+    }
+
+    /// Drops the buckets that started before the window.
+    fn expire(&mut self, now: Instant) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| now.saturating_duration_since(bucket.started_at) > HEALTH_WINDOW)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn count(&self, event: Event) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| u64::from(bucket.counts[event as usize]))
+            .sum()
+    }
+
+    /// Health of the pool, 1 when nothing went wrong in the window.
+    fn health(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let acknowledged = self.count(Event::ShareAcknowledged) as f64;
+        let rejected = self.count(Event::ShareRejected) as f64;
+        let lost = self.count(Event::ShareLost) as f64;
+        let failures = self.count(Event::Disconnected) + self.count(Event::SetupFailed);
+
+        let mut health = FAILURE_FACTOR.powi(failures.min(i32::MAX as u64) as i32);
+        health *= 1.0 - rejected / (acknowledged + rejected + RATE_PRIOR_SHARES);
+        health *= 1.0 - lost / (acknowledged + rejected + lost + RATE_PRIOR_SHARES);
+        let last_activity = self.last_job.or(self.connected_at);
+        if last_activity.is_some_and(|at| now.saturating_duration_since(at) > JOB_STALE_AFTER) {
+            health *= STALE_FACTOR;
+        }
+        health
+    }
+}
+
+/// Called when the proxy connects to `pool`, the next events are attributed to it.
+pub fn connected(pool: SocketAddr) {
+    registry().connected(pool, Instant::now());
+}
+
+/// Called when the connection to the current pool is lost.
+pub fn disconnected() {
+    registry().record_current(Event::Disconnected, 1, Instant::now());
+}
+
+/// Called when a connection to `pool` could not be set up.
+pub fn setup_failed(pool: SocketAddr) {
+    registry().record(pool, Event::SetupFailed, 1, Instant::now());
+}
+
+pub fn share_acknowledged() {
+    registry().record_current(Event::ShareAcknowledged, 1, Instant::now());
+}
+
+pub fn share_rejected() {
+    registry().record_current(Event::ShareRejected, 1, Instant::now());
+}
+
+pub fn shares_lost(count: usize) {
+    let count = u32::try_from(count).unwrap_or(u32::MAX);
+    registry().record_current(Event::ShareLost, count, Instant::now());
+}
+
+pub fn new_job() {
+    let mut registry = registry();
+    if let Some(current) = registry.current {
+        registry.pools.entry(current).or_default().last_job = Some(Instant::now());
+    }
+}
+
+/// Health of `pool`, 1 for a pool nothing is known of.
+pub fn health(pool: SocketAddr) -> f64 {
+    registry()
+        .pools
+        .get_mut(&pool)
+        .map(|health| health.health(Instant::now()))
+        .unwrap_or(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_drops_with_rejects_failures_and_stale_jobs() {
+        let now = Instant::now();
+        let mut pool = PoolHealth::default();
+        assert_eq!(pool.health(now), 1.0);
+
+        // 10% of the shares rejected
+        for i in 0..100 {
+            let event = match i % 10 {
+                0 => Event::ShareRejected,
+                _ => Event::ShareAcknowledged,
+            };
+            pool.record(event, 1, now);
+        }
+        let health = pool.health(now);
+        assert!(health < 0.92 && health > 0.9, "{health}");
+
+        pool.record(Event::Disconnected, 1, now);
+        assert!((pool.health(now) - health * FAILURE_FACTOR).abs() < 1e-9);
+
+        // The events expire, a pool without jobs is stale
+        pool.connected_at = Some(now);
+        let later = now + HEALTH_WINDOW + Duration::from_secs(1);
+        assert_eq!(pool.health(later), STALE_FACTOR);
+        pool.last_job = Some(later);
+        assert_eq!(pool.health(later), 1.0);
+    }
+
+    #[test]
+    fn events_expire_with_their_minute() {
+        let now = Instant::now();
+        let mut pool = PoolHealth::default();
+        pool.record(Event::Disconnected, 1, now);
+        // Same minute, same bucket
+        pool.record(Event::ShareLost, 50, now + Duration::from_secs(30));
+        pool.record(Event::Disconnected, 1, now + Duration::from_secs(20 * 60));
+        assert_eq!(pool.buckets.len(), 2);
+        assert_eq!(pool.count(Event::ShareLost), 50);
+
+        let later = now + HEALTH_WINDOW + Duration::from_secs(1);
+        assert_eq!(pool.health(later), FAILURE_FACTOR);
+        assert_eq!(pool.buckets.len(), 1);
+        let much_later = later + Duration::from_secs(20 * 60);
+        assert_eq!(pool.health(much_later), 1.0);
+        assert!(pool.buckets.is_empty());
+    }
+
+    #[test]
+    fn setup_failures_go_to_their_pool_and_events_to_the_current_one() {
+        let now = Instant::now();
+        let current: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let probed: SocketAddr = "127.0.0.2:2000".parse().unwrap();
+        let mut registry = Registry::default();
+
+        // Nothing is attributed before a connection
+        registry.record_current(Event::ShareRejected, 1, now);
+        assert!(registry.pools.is_empty());
+
+        registry.connected(current, now);
+        registry.record(probed, Event::SetupFailed, 1, now);
+        registry.record_current(Event::ShareLost, 3, now);
+        let current = &registry.pools[&current];
+        assert_eq!(current.count(Event::SetupFailed), 0);
+        assert_eq!(current.count(Event::ShareLost), 3);
+        let probed = &registry.pools[&probed];
+        assert_eq!(probed.count(Event::SetupFailed), 1);
+        assert_eq!(probed.count(Event::ShareLost), 0);
+    }
+}
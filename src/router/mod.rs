@@ -3,7 +3,10 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::jd_client::job_declarator::{setup_connection::SetupConnectionHandler, JobDeclarator};
+use crate::{
+    config::{Configuration, PoolConfig},
+    jd_client::job_declarator::{setup_connection::SetupConnectionHandler, JobDeclarator},
+};
 use codec_sv2::{buffer_sv2::Slice, HandshakeRole};
 use demand_share_accounting_ext::parser::PoolExtMessages;
 use demand_sv2_connection::noise_connection_tokio::Connection;
@@ -14,27 +17,82 @@ use tokio::{
     net::TcpStream,
     sync::mpsc::{Receiver, Sender},
 };
-use tracing::{error, info};
+use tracing::{error, info, warn, Instrument};
 
 use crate::{
     minin_pool_connection::{self, get_mining_setup_connection_msg, mining_setup_connection},
     shared::utils::AbortOnDrop,
 };
 
+pub mod health;
+pub mod policy;
+
+use policy::{PoolPolicy, Score, Selection};
+
+/// Deadline of a probe, the pools are probed concurrently and share it
+const PROBE_DEADLINE: Duration = Duration::from_secs(15);
+
+/// How the latency of the pools is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProbeMode {
+    /// Connection and SetupConnection only, no channel is opened on the pool
+    #[default]
+    Cheap,
+    /// Also opens a channel and waits for the first job, and gets a token from the JDS when in
+    /// JD mode
+    Full,
+}
+
+impl std::str::FromStr for ProbeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cheap" => Ok(Self::Cheap),
+            "full" => Ok(Self::Full),
+            _ => Err(format!("Invalid probe mode '{s}': expected cheap or full")),
+        }
+    }
+}
+
+/// A pool and one of the addresses its host resolved to, the router selects among them. Two
+/// pools behind the same address are two candidates, each connected to with its own key and
+/// token. The health and latency are those of the address.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub address: SocketAddr,
+    pub pool: PoolConfig,
+}
+
+impl Candidate {
+    /// Returns true if `other` is the same pool at the same address.
+    pub fn is(&self, other: &Candidate) -> bool {
+        self.address == other.address && self.pool.host == other.pool.host
+    }
+}
+
+impl std::fmt::Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.pool.host, self.address)
+    }
+}
+
 /// Router handles connection to Multiple upstreams.
 pub struct Router {
-    pool_addresses: Vec<SocketAddr>,
-    current_pool: Option<SocketAddr>,
-    auth_pub_k: Secp256k1PublicKey,
+    /// Pools as configured, identified by their host
+    pools: Vec<PoolConfig>,
+    /// One entry per address the hosts of `pools` resolved to, the pools are selected among them
+    candidates: Vec<Candidate>,
+    current_pool: Option<Candidate>,
     setup_connection_msg: Option<SetupConnection<'static>>,
     timer: Option<Duration>,
+    selection: Selection,
 }
 
 impl Router {
-    /// Creates a new `Router` instance with the specified upstream addresses.
+    /// Creates a new `Router` instance with the specified upstreams.
     pub fn new(
-        pool_addresses: Vec<SocketAddr>,
-        auth_pub_k: Secp256k1PublicKey,
+        pools: Vec<PoolConfig>,
         // Configuration msg used to setup connection between client and pool
         // If not, present `get_mining_setup_connection_msg()` is called to generated default values
         setup_connection_msg: Option<SetupConnection<'static>>,
@@ -43,36 +101,221 @@ impl Router {
         timer: Option<Duration>,
     ) -> Self {
         Self {
-            pool_addresses,
+            // Resolved before each selection
+            candidates: Vec::new(),
+            pools,
             current_pool: None,
-            auth_pub_k,
             setup_connection_msg,
             timer,
+            selection: Selection::default(),
         }
     }
 
-    /// Internal function to select pool with the least latency.
-    async fn select_pool(&self) -> Option<(SocketAddr, Duration)> {
-        let mut best_pool = None;
-        let mut least_latency = Duration::MAX;
+    /// Internal function to select a pool according to the configured policy.
+    async fn select_pool(&mut self) -> Option<(Candidate, Duration)> {
+        let pools = self.candidates.clone();
+        let latencies = self.probe(&pools).await;
+        let mut reachable: Vec<(&Candidate, Duration)> = pools
+            .iter()
+            .zip(latencies)
+            .filter_map(|(pool, latency)| Some((pool, latency?)))
+            .collect();
+        match Configuration::pool_policy() {
+            PoolPolicy::Latency => {
+                let scores: Vec<Score> = reachable
+                    .iter()
+                    .map(|(pool, latency)| Score {
+                        health: health::health(pool.address),
+                        latency: *latency,
+                    })
+                    .collect();
+                policy::best(&scores).map(|index| {
+                    let (pool, latency) = reachable[index];
+                    (pool.clone(), latency)
+                })
+            }
+            PoolPolicy::Priority => {
+                // Stable, the configuration order breaks ties
+                reachable.sort_by_key(|(candidate, _)| candidate.pool.priority);
+                reachable
+                    .first()
+                    .map(|(pool, latency)| ((*pool).clone(), *latency))
+            }
+            PoolPolicy::Weighted | PoolPolicy::Split => {
+                // The fastest address of each pool is the one it is picked with
+                reachable.sort_by_key(|(_, latency)| *latency);
+                let candidates: Vec<&Candidate> =
+                    reachable.iter().map(|(candidate, _)| *candidate).collect();
+                let selected = self.selection.next_weighted(&candidates)?;
+                reachable
+                    .into_iter()
+                    .find(|(pool, _)| pool.is(selected))
+                    .map(|(pool, latency)| (pool.clone(), latency))
+            }
+        }
+    }
 
-        for &pool_addr in &self.pool_addresses {
-            if let Ok(latency) = self.get_latency(pool_addr).await {
-                if latency < least_latency {
-                    least_latency = latency;
-                    best_pool = Some(pool_addr)
+    /// Measures the latency of `pools` concurrently, returns the smoothed latency of the ones
+    /// that answered before [`PROBE_DEADLINE`]. The stages measured are reported for every
+    /// pool, even the ones that did not answer in time.
+    async fn probe(&mut self, pools: &[Candidate]) -> Vec<Option<Duration>> {
+        let deadline = tokio::time::Instant::now() + PROBE_DEADLINE;
+        let full = Configuration::probe_mode() == ProbeMode::Full;
+        let mut latencies: Vec<PoolLatency> = pools
+            .iter()
+            .map(|pool| PoolLatency::new(pool.address))
+            .collect();
+        let setup_connection_msg = &self.setup_connection_msg;
+        let timer = self.timer;
+        let measured = futures::future::join_all(pools.iter().zip(latencies.iter_mut()).map(
+            move |(pool, latency)| async move {
+                let measure =
+                    latency.measure(setup_connection_msg.clone(), timer, &pool.pool, full);
+                match tokio::time::timeout_at(deadline, measure).await {
+                    Ok(result) => result.is_ok(),
+                    Err(_) => {
+                        error!(
+                            "Failed to get mining setup latencies for {:?}: Timeout",
+                            pool.address
+                        );
+                        false
+                    }
+                }
+            },
+        ))
+        .await;
+
+        let now = Instant::now();
+        latencies
+            .into_iter()
+            .zip(measured)
+            .map(|(latency, measured)| {
+                let sample = measured.then(|| latency.total());
+                if sample.is_none() {
+                    health::setup_failed(latency.pool);
+                }
+                self.selection.probed(latency.pool, sample, now);
+                let smoothed = sample.and(self.selection.latency(latency.pool));
+                crate::api::metrics::pool_latency(latency.pool, latency.stages());
+                crate::api::status::pool_probed(
+                    latency.pool,
+                    smoothed,
+                    health::health(latency.pool),
+                    latency.stages(),
+                );
+                smoothed
+            })
+            .collect()
+    }
+
+    /// Resolves the host of each pool again, every address returned is a candidate. A pool that
+    /// does not resolve keeps its previous addresses, if it ever resolved, and the address of the
+    /// current pool stays a candidate while the proxy is connected to it.
+    async fn resolve(&mut self) {
+        let resolved = futures::future::join_all(self.pools.iter().map(resolve_pool)).await;
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for (pool, resolved) in self.pools.iter().zip(resolved) {
+            let previous = self
+                .candidates
+                .iter()
+                .filter(|c| c.pool.same_upstream(pool));
+            let mut addresses: Vec<SocketAddr> = match resolved {
+                Ok(resolved) => resolved.iter().map(|c| c.address).collect(),
+                Err(e) => {
+                    warn!(
+                        "Failed to resolve pool {}, keeping its previous addresses: {e}",
+                        pool.host
+                    );
+                    previous.clone().map(|c| c.address).collect()
+                }
+            };
+            if let Some(current) = self.current_pool.as_ref() {
+                if current.pool.same_upstream(pool) && !addresses.contains(&current.address) {
+                    addresses.push(current.address);
+                }
+            }
+            if !previous.map(|c| c.address).eq(addresses.iter().copied()) {
+                let list: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                info!("Pool {} resolves to {}", pool.host, list.join(", "));
+            }
+            for address in addresses {
+                let candidate = Candidate {
+                    address,
+                    pool: pool.clone(),
+                };
+                // Another pool behind the same address is another candidate
+                if !candidates.iter().any(|c| c.is(&candidate)) {
+                    candidates.push(candidate);
                 }
             }
         }
+        let addresses: Vec<SocketAddr> = candidates.iter().map(|c| c.address).collect();
+        self.selection.retain(&addresses);
+        crate::api::metrics::pools_resolved(&addresses);
+        crate::api::status::pools_resolved(&addresses);
+        self.candidates = candidates;
+    }
+
+    /// The candidate for the same pool and address as `candidate`, with the pool as currently
+    /// configured.
+    fn get_pool(&self, candidate: &Candidate) -> Option<&Candidate> {
+        self.candidates.iter().find(|pool| pool.is(candidate))
+    }
 
-        best_pool.map(|pool| (pool, least_latency))
+    /// Pools the router selects from
+    pub fn pools(&self) -> &[PoolConfig] {
+        &self.pools
+    }
+
+    /// Returns the pool the proxy is currently connected to
+    pub fn current_pool(&self) -> Option<Candidate> {
+        self.current_pool.clone()
+    }
+
+    /// Replaces the pools the router selects from. Returns false if the current pool has been
+    /// removed or its credentials changed, in that case the proxy must reconnect.
+    pub fn update_pools(&mut self, pools: Vec<PoolConfig>) -> bool {
+        // The pools that were already configured keep the addresses they resolved to, the new
+        // ones are resolved at the next selection
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for pool in &pools {
+            candidates.extend(
+                self.candidates
+                    .iter()
+                    .filter(|c| c.pool.same_upstream(pool))
+                    .map(|c| Candidate {
+                        address: c.address,
+                        pool: pool.clone(),
+                    }),
+            );
+        }
+        self.candidates = candidates;
+        self.pools = pools;
+        let Some(current_pool) = self.current_pool.take() else {
+            return true;
+        };
+        let Some(pool) = self
+            .pools
+            .iter()
+            .find(|p| p.same_upstream(&current_pool.pool))
+        else {
+            return false;
+        };
+        self.current_pool = Some(Candidate {
+            address: current_pool.address,
+            pool: pool.clone(),
+        });
+        true
     }
 
     /// Select the best pool for connection
-    pub async fn select_pool_connect(&self) -> Option<SocketAddr> {
+    pub async fn select_pool_connect(&mut self) -> Option<Candidate> {
+        // Not connected anymore, the address of the previous pool is not kept as a candidate
+        self.current_pool = None;
+        self.resolve().await;
         info!("Selecting the best upstream ");
         if let Some((pool, latency)) = self.select_pool().await {
-            info!("Latency for upstream {:?} is {:?}", pool, latency);
+            info!("Latency for upstream {} is {:?}", pool, latency);
             Some(pool)
         } else {
             //info!("No available pool");
@@ -81,31 +324,80 @@ impl Router {
     }
 
     /// Select the best pool for monitoring
-    async fn select_pool_monitor(&self, epsilon: Duration) -> Option<SocketAddr> {
-        if let Some((best_pool, best_pool_latency)) = self.select_pool().await {
-            if let Some(current_pool) = self.current_pool {
-                if best_pool == current_pool {
-                    return None;
-                }
-                let current_latency = match self.get_latency(current_pool).await {
-                    Ok(latency) => latency,
-                    Err(e) => {
-                        error!("Failed to get latency: {:?}", e);
-                        return None;
-                    }
-                };
-                if best_pool_latency < (current_latency - epsilon) {
-                    info!(
-                        "Found faster pool: {:?} with latency {:?}",
-                        best_pool, best_pool_latency
-                    );
-                    return Some(best_pool);
-                } else {
-                    return None;
-                }
-            } else {
-                return Some(best_pool);
+    async fn select_pool_monitor(&mut self, epsilon: Duration) -> Option<Candidate> {
+        self.resolve().await;
+        let current_pool = self.current_pool.clone().filter(|current_pool| {
+            self.pools
+                .iter()
+                .any(|pool| pool.same_upstream(&current_pool.pool))
+        });
+        match (Configuration::pool_policy(), current_pool) {
+            // Not connected, or the current pool is not configured anymore
+            (_, None) => self.select_pool().await.map(|(pool, _)| pool),
+            (PoolPolicy::Latency, Some(_)) => self.select_better_pool(epsilon).await,
+            (PoolPolicy::Priority, Some(current_pool)) => {
+                self.select_preferred_pool(&current_pool).await
             }
+            // Only reconnections are balanced, a pool that is up is kept
+            (PoolPolicy::Weighted | PoolPolicy::Split, Some(_)) => None,
+        }
+    }
+
+    /// Returns a pool of higher priority than `current_pool` that has been reachable for long
+    /// enough to go back to it, and is about as healthy.
+    async fn select_preferred_pool(&mut self, current_pool: &Candidate) -> Option<Candidate> {
+        let fail_back_after = Configuration::fail_back_after();
+        let preferred: Vec<Candidate> = policy::by_priority(&self.candidates)
+            .into_iter()
+            .filter(|candidate| candidate.pool.priority < current_pool.pool.priority)
+            .cloned()
+            .collect();
+        let latencies = self.probe(&preferred).await;
+        let now = Instant::now();
+        let current_health = health::health(current_pool.address);
+        for (pool, latency) in preferred.iter().zip(latencies) {
+            if latency.is_none() {
+                continue;
+            }
+            let pool_health = health::health(pool.address);
+            if !policy::as_healthy(pool_health, current_health) {
+                info!(
+                    "Upstream {} of higher priority is less healthy: {:.2} against {:.2}",
+                    pool.address, pool_health, current_health
+                );
+                continue;
+            }
+            let reachable_for = self.selection.reachable_for(pool.address, now);
+            if reachable_for >= fail_back_after {
+                info!(
+                    "Upstream {} of higher priority reachable for {}s, going back to it",
+                    pool.address,
+                    reachable_for.as_secs()
+                );
+                return Some(pool.clone());
+            }
+        }
+        None
+    }
+
+    /// Returns a pool that has been healthier than the current one, or faster by a margin, see
+    /// [`Selection::should_switch`].
+    async fn select_better_pool(&mut self, epsilon: Duration) -> Option<Candidate> {
+        // Every pool is probed, the current one included
+        let (best_pool, best_pool_latency) = self.select_pool().await?;
+        let current_pool = self.current_pool.as_ref()?.address;
+        if self
+            .selection
+            .should_switch(current_pool, best_pool.address, health::health, epsilon)
+        {
+            info!(
+                "Found better pool: {} with latency {:?} and health {:.2}, current health {:.2}",
+                best_pool,
+                best_pool_latency,
+                health::health(best_pool.address),
+                health::health(current_pool)
+            );
+            return Some(best_pool);
         }
         None
     }
@@ -114,7 +406,7 @@ impl Router {
     /// Uses minin_pool_connection::connect_pool
     pub async fn connect_pool(
         &mut self,
-        pool_addr: Option<SocketAddr>,
+        pool: Option<Candidate>,
     ) -> Result<
         (
             tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
@@ -123,10 +415,10 @@ impl Router {
         ),
         minin_pool_connection::errors::Error,
     > {
-        let pool = match pool_addr {
-            Some(addr) => addr,
+        let pool = match pool {
+            Some(pool) => pool,
             None => match self.select_pool_connect().await {
-                Some(addr) => addr,
+                Some(pool) => pool,
                 // Called when we initialize the proxy, without a valid pool we can not start mine and we
                 // return Err
                 None => {
@@ -134,88 +426,55 @@ impl Router {
                 }
             },
         };
-        self.current_pool = Some(pool);
+        let pool = match self.get_pool(&pool) {
+            Some(pool) => pool.clone(),
+            None => {
+                error!("Upstream {} is not a configured pool", pool);
+                return Err(minin_pool_connection::errors::Error::Unrecoverable);
+            }
+        };
+        info!("Upstream {} selected", pool);
+        self.current_pool = Some(pool.clone());
 
-        info!("Upstream {:?} selected", pool);
+        let connection = self.connect_to(&pool).await?;
+        health::connected(pool.address);
+        Ok(connection)
+    }
 
-        match minin_pool_connection::connect_pool(
-            pool,
-            self.auth_pub_k,
+    /// Connects to `pool` without making it the current pool, used when the hashrate is split
+    /// across all the pools.
+    pub async fn connect_to(
+        &self,
+        pool: &Candidate,
+    ) -> Result<
+        (
+            tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
+            tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
+            AbortOnDrop,
+        ),
+        minin_pool_connection::errors::Error,
+    > {
+        minin_pool_connection::connect_pool(
+            pool.address,
+            pool.pool.auth_pub_key,
+            &pool.pool.token,
             self.setup_connection_msg.clone(),
             self.timer,
         )
+        .instrument(tracing::info_span!("pool", pool = %pool.address))
         .await
-        {
-            Ok((send_to_pool, recv_from_pool, pool_connection_abortable)) => {
-                Ok((send_to_pool, recv_from_pool, pool_connection_abortable))
-            }
-
-            Err(e) => Err(e),
-        }
+        .inspect_err(|_| health::setup_failed(pool.address))
     }
 
-    /// Returns the sum all the latencies for a given upstream
-    async fn get_latency(&self, pool_address: SocketAddr) -> Result<Duration, ()> {
-        let mut pool = PoolLatency::new(pool_address);
-        let setup_connection_msg = self.setup_connection_msg.as_ref();
-        let timer = self.timer.as_ref();
-        let auth_pub_key = self.auth_pub_k;
-
-        tokio::time::timeout(
-            Duration::from_secs(15),
-            PoolLatency::get_mining_setup_latencies(
-                &mut pool,
-                setup_connection_msg.cloned(),
-                timer.cloned(),
-                auth_pub_key,
-            ),
-        )
-        .await
-        .map_err(|_| {
-            error!(
-                "Failed to get mining setup latencies for {:?}: Timeout",
-                pool_address
-            );
-        })??;
-
-        if (PoolLatency::get_mining_setup_latencies(
-            &mut pool,
-            setup_connection_msg.cloned(),
-            timer.cloned(),
-            auth_pub_key,
-        )
-        .await)
-            .is_err()
-        {
-            error!(
-                "Failed to get mining setup latencies for: {:?}",
-                pool_address
-            );
-            return Err(());
-        }
-        if (PoolLatency::get_jd_latencies(&mut pool, auth_pub_key).await).is_err() {
-            error!("Failed to get jd setup latencies for: {:?}", pool_address);
-            return Err(());
-        }
-
-        let latencies = [
-            pool.open_sv2_mining_connection,
-            pool.setup_a_channel,
-            pool.receive_first_job,
-            pool.receive_first_set_new_prev_hash,
-            pool.open_sv2_jd_connection,
-            pool.get_a_mining_token,
-        ];
-        // Get sum of all latencies for pool
-        let sum_of_latencies: Duration = latencies.iter().flatten().sum();
-        Ok(sum_of_latencies)
-    }
-
-    /// Checks for faster upstream switch to it if found
-    pub async fn monitor_upstream(&mut self, epsilon: Duration) -> Option<SocketAddr> {
+    /// Checks for a better upstream according to the pool policy, switch to it if found
+    pub async fn monitor_upstream(&mut self, epsilon: Duration) -> Option<Candidate> {
         if let Some(best_pool) = self.select_pool_monitor(epsilon).await {
-            if Some(best_pool) != self.current_pool {
-                info!("Switching to faster upstreamn {:?}", best_pool);
+            if !self
+                .current_pool
+                .as_ref()
+                .is_some_and(|pool| pool.is(&best_pool))
+            {
+                info!("Switching to upstream {}", best_pool);
                 return Some(best_pool);
             } else {
                 return None;
@@ -225,12 +484,32 @@ impl Router {
     }
 }
 
+/// Candidates for `pool`: one per address its host resolves to, in the order of the resolver.
+pub async fn resolve_pool(pool: &PoolConfig) -> std::io::Result<Vec<Candidate>> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for address in tokio::net::lookup_host(pool.host.as_str()).await? {
+        if !candidates.iter().any(|c| c.address == address) {
+            candidates.push(Candidate {
+                address,
+                pool: pool.clone(),
+            });
+        }
+    }
+    if candidates.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no address found",
+        ));
+    }
+    Ok(candidates)
+}
+
 /// Track latencies for various stages of pool connection setup.
 #[derive(Clone, Copy, Debug)]
 struct PoolLatency {
     pool: SocketAddr,
     open_sv2_mining_connection: Option<Duration>,
-    setup_a_channel: Option<Duration>,
+    setup_connection: Option<Duration>,
     receive_first_job: Option<Duration>,
     receive_first_set_new_prev_hash: Option<Duration>,
     open_sv2_jd_connection: Option<Duration>,
@@ -243,7 +522,7 @@ impl PoolLatency {
         Self {
             pool,
             open_sv2_mining_connection: None,
-            setup_a_channel: None,
+            setup_connection: None,
             receive_first_job: None,
             receive_first_set_new_prev_hash: None,
             open_sv2_jd_connection: None,
@@ -251,13 +530,69 @@ impl PoolLatency {
         }
     }
 
-    /// Sets the `PoolLatency`'s `open_sv2_mining_connection`, `setup_channel_timer`, `receive_first_job`,
-    /// and `receive_first_set_new_prev_hash`
+    /// Sets the latencies of the stages measured with the [`ProbeMode`], `full` or cheap.
+    async fn measure(
+        &mut self,
+        setup_connection_msg: Option<SetupConnection<'static>>,
+        timer: Option<Duration>,
+        pool: &PoolConfig,
+        full: bool,
+    ) -> Result<(), ()> {
+        self.get_mining_setup_latencies(
+            setup_connection_msg,
+            timer,
+            pool.auth_pub_key,
+            &pool.token,
+            full,
+        )
+        .await?;
+        if full
+            && self
+                .get_jd_latencies(pool.auth_pub_key, &pool.token)
+                .await
+                .is_err()
+        {
+            error!("Failed to get jd setup latencies for: {:?}", self.pool);
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Stages measured so far, with their latency
+    fn stages(&self) -> Vec<(&'static str, Duration)> {
+        [
+            (
+                "open_sv2_mining_connection",
+                self.open_sv2_mining_connection,
+            ),
+            ("setup_connection", self.setup_connection),
+            ("receive_first_job", self.receive_first_job),
+            (
+                "receive_first_set_new_prev_hash",
+                self.receive_first_set_new_prev_hash,
+            ),
+            ("open_sv2_jd_connection", self.open_sv2_jd_connection),
+            ("get_a_mining_token", self.get_a_mining_token),
+        ]
+        .into_iter()
+        .filter_map(|(stage, latency)| Some((stage, latency?)))
+        .collect()
+    }
+
+    /// Sum of the latencies of the stages measured
+    fn total(&self) -> Duration {
+        self.stages().iter().map(|(_, latency)| *latency).sum()
+    }
+
+    /// Sets the `PoolLatency`'s `open_sv2_mining_connection`, `setup_connection`, and if
+    /// `with_channel` is set `receive_first_job` and `receive_first_set_new_prev_hash`
     async fn get_mining_setup_latencies(
         &mut self,
         setup_connection_msg: Option<SetupConnection<'static>>,
         timer: Option<Duration>,
         authority_public_key: Secp256k1PublicKey,
+        token: &str,
+        with_channel: bool,
     ) -> Result<(), ()> {
         // Set open_sv2_mining_connection latency
         let open_sv2_mining_connection_timer = Instant::now();
@@ -270,11 +605,12 @@ impl PoolLatency {
                         setup_connection_msg,
                         stream,
                         authority_public_key,
+                        token,
                     )
                     .await?;
 
-                // Set setup_channel latency
-                let setup_channel_timer = Instant::now();
+                // Set setup_connection latency
+                let setup_connection_timer = Instant::now();
                 let result = mining_setup_connection(
                     &mut receiver,
                     &mut sender,
@@ -283,8 +619,12 @@ impl PoolLatency {
                 )
                 .await;
                 match result {
+                    Ok(_) if !with_channel => {
+                        self.setup_connection = Some(setup_connection_timer.elapsed());
+                        Ok(())
+                    }
                     Ok(_) => {
-                        self.setup_a_channel = Some(setup_channel_timer.elapsed());
+                        self.setup_connection = Some(setup_connection_timer.elapsed());
                         let (send_to_down, mut recv_from_down) = tokio::sync::mpsc::channel(10);
                         let (send_from_down, recv_to_up) = tokio::sync::mpsc::channel(10);
                         let channel = open_channel();
@@ -351,6 +691,7 @@ impl PoolLatency {
     async fn get_jd_latencies(
         &mut self,
         authority_public_key: Secp256k1PublicKey,
+        token: &str,
     ) -> Result<(), ()> {
         let address = self.pool;
 
@@ -363,9 +704,13 @@ impl PoolLatency {
                     .safe_lock(|tp| tp.clone())
                     .map_err(|_| error!(" TP_ADDRESS Mutex Corrupted"))?;
                 if let Some(_tp_addr) = tp {
-                    let initiator = Initiator::from_raw_k(authority_public_key.into_bytes())
-                        // Safe expect Key is a constant and must be right
-                        .expect("Unable to create initialtor");
+                    let initiator = match Initiator::from_raw_k(authority_public_key.into_bytes()) {
+                        Ok(initiator) => initiator,
+                        Err(e) => {
+                            error!("Invalid authority public key: {:?}", e);
+                            return Err(());
+                        }
+                    };
                     let (mut receiver, mut sender, _, _) =
                         match Connection::new(stream, HandshakeRole::Initiator(initiator)).await {
                             Ok(connection) => connection,
@@ -375,7 +720,8 @@ impl PoolLatency {
                             }
                         };
                     if let Err(e) =
-                        SetupConnectionHandler::setup(&mut receiver, &mut sender, address).await
+                        SetupConnectionHandler::setup(&mut receiver, &mut sender, address, token)
+                            .await
                     {
                         error!("Failed to setup connection: {:?}", e);
                         return Err(());
@@ -396,6 +742,7 @@ impl PoolLatency {
                     let (job_declarator, _aborter) = match JobDeclarator::new(
                         address,
                         authority_public_key.into_bytes(),
+                        token,
                         upstream,
                         false,
                     )
@@ -424,7 +771,7 @@ impl PoolLatency {
 }
 
 // Helper functions
-fn open_channel() -> Mining<'static> {
+pub(crate) fn open_channel() -> Mining<'static> {
     roles_logic_sv2::parsers::Mining::OpenExtendedMiningChannel(
         roles_logic_sv2::mining_sv2::OpenExtendedMiningChannel {
             request_id: 0,
@@ -444,6 +791,7 @@ async fn initialize_mining_connections(
     setup_connection_msg: Option<SetupConnection<'static>>,
     stream: TcpStream,
     authority_public_key: Secp256k1PublicKey,
+    token: &str,
 ) -> Result<
     (
         Receiver<codec_sv2::Frame<PoolExtMessages<'static>, Slice>>,
@@ -452,9 +800,13 @@ async fn initialize_mining_connections(
     ),
     (),
 > {
-    let initiator =
-        // Safe expect Key is a constant and must be right
-        Initiator::from_raw_k(authority_public_key.into_bytes()).expect("Invalid authority key");
+    let initiator = match Initiator::from_raw_k(authority_public_key.into_bytes()) {
+        Ok(initiator) => initiator,
+        Err(e) => {
+            error!("Invalid authority public key: {:?}", e);
+            return Err(());
+        }
+    };
     let (receiver, sender, _, _) =
         match Connection::new(stream, HandshakeRole::Initiator(initiator)).await {
             Ok(connection) => connection,
@@ -464,6 +816,6 @@ async fn initialize_mining_connections(
             }
         };
     let setup_connection_msg =
-        setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true));
+        setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true, token));
     Ok((receiver, sender, setup_connection_msg))
 }
@@ -0,0 +1,463 @@
+//! How the router chooses among the configured pools.
+//!
+//! - `latency`: the healthiest pool, see [`super::health`], and among the pools about as healthy
+//!   the one with the least setup latency. The latencies are smoothed over the probes, and the
+//!   proxy only switches when another pool has been healthier, or faster by a margin, at several
+//!   checks in a row: one slow sample does not rebuild the proxy.
+//! - `priority`: the reachable pool with the lowest `priority`, the others are backups. Once on a
+//!   backup the proxy goes back to a pool of higher priority when it has been reachable for
+//!   `fail_back_after_secs`, and is about as healthy.
+//! - `weighted`: each new connection goes to the next reachable pool of a weighted round-robin,
+//!   so that over the reconnections the pools are used in proportion to their `weight`. The
+//!   proxy does not switch away from a pool that is up.
+//! - `split`: the proxy is connected to every pool at once and the miners are spread across
+//!   them in proportion to their `weight`, see [`crate::split`].
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::Candidate;
+
+/// Weight of a new sample in the smoothed latency of a pool
+const EWMA_ALPHA: f64 = 0.3;
+/// To be switched to, a pool must be faster than the current one by this fraction of the
+/// current latency...
+const SWITCH_MARGIN: f64 = 0.2;
+/// ...at this many consecutive checks
+const SWITCH_AFTER_CHECKS: u32 = 3;
+/// Pools whose health is within this of each other are ranked on their latency
+const HEALTH_TOLERANCE: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PoolPolicy {
+    #[default]
+    Latency,
+    Priority,
+    Weighted,
+    Split,
+}
+
+impl std::str::FromStr for PoolPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "latency" => Ok(Self::Latency),
+            "priority" => Ok(Self::Priority),
+            "weighted" => Ok(Self::Weighted),
+            "split" => Ok(Self::Split),
+            _ => Err(format!(
+                "Invalid pool policy '{s}': expected latency, priority, weighted or split"
+            )),
+        }
+    }
+}
+
+/// Pools from the most to the least preferred: lowest priority first, ties are kept in the
+/// configuration order.
+pub fn by_priority(pools: &[Candidate]) -> Vec<&Candidate> {
+    let mut pools: Vec<&Candidate> = pools.iter().collect();
+    pools.sort_by_key(|candidate| candidate.pool.priority);
+    pools
+}
+
+/// What the pools are ranked on: their health first, then their smoothed latency.
+#[derive(Debug, Clone, Copy)]
+pub struct Score {
+    pub health: f64,
+    pub latency: Duration,
+}
+
+/// Whether `health` is not clearly below `other`.
+pub fn as_healthy(health: f64, other: f64) -> bool {
+    health + HEALTH_TOLERANCE >= other
+}
+
+impl Score {
+    /// Whether the health of `self` is not clearly below the one of `other`.
+    fn as_healthy_as(&self, other: &Score) -> bool {
+        as_healthy(self.health, other.health)
+    }
+
+    /// Whether `self` is worth switching to from `current`: clearly healthier, or about as
+    /// healthy and faster by a margin.
+    fn better_than(&self, current: &Score, epsilon: Duration) -> bool {
+        if !current.as_healthy_as(self) {
+            return true;
+        }
+        self.as_healthy_as(current)
+            && self.latency + epsilon + current.latency.mul_f64(SWITCH_MARGIN) < current.latency
+    }
+}
+
+/// Index of the best of `scores`: the fastest of the pools about as healthy as the healthiest.
+pub fn best(scores: &[Score]) -> Option<usize> {
+    let healthiest = scores
+        .iter()
+        .copied()
+        .max_by(|a, b| a.health.total_cmp(&b.health))?;
+    scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| score.as_healthy_as(&healthiest))
+        .min_by_key(|(_, score)| score.latency)
+        .map(|(index, _)| index)
+}
+
+/// What the router remembers of the previous selections.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Since when each pool answers the probes
+    reachable_since: HashMap<SocketAddr, Instant>,
+    /// Smoothed latency of each pool
+    latencies: HashMap<SocketAddr, Duration>,
+    /// Pool faster than the current one, and at how many consecutive checks it was
+    challenger: Option<(SocketAddr, u32)>,
+    /// Current weights of the smooth weighted round-robin, by pool host
+    current_weights: HashMap<String, i64>,
+}
+
+impl Selection {
+    /// Records the result of a probe of `pool`, `None` if it did not answer.
+    pub fn probed(&mut self, pool: SocketAddr, latency: Option<Duration>, now: Instant) {
+        let Some(latency) = latency else {
+            self.reachable_since.remove(&pool);
+            return;
+        };
+        self.reachable_since.entry(pool).or_insert(now);
+        self.latencies
+            .entry(pool)
+            .and_modify(|smoothed| {
+                *smoothed = latency.mul_f64(EWMA_ALPHA) + smoothed.mul_f64(1.0 - EWMA_ALPHA)
+            })
+            .or_insert(latency);
+    }
+
+    /// Smoothed latency of `pool`, if it has ever answered.
+    pub fn latency(&self, pool: SocketAddr) -> Option<Duration> {
+        self.latencies.get(&pool).copied()
+    }
+
+    /// Score of `pool` with its `health`, if it has ever answered.
+    pub fn score(&self, pool: SocketAddr, health: f64) -> Option<Score> {
+        let latency = self.latency(pool)?;
+        Some(Score { health, latency })
+    }
+
+    /// Called at each check with the best pool, returns true when the proxy should switch from
+    /// `current` to it. `health` gives the health of a pool.
+    pub fn should_switch(
+        &mut self,
+        current: SocketAddr,
+        best: SocketAddr,
+        health: impl Fn(SocketAddr) -> f64,
+        epsilon: Duration,
+    ) -> bool {
+        let better = match (
+            self.score(current, health(current)),
+            self.score(best, health(best)),
+        ) {
+            (Some(current_score), Some(best_score)) => {
+                best != current && best_score.better_than(&current_score, epsilon)
+            }
+            _ => false,
+        };
+        if !better {
+            self.challenger = None;
+            return false;
+        }
+        let checks = match self.challenger {
+            Some((challenger, checks)) if challenger == best => checks + 1,
+            _ => 1,
+        };
+        if checks >= SWITCH_AFTER_CHECKS {
+            self.challenger = None;
+            return true;
+        }
+        self.challenger = Some((best, checks));
+        false
+    }
+
+    /// Forgets the addresses that are not in `pools` anymore, after the pool hosts have been
+    /// resolved again.
+    pub fn retain(&mut self, pools: &[SocketAddr]) {
+        self.reachable_since.retain(|pool, _| pools.contains(pool));
+        self.latencies.retain(|pool, _| pools.contains(pool));
+        if self
+            .challenger
+            .is_some_and(|(challenger, _)| !pools.contains(&challenger))
+        {
+            self.challenger = None;
+        }
+    }
+
+    /// How long `pool` has been answering the probes.
+    pub fn reachable_for(&self, pool: SocketAddr, now: Instant) -> Duration {
+        self.reachable_since
+            .get(&pool)
+            .map(|since| now.saturating_duration_since(*since))
+            .unwrap_or_default()
+    }
+
+    /// Next pool of the weighted round-robin among the reachable ones. The picks are spread
+    /// evenly: with weights 2 and 1 the pools are picked a, b, a, a, b, a...
+    ///
+    /// A host that resolves to several addresses is one pool, it is picked with the first of
+    /// its addresses in `reachable`.
+    pub fn next_weighted<'a>(&mut self, reachable: &[&'a Candidate]) -> Option<&'a Candidate> {
+        let mut pools: Vec<&Candidate> = Vec::new();
+        for candidate in reachable {
+            if !pools.iter().any(|p| p.pool.host == candidate.pool.host) {
+                pools.push(candidate);
+            }
+        }
+        let total: i64 = pools.iter().map(|c| i64::from(c.pool.weight)).sum();
+        let mut best: Option<(&Candidate, i64)> = None;
+        for candidate in pools {
+            let current = self
+                .current_weights
+                .entry(candidate.pool.host.clone())
+                .or_default();
+            *current += i64::from(candidate.pool.weight);
+            if best.is_none_or(|(_, weight)| *current > weight) {
+                best = Some((candidate, *current));
+            }
+        }
+        let (candidate, _) = best?;
+        if let Some(current) = self.current_weights.get_mut(&candidate.pool.host) {
+            *current -= total;
+        }
+        Some(candidate)
+    }
+}
+
+/// Index of the pool a new miner goes to when the hashrate is split: the one that is the most
+/// under its share of the miners. `pools` holds the miners assigned to each pool and its weight,
+/// `None` for the pools that are down.
+pub fn least_served(pools: &[Option<(usize, u32)>]) -> Option<usize> {
+    // (miners + 1) / weight, compared without dividing
+    let served = |miners: usize, weight: u32| (miners as u128 + 1) * u128::from(weight);
+    let mut best: Option<(usize, usize, u32)> = None;
+    for (index, pool) in pools.iter().enumerate() {
+        let Some((miners, weight)) = *pool else {
+            continue;
+        };
+        if best.is_none_or(|(_, best_miners, best_weight)| {
+            served(miners, best_weight) < served(best_miners, weight)
+        }) {
+            best = Some((index, miners, weight));
+        }
+    }
+    best.map(|(index, _, _)| index)
+}
+
+/// Number of miners each pool has above its share when the hashrate is split, the ones that have
+/// to move for the split to match the weights again. The share of each pool is what
+/// [`least_served`] would give it if all the miners reconnected, the pools that are down have
+/// none to give.
+pub fn over_served(pools: &[Option<(usize, u32)>]) -> Vec<usize> {
+    let miners: usize = pools.iter().flatten().map(|(miners, _)| miners).sum();
+    let mut shares: Vec<Option<(usize, u32)>> = pools
+        .iter()
+        .map(|pool| pool.map(|(_, weight)| (0, weight)))
+        .collect();
+    for _ in 0..miners {
+        if let Some(index) = least_served(&shares) {
+            if let Some((share, _)) = shares[index].as_mut() {
+                *share += 1;
+            }
+        }
+    }
+    pools
+        .iter()
+        .zip(shares)
+        .map(|(pool, share)| match (pool, share) {
+            (Some((miners, _)), Some((share, _))) => miners.saturating_sub(share),
+            _ => 0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PoolConfig;
+
+    fn pool(address: &str, priority: u32, weight: u32) -> Candidate {
+        Candidate {
+            address: address.parse().unwrap(),
+            pool: PoolConfig {
+                host: address.to_string(),
+                auth_pub_key: "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+                    .parse()
+                    .unwrap(),
+                token: "token".to_string(),
+                priority,
+                weight,
+            },
+        }
+    }
+
+    #[test]
+    fn selects_by_priority_and_weight() {
+        let pools = vec![
+            pool("127.0.0.1:2000", 1, 1),
+            pool("127.0.0.2:2000", 0, 3),
+            pool("127.0.0.3:2000", 1, 1),
+        ];
+        let ordered: Vec<SocketAddr> = by_priority(&pools).iter().map(|p| p.address).collect();
+        assert_eq!(
+            ordered,
+            vec![pools[1].address, pools[0].address, pools[2].address]
+        );
+
+        let mut selection = Selection::default();
+        let reachable: Vec<&Candidate> = pools.iter().collect();
+        let picks: Vec<SocketAddr> = (0..10)
+            .filter_map(|_| selection.next_weighted(&reachable).map(|c| c.address))
+            .collect();
+        let count = |i: usize| picks.iter().filter(|p| **p == pools[i].address).count();
+        assert_eq!((count(0), count(1), count(2)), (2, 6, 2));
+        // Spread, not one pool after the other
+        assert_ne!(picks[0], picks[1]);
+        assert!(selection.next_weighted(&[]).is_none());
+
+        // A host with two addresses is picked as often as any other, with its first address
+        let mut selection = Selection::default();
+        let other_address = Candidate {
+            address: "127.0.0.4:2000".parse().unwrap(),
+            ..pools[0].clone()
+        };
+        let reachable = vec![&pools[0], &other_address, &pools[2]];
+        let picks: Vec<SocketAddr> = (0..4)
+            .filter_map(|_| selection.next_weighted(&reachable).map(|c| c.address))
+            .collect();
+        let count = |address: SocketAddr| picks.iter().filter(|p| **p == address).count();
+        assert_eq!(count(pools[0].address), 2);
+        assert_eq!(count(other_address.address), 0);
+
+        // Another pool behind the same address is picked on its own
+        let mut selection = Selection::default();
+        let same_address = Candidate {
+            pool: PoolConfig {
+                host: "pool.example.com:2000".to_string(),
+                ..pools[0].pool.clone()
+            },
+            ..pools[0].clone()
+        };
+        let reachable = vec![&pools[0], &same_address];
+        let picks: Vec<String> = (0..2)
+            .filter_map(|_| selection.next_weighted(&reachable).map(|c| c.to_string()))
+            .collect();
+        assert_eq!(
+            picks,
+            vec![
+                "127.0.0.1:2000 (127.0.0.1:2000)",
+                "pool.example.com:2000 (127.0.0.1:2000)"
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_the_miners_by_weight() {
+        let mut miners = [0, 0];
+        for _ in 0..10 {
+            let pools = [Some((miners[0], 80)), Some((miners[1], 20))];
+            miners[least_served(&pools).unwrap()] += 1;
+        }
+        assert_eq!(miners, [8, 2]);
+
+        assert_eq!(least_served(&[None, Some((5, 20))]), Some(1));
+        assert_eq!(least_served(&[None, None]), None);
+    }
+
+    #[test]
+    fn moves_the_miners_above_the_share_of_their_pool() {
+        // The pool of 20% came back, its share is on the other one
+        assert_eq!(over_served(&[Some((10, 80)), Some((0, 20))]), [2, 0]);
+        assert_eq!(over_served(&[Some((8, 80)), Some((2, 20))]), [0, 0]);
+        assert_eq!(over_served(&[None, Some((5, 20))]), [0, 0]);
+    }
+
+    #[test]
+    fn tracks_how_long_a_pool_is_reachable() {
+        let address: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut selection = Selection::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        selection.probed(address, Some(ms(100)), start);
+        selection.probed(address, Some(ms(100)), start + Duration::from_secs(100));
+        assert_eq!(
+            selection.reachable_for(address, start + Duration::from_secs(200)),
+            Duration::from_secs(200)
+        );
+        selection.probed(address, None, start + Duration::from_secs(300));
+        selection.probed(address, Some(ms(100)), start + Duration::from_secs(400));
+        assert_eq!(
+            selection.reachable_for(address, start + Duration::from_secs(450)),
+            Duration::from_secs(50)
+        );
+    }
+
+    #[test]
+    fn switches_only_when_faster_at_several_checks() {
+        let current: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:2000".parse().unwrap();
+        let ms = Duration::from_millis;
+        let healthy = |_| 1.0;
+        let now = Instant::now();
+        let mut selection = Selection::default();
+        selection.probed(current, Some(ms(100)), now);
+        selection.probed(other, Some(ms(100)), now);
+
+        // A single slow sample is smoothed and does not make the other pool faster
+        selection.probed(current, Some(ms(150)), now);
+        assert_eq!(selection.latency(current), Some(ms(115)));
+        assert!(!selection.should_switch(current, other, healthy, ms(10)));
+
+        selection.probed(current, Some(ms(300)), now);
+        let switches: Vec<bool> = (0..3)
+            .map(|_| selection.should_switch(current, other, healthy, ms(10)))
+            .collect();
+        assert_eq!(switches, vec![false, false, true]);
+
+        // The count restarts when the other pool is not faster anymore
+        selection.should_switch(current, other, healthy, ms(10));
+        selection.should_switch(current, current, healthy, ms(10));
+        assert!(!selection.should_switch(current, other, healthy, ms(10)));
+    }
+
+    #[test]
+    fn ranks_the_pools_on_health_before_latency() {
+        let ms = Duration::from_millis;
+        let score = |health, latency| Score {
+            health,
+            latency: ms(latency),
+        };
+        // Fast but rejecting 10% of the shares
+        let rejecting = score(0.9, 20);
+        let slower = score(1.0, 80);
+        let slowest = score(0.98, 300);
+        assert_eq!(best(&[rejecting, slower, slowest]), Some(1));
+        assert_eq!(best(&[rejecting, slowest]), Some(1));
+        assert_eq!(best(&[rejecting]), Some(0));
+        assert_eq!(best(&[]), None);
+
+        let current: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:2000".parse().unwrap();
+        let now = Instant::now();
+        let mut selection = Selection::default();
+        selection.probed(current, Some(ms(20)), now);
+        selection.probed(other, Some(ms(80)), now);
+        let health = |pool| if pool == current { 0.9 } else { 1.0 };
+        let switches: Vec<bool> = (0..3)
+            .map(|_| selection.should_switch(current, other, health, ms(10)))
+            .collect();
+        assert_eq!(switches, vec![false, false, true]);
+        // Slightly less healthy is not enough to leave a faster pool
+        let health = |pool| if pool == current { 0.97 } else { 1.0 };
+        assert!(!selection.should_switch(current, other, health, ms(10)));
+    }
+}
@@ -0,0 +1,236 @@
+//! systemd `sd_notify` protocol, so that the proxy can run as a `Type=notify` service with
+//! `WatchdogSec` set.
+//!
+//! The datagrams are sent to the socket in `NOTIFY_SOCKET`. When the variable is not set (or on
+//! platforms without unix sockets) every function here is a no-op.
+use lazy_static::lazy_static;
+#[cfg(unix)]
+use tracing::{debug, warn};
+
+lazy_static! {
+    static ref NOTIFIER: Option<Notifier> = Notifier::from_env();
+}
+
+/// Tells systemd that the pool connection and the SV1 listener are up.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that we are shutting down, so that the shutdown is not reported as a hang.
+pub fn stopping() {
+    if let Some(notifier) = NOTIFIER.as_ref() {
+        notifier.status_changed("Shutting down");
+        notifier.send("STOPPING=1\nSTATUS=Shutting down");
+    }
+}
+
+/// Updates the status text shown by `systemctl status`, it is only sent when it changed.
+pub fn status(status: &str) {
+    if let Some(notifier) = NOTIFIER.as_ref() {
+        if notifier.status_changed(status) {
+            notifier.send(&format!("STATUS={status}"));
+        }
+    }
+}
+
+/// Pings the watchdog and updates the status text. Called often, the ping is only sent once
+/// every half watchdog interval as suggested by `sd_watchdog_enabled(3)`, the status when it
+/// changed, with or without the watchdog enabled.
+pub fn watchdog(status: &str) {
+    if let Some(notifier) = NOTIFIER.as_ref() {
+        let mut state = Vec::new();
+        if notifier.watchdog_due() {
+            state.push("WATCHDOG=1".to_string());
+        }
+        if notifier.status_changed(status) {
+            state.push(format!("STATUS={status}"));
+        }
+        if !state.is_empty() {
+            notifier.send(&state.join("\n"));
+        }
+    }
+}
+
+/// Status text for systemd built from `ProxyState::is_proxy_down()`.
+pub fn status_from_proxy_state(is_proxy_down: (bool, Option<String>)) -> String {
+    match is_proxy_down {
+        (false, _) => "Proxy up".to_string(),
+        (true, Some(down)) => format!("Proxy down: {down}"),
+        (true, None) => "Proxy down".to_string(),
+    }
+}
+
+fn notify(state: &str) {
+    if let Some(notifier) = NOTIFIER.as_ref() {
+        notifier.send(state);
+    }
+}
+
+/// Returns the watchdog interval if the watchdog is enabled for this process.
+#[cfg(unix)]
+fn watchdog_interval(usec: Option<String>, pid: Option<String>) -> Option<std::time::Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| std::time::Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    address: std::os::unix::net::SocketAddr,
+    watchdog_interval: Option<std::time::Duration>,
+    last_ping: std::sync::Mutex<Option<std::time::Instant>>,
+    last_status: std::sync::Mutex<String>,
+}
+
+#[cfg(unix)]
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog_interval = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok(),
+            std::env::var("WATCHDOG_PID").ok(),
+        );
+        match Self::new(&path, watchdog_interval) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                warn!("Invalid NOTIFY_SOCKET {path}: {e}");
+                None
+            }
+        }
+    }
+
+    fn new(path: &str, watchdog_interval: Option<std::time::Duration>) -> std::io::Result<Self> {
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+        let address = match path.strip_prefix('@') {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            _ => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            address,
+            watchdog_interval,
+            last_ping: std::sync::Mutex::new(None),
+            last_status: std::sync::Mutex::new(String::new()),
+        })
+    }
+
+    fn send(&self, state: &str) {
+        match self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            Ok(_) => debug!("sd_notify: {}", state.replace('\n', " ")),
+            Err(e) => warn!("sd_notify failed: {e}"),
+        }
+    }
+
+    fn watchdog_due(&self) -> bool {
+        let Some(interval) = self.watchdog_interval else {
+            return false;
+        };
+        let mut last_ping = match self.last_ping.lock() {
+            Ok(last_ping) => last_ping,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = std::time::Instant::now();
+        match *last_ping {
+            Some(last) if now.duration_since(last) < interval / 2 => false,
+            _ => {
+                *last_ping = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Returns true if `status` is not the last status sent, it is then the last one.
+    fn status_changed(&self, status: &str) -> bool {
+        let mut last_status = match self.last_status.lock() {
+            Ok(last_status) => last_status,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if *last_status == status {
+            return false;
+        }
+        *last_status = status.to_string();
+        true
+    }
+}
+
+#[cfg(not(unix))]
+struct Notifier;
+
+#[cfg(not(unix))]
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        None
+    }
+    fn send(&self, _state: &str) {}
+    fn watchdog_due(&self) -> bool {
+        false
+    }
+    fn status_changed(&self, _status: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{os::unix::net::UnixDatagram, time::Duration};
+
+    #[test]
+    fn sends_states_to_notify_socket() {
+        let path = std::env::temp_dir().join(format!("demand-cli-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 256];
+
+        let notifier =
+            Notifier::new(path.to_str().unwrap(), Some(Duration::from_secs(10))).unwrap();
+        notifier.send("READY=1");
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        // First ping is sent, the next ones wait for half the interval
+        assert!(notifier.watchdog_due());
+        assert!(!notifier.watchdog_due());
+
+        // The status is sent again only once it changed
+        assert!(notifier.status_changed("Proxy up"));
+        assert!(!notifier.status_changed("Proxy up"));
+        assert!(notifier.status_changed("Proxy down"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_watchdog_interval() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("20000000".into()), Some(pid)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            watchdog_interval(Some("20000000".into()), None),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            watchdog_interval(Some("20000000".into()), Some("1".into())),
+            None
+        );
+        assert_eq!(watchdog_interval(Some("0".into()), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,357 @@
+//! Append-only ledger of the shares sent to the pool.
+//!
+//! Every share forwarded upstream and every `ShareOk` received for it is appended as a JSON line
+//! to the file given with `--share-ledger`, so that what the pool credits can be checked against
+//! what was mined, across restarts. Records are sent to a thread that owns the file, so that the
+//! async tasks that record them never wait on the disk.
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use roles_logic_sv2::mining_sv2::SubmitSharesExtended;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::config::Configuration;
+
+lazy_static! {
+    static ref LEDGER: Option<Ledger> = Configuration::share_ledger().and_then(|path| {
+        match Ledger::open(&path) {
+            Ok(ledger) => {
+                info!(
+                    "Recording the shares sent to the pool in {}",
+                    path.display()
+                );
+                Some(ledger)
+            }
+            Err(e) => {
+                error!("Can not open share ledger {}: {e}", path.display());
+                None
+            }
+        }
+    });
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    /// A share sent to the pool
+    Share {
+        channel_id: u32,
+        sequence_number: u32,
+        job_id: u32,
+        nonce: u32,
+        ntime: u32,
+        version: u32,
+        extranonce: String,
+        /// Difficulty of the channel target the share was sent for
+        difficulty: f64,
+        timestamp_ms: u64,
+    },
+    /// The pool accounted for a share. The channel and sequence number are those of the share
+    /// the ack is attributed to, see [`super::pending`]
+    ShareOk {
+        channel_id: u32,
+        sequence_number: u32,
+        job_id: u32,
+        ref_job_id: u64,
+        timestamp_ms: u64,
+    },
+}
+
+/// A share sent to the pool, as recorded in the ledger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmittedShare {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    /// A `ShareOk` was recorded for it
+    pub acknowledged: bool,
+}
+
+struct Ledger {
+    path: PathBuf,
+    records: Sender<Record>,
+}
+
+impl Ledger {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (records, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("share-ledger".to_string())
+            .spawn(move || write_records(BufWriter::new(file), receiver))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            records,
+        })
+    }
+
+    /// Shares sent to the pool for each job id, a share sent twice is counted once.
+    fn submitted_shares(&self) -> std::io::Result<HashMap<u32, Vec<SubmittedShare>>> {
+        let mut shares: HashMap<u32, Vec<SubmittedShare>> = HashMap::new();
+        let mut sent = HashSet::new();
+        // Sequence numbers start over with each channel, an ack is for the last share sent
+        // with its channel and sequence number
+        let mut last_sent: HashMap<(u32, u32), (u32, usize)> = HashMap::new();
+        let reader = BufReader::new(File::open(&self.path)?);
+        for line in reader.lines() {
+            // The last line can be incomplete if the proxy was killed while writing it
+            match serde_json::from_str(&line?) {
+                Ok(Record::Share {
+                    channel_id,
+                    sequence_number,
+                    job_id,
+                    nonce,
+                    ntime,
+                    version,
+                    extranonce,
+                    ..
+                }) => {
+                    if !sent.insert((job_id, nonce, ntime, version, extranonce)) {
+                        continue;
+                    }
+                    let job = shares.entry(job_id).or_default();
+                    last_sent.insert((channel_id, sequence_number), (job_id, job.len()));
+                    job.push(SubmittedShare {
+                        channel_id,
+                        sequence_number,
+                        acknowledged: false,
+                    });
+                }
+                Ok(Record::ShareOk {
+                    channel_id,
+                    sequence_number,
+                    ..
+                }) => {
+                    if let Some((job_id, index)) = last_sent.get(&(channel_id, sequence_number)) {
+                        if let Some(share) =
+                            shares.get_mut(job_id).and_then(|job| job.get_mut(*index))
+                        {
+                            share.acknowledged = true;
+                        }
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+        Ok(shares)
+    }
+
+    fn append(&self, record: Record) {
+        if self.records.send(record).is_err() {
+            error!("Share ledger writer stopped, record lost");
+        }
+    }
+}
+
+/// Appends the records received to `file` until the ledger is dropped. The file is flushed
+/// each time no record is waiting, so that the records are on disk soon after they are sent.
+fn write_records(mut file: BufWriter<File>, records: Receiver<Record>) {
+    loop {
+        let record = match records.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = file.flush() {
+                    error!("Can not write to share ledger: {e}");
+                }
+                match records.recv() {
+                    Ok(record) => record,
+                    Err(_) => return,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Can not serialize share ledger record: {e}");
+                continue;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Can not write to share ledger: {e}");
+        }
+    }
+    if let Err(e) = file.flush() {
+        error!("Can not write to share ledger: {e}");
+    }
+}
+
+fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|byte| match byte {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Difficulty of a little-endian SV2 target, relative to the difficulty 1 target.
+pub fn target_to_difficulty(target: &[u8]) -> f64 {
+    let target = target
+        .iter()
+        .rev()
+        .fold(0f64, |acc, byte| acc * 256.0 + *byte as f64);
+    if target == 0.0 {
+        return 0.0;
+    }
+    2f64.powi(224) / target
+}
+
+/// Records a share sent to the pool.
+pub fn share_sent(share: &SubmitSharesExtended<'static>, difficulty: f64) {
+    if let Some(ledger) = LEDGER.as_ref() {
+        ledger.append(Record::Share {
+            channel_id: share.channel_id,
+            sequence_number: share.sequence_number,
+            job_id: share.job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version: share.version,
+            extranonce: to_hex(&share.extranonce.to_vec()),
+            difficulty,
+            timestamp_ms: timestamp_ms(),
+        });
+    }
+}
+
+/// Records the `ShareOk` with `ref_job_id` acknowledging a share sent to the pool.
+pub fn share_ok(channel_id: u32, sequence_number: u32, job_id: u32, ref_job_id: u64) {
+    if let Some(ledger) = LEDGER.as_ref() {
+        ledger.append(Record::ShareOk {
+            channel_id,
+            sequence_number,
+            job_id,
+            ref_job_id,
+            timestamp_ms: timestamp_ms(),
+        });
+    }
+}
+
+/// Returns true if shares are recorded.
+pub fn is_enabled() -> bool {
+    LEDGER.is_some()
+}
+
+/// Path of the ledger, `None` if it is disabled.
+pub fn path() -> Option<&'static Path> {
+    LEDGER.as_ref().map(|ledger| ledger.path.as_path())
+}
+
+/// Reads back the shares sent to the pool for each job id, `None` if the ledger is disabled or
+/// can not be read. This reads the whole file, do not call it from async code.
+pub fn submitted_shares() -> Option<HashMap<u32, Vec<SubmittedShare>>> {
+    let ledger = LEDGER.as_ref()?;
+    match ledger.submitted_shares() {
+        Ok(shares) => Some(shares),
+        Err(e) => {
+            error!("Can not read share ledger {}: {e}", ledger.path.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `records` like the writer of a ledger opened on `path`, and waits for it.
+    fn write(path: &Path, records: Vec<Record>) {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for record in records {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        write_records(BufWriter::new(file), receiver);
+    }
+
+    #[test]
+    fn appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("demand-cli-ledger-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let share_ok = |sequence_number, ref_job_id| Record::ShareOk {
+            channel_id: 1,
+            sequence_number,
+            job_id: 3,
+            ref_job_id,
+            timestamp_ms: 5,
+        };
+        // Reopening keeps what was written before
+        write(&path, vec![share_ok(2, 4)]);
+        write(&path, vec![share_ok(2, 4)]);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let line = r#"{"type":"share_ok","channel_id":1,"sequence_number":2,"job_id":3,"ref_job_id":4,"timestamp_ms":5}"#;
+        assert_eq!(content, format!("{line}\n{line}\n"));
+
+        let share = |sequence_number, nonce| Record::Share {
+            channel_id: 1,
+            sequence_number,
+            job_id: 3,
+            nonce,
+            ntime: 0,
+            version: 0,
+            extranonce: String::new(),
+            difficulty: 1.0,
+            timestamp_ms: 6,
+        };
+        // Sent again after a restart, with its sequence number reused by the next share
+        write(
+            &path,
+            vec![share(2, 7), share(1, 7), share(2, 8), share_ok(2, 3 << 32)],
+        );
+        let submitted = |sequence_number, acknowledged| SubmittedShare {
+            channel_id: 1,
+            sequence_number,
+            acknowledged,
+        };
+        let ledger = Ledger::open(&path).unwrap();
+        assert_eq!(
+            ledger.submitted_shares().unwrap(),
+            HashMap::from([(3, vec![submitted(2, false), submitted(2, true)])])
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn converts_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x12]), "00ab12");
+        assert_eq!(from_hex("00ab12"), Some(vec![0x00, 0xab, 0x12]));
+        assert_eq!(from_hex("0ab"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn converts_target_to_difficulty() {
+        let mut target = [0u8; 32];
+        target[28] = 1; // 2^224
+        assert_eq!(target_to_difficulty(&target), 1.0);
+        target[28] = 0;
+        target[27] = 1; // 2^216
+        assert_eq!(target_to_difficulty(&target), 256.0);
+        assert_eq!(target_to_difficulty(&[0; 32]), 0.0);
+    }
+}
@@ -1,123 +1,411 @@
 mod errors;
+mod ledger;
+mod pending;
+mod reconcile;
 mod task_manager;
 
 use errors::Error;
-use std::sync::Arc;
-use tracing::error;
+use pending::{Ack, PendingShares, ShareSentUp};
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, error, info, warn};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use demand_share_accounting_ext::*;
 use parser::{PoolExtMessages, ShareAccountingMessages};
-use roles_logic_sv2::{mining_sv2::SubmitSharesSuccess, parsers::Mining};
+use roles_logic_sv2::{
+    mining_sv2::{CloseChannel, SubmitSharesSuccess},
+    parsers::Mining,
+};
 use task_manager::TaskManager;
 
 use crate::{
+    config::Configuration,
     proxy_state::{ProxyState, ShareAccounterState},
     shared::utils::AbortOnDrop,
     PoolState,
 };
 
+/// Ends of the channel between the share accounter and the translator or the JD client.
+pub struct Downstream {
+    pub receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
+    pub sender: tokio::sync::mpsc::Sender<Mining<'static>>,
+    /// Mines on the channels opened by the previous downstream, they are kept open. This is the
+    /// translator once the JD client that relayed its channels to the pool failed.
+    pub takes_over_channels: bool,
+}
+
+impl Downstream {
+    /// Downstream that opens its own channels.
+    pub fn new(
+        receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
+        sender: tokio::sync::mpsc::Sender<Mining<'static>>,
+    ) -> Self {
+        Self {
+            receiver,
+            sender,
+            takes_over_channels: false,
+        }
+    }
+}
+
+/// Channels opened with the pool by the current downstream, and the ones the proxy closed.
+#[derive(Debug, Default)]
+struct Channels {
+    /// Channel id -> difficulty of its target, for the ledger
+    open: DashMap<u32, f64>,
+    /// Closed when the downstream restarted, what the pool still sends for them is dropped
+    closed: DashSet<u32>,
+}
+
+/// Starts the share accounter on top of the pool connection. The downstream is received on
+/// `downstreams` and replaced by the next one received, so that the mining subsystem can be
+/// restarted without the pool connection: the channels opened by the previous downstream are
+/// closed, unless the next one takes them over, and the shares it sent stay pending.
 pub async fn start(
-    receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
-    sender: tokio::sync::mpsc::Sender<Mining<'static>>,
+    downstreams: tokio::sync::mpsc::Receiver<Downstream>,
     up_receiver: tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
     up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
 ) -> Result<AbortOnDrop, Error> {
     let task_manager = TaskManager::initialize();
-    let shares_sent_up = Arc::new(DashMap::with_capacity(100));
+    let shares_sent_up = Arc::new(PendingShares::default());
+    let channels = Arc::new(Channels::default());
+    let (to_reconcile, from_pool) = tokio::sync::mpsc::channel(10);
+    let (to_downstream, downstream) = tokio::sync::watch::channel(None);
     let abortable = task_manager
         .safe_lock(|t| t.get_aborter())
         .map_err(|_| Error::ShareAccounterTaskManagerMutexCorrupted)?
         .ok_or(Error::ShareAccounterTaskManagerError)?;
 
-    let relay_up_task = relay_up(receiver, up_sender, shares_sent_up.clone());
+    let relay_up_task = relay_up(
+        downstreams,
+        to_downstream,
+        up_sender.clone(),
+        shares_sent_up.clone(),
+        channels.clone(),
+    );
     TaskManager::add_relay_up(task_manager.clone(), relay_up_task)
         .await
         .map_err(|_| Error::ShareAccounterTaskManagerError)?;
 
-    let relay_down_task = relay_down(up_receiver, sender, shares_sent_up.clone());
+    let relay_down_task = relay_down(
+        up_receiver,
+        downstream,
+        shares_sent_up.clone(),
+        channels.clone(),
+        to_reconcile,
+    );
     TaskManager::add_relay_down(task_manager.clone(), relay_down_task)
         .await
         .map_err(|_| Error::ShareAccounterTaskManagerError)?;
+
+    // Reconciliation needs the shares recorded in the ledger
+    if ledger::is_enabled() {
+        let reconcile_task = reconcile::start(up_sender.clone(), from_pool);
+        TaskManager::add_reconcile(task_manager.clone(), reconcile_task)
+            .await
+            .map_err(|_| Error::ShareAccounterTaskManagerError)?;
+    }
+
+    let expire_task = expire_pending_shares(shares_sent_up.clone());
+    TaskManager::add_expire(task_manager.clone(), expire_task)
+        .await
+        .map_err(|_| Error::ShareAccounterTaskManagerError)?;
+
+    let drain_task = drain_on_shutdown(up_sender, shares_sent_up, channels);
+    TaskManager::add_drain(task_manager.clone(), drain_task)
+        .await
+        .map_err(|_| Error::ShareAccounterTaskManagerError)?;
     Ok(abortable)
 }
 
-struct ShareSentUp {
-    channel_id: u32,
-    sequence_number: u32,
+/// On shutdown waits for the pool to acknowledge the shares sent up, then closes the channels.
+fn drain_on_shutdown(
+    up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
+    shares_sent_up: Arc<PendingShares>,
+    channels: Arc<Channels>,
+) -> AbortOnDrop {
+    let task = crate::proxy_state::spawn(async move {
+        crate::shutdown::wait_for_drain().await;
+        let deadline = tokio::time::Instant::now() + crate::shutdown::SHARES_DRAIN_TIMEOUT;
+        while !shares_sent_up.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        match shares_sent_up.len() {
+            0 => info!("All shares acknowledged by the pool"),
+            pending => warn!("{pending} shares not acknowledged by the pool"),
+        }
+        close_channels(&up_sender, &channels, "shutdown").await;
+        crate::shutdown::drained();
+    });
+    task.into()
+}
+
+/// Closes the channels opened with the pool and remembers them as closed.
+async fn close_channels(
+    up_sender: &tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
+    channels: &Channels,
+    reason: &str,
+) {
+    let channel_ids: Vec<u32> = channels.open.iter().map(|c| *c.key()).collect();
+    for channel_id in channel_ids {
+        channels.open.remove(&channel_id);
+        channels.closed.insert(channel_id);
+        let close_channel = CloseChannel {
+            channel_id,
+            reason_code: reason
+                .to_string()
+                .try_into()
+                .expect("Internal error: this operation can not fail because a short string can always be converted into Str0255"),
+        };
+        let msg = PoolExtMessages::Mining(Mining::CloseChannel(close_channel));
+        if up_sender.send(msg).await.is_err() {
+            warn!("Pool connection closed before channel {channel_id} was closed");
+            break;
+        }
+        info!("Closed channel {channel_id}");
+    }
+}
+
+/// Channel a message from the pool is for, when it is for a single channel.
+fn channel_id(msg: &Mining) -> Option<u32> {
+    match msg {
+        Mining::NewExtendedMiningJob(m) => Some(m.channel_id),
+        Mining::SetNewPrevHash(m) => Some(m.channel_id),
+        Mining::SetTarget(m) => Some(m.channel_id),
+        Mining::SetExtranoncePrefix(m) => Some(m.channel_id),
+        Mining::SetCustomMiningJobSuccess(m) => Some(m.channel_id),
+        Mining::SubmitSharesSuccess(m) => Some(m.channel_id),
+        Mining::SubmitSharesError(m) => Some(m.channel_id),
+        _ => None,
+    }
+}
+
+/// Receives from the current downstream, never resolves when there is none.
+async fn recv_from(
+    downstream: &mut Option<tokio::sync::mpsc::Receiver<Mining<'static>>>,
+) -> Option<Mining<'static>> {
+    match downstream {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Counts as lost the shares the pool did not acknowledge in time, and alerts when too many
+/// are lost.
+fn expire_pending_shares(shares_sent_up: Arc<PendingShares>) -> AbortOnDrop {
+    let task = crate::proxy_state::spawn(async move {
+        let mut alerting = false;
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let lost = shares_sent_up.expire(Configuration::ack_timeout(), Instant::now());
+            if lost > 0 {
+                warn!("{lost} shares not acknowledged by the pool in time");
+                crate::api::metrics::shares_lost(lost as u64);
+                crate::router::health::shares_lost(lost);
+            }
+            crate::api::metrics::shares_pending_ack(shares_sent_up.len() as u64);
+
+            let recently_lost = shares_sent_up.recently_lost();
+            let threshold = Configuration::lost_shares_alert_threshold();
+            if threshold > 0 && recently_lost >= threshold {
+                if !alerting {
+                    error!(
+                        "ALERT: {recently_lost} shares lost in the last {} minutes, check the pool accounting",
+                        pending::ALERT_WINDOW.as_secs() / 60
+                    );
+                    alerting = true;
+                }
+            } else if alerting {
+                info!("Lost shares back under the alert threshold");
+                alerting = false;
+            }
+        }
+    });
+    task.into()
 }
 
 fn relay_up(
-    mut receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
+    mut downstreams: tokio::sync::mpsc::Receiver<Downstream>,
+    to_downstream: tokio::sync::watch::Sender<Option<tokio::sync::mpsc::Sender<Mining<'static>>>>,
     up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
-    shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
+    shares_sent_up: Arc<PendingShares>,
+    channels: Arc<Channels>,
 ) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            if let Mining::SubmitSharesExtended(m) = &msg {
-                shares_sent_up.insert(
-                    m.job_id,
-                    ShareSentUp {
-                        channel_id: m.channel_id,
-                        sequence_number: m.sequence_number,
-                    },
-                );
-            };
-            let msg = PoolExtMessages::Mining(msg);
-            if up_sender.send(msg).await.is_err() {
-                break;
+    let task = crate::proxy_state::spawn(async move {
+        let mut receiver = None;
+        loop {
+            tokio::select! {
+                downstream = downstreams.recv() => {
+                    let Some(downstream) = downstream else {
+                        ProxyState::update_share_accounter_state(
+                            ShareAccounterState::Down,
+                            "relay_up: downstreams channel closed",
+                        );
+                        break;
+                    };
+                    // The new downstream opens its own channels
+                    if !downstream.takes_over_channels {
+                        close_channels(&up_sender, &channels, "downstream restarted").await;
+                    }
+                    receiver = Some(downstream.receiver);
+                    to_downstream.send_replace(Some(downstream.sender));
+                    debug!("Share accounter downstream attached");
+                }
+                msg = recv_from(&mut receiver) => {
+                    let Some(msg) = msg else {
+                        // Restarting, wait for the next downstream
+                        receiver = None;
+                        continue;
+                    };
+                    if let Mining::SubmitSharesExtended(m) = &msg {
+                        let difficulty = channels
+                            .open
+                            .get(&m.channel_id)
+                            .map(|d| *d)
+                            .unwrap_or(0.0);
+                        ledger::share_sent(m, difficulty);
+                        let lost = shares_sent_up.insert(ShareSentUp {
+                            channel_id: m.channel_id,
+                            sequence_number: m.sequence_number,
+                            job_id: m.job_id,
+                            difficulty,
+                            sent_at: Instant::now(),
+                        });
+                        if lost {
+                            warn!("Too many shares waiting for an ack, oldest one counted as lost");
+                            crate::api::metrics::shares_lost(1);
+                            crate::router::health::shares_lost(1);
+                        }
+                    };
+                    let msg = PoolExtMessages::Mining(msg);
+                    if up_sender.send(msg).await.is_err() {
+                        ProxyState::update_share_accounter_state(
+                            ShareAccounterState::Down,
+                            "relay_up: pool connection closed",
+                        );
+                        break;
+                    }
+                }
             }
         }
     });
     task.into()
 }
 
+/// Id of the job a `ref_job_id` of the pool refers to, it is in its 4 most significant bytes.
+fn job_id(ref_job_id: u64) -> u32 {
+    let job_id_bytes = ref_job_id.to_le_bytes();
+    u32::from_le_bytes(
+        job_id_bytes[4..8]
+            .try_into()
+            .expect("Internal error: job_id_bytes[4..8] can always be convertible into a u32"),
+    )
+}
+
 fn relay_down(
     mut up_receiver: tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
-    sender: tokio::sync::mpsc::Sender<Mining<'static>>,
-    shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
+    downstream: tokio::sync::watch::Receiver<Option<tokio::sync::mpsc::Sender<Mining<'static>>>>,
+    shares_sent_up: Arc<PendingShares>,
+    channels: Arc<Channels>,
+    to_reconcile: tokio::sync::mpsc::Sender<ShareAccountingMessages<'static>>,
 ) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
+    let task = crate::proxy_state::spawn(async move {
         while let Some(msg) = up_receiver.recv().await {
             match msg {
                 PoolExtMessages::ShareAccountingMessages(msg) => {
                     if let ShareAccountingMessages::ShareOk(msg) = msg {
-                        let job_id_bytes = msg.ref_job_id.to_le_bytes();
-                        let job_id = u32::from_le_bytes(job_id_bytes[4..8].try_into().expect("Internal error: job_id_bytes[4..8] can always be convertible into a u32"));
-                        let share_sent_up = match shares_sent_up.remove(&job_id) {
-                            Some(shares) => shares.1,
-                            // job_id doesn't exist
-                            None => {
-                                error!("Pool sent invalid share success");
-                                // Set global pool state to Down
-                                ProxyState::update_pool_state(PoolState::Down);
-                                return;
+                        let job_id = job_id(msg.ref_job_id);
+                        // Attributed to the oldest share of the job, the ack does not tell
+                        // which one it is for
+                        let share_sent_up = match shares_sent_up.acknowledge(job_id) {
+                            Ack::Pending(share) => share,
+                            Ack::Late(share) => {
+                                warn!(
+                                    job_id,
+                                    "Pool acknowledged a share after {}s",
+                                    share.sent_at.elapsed().as_secs()
+                                );
+                                crate::api::metrics::late_ack();
+                                share
+                            }
+                            // Counted, the pool accounting is checked by the reconciliation
+                            Ack::Unexpected => {
+                                warn!(job_id, "Pool acknowledged a share that is not pending");
+                                crate::api::metrics::unexpected_ack();
+                                continue;
                             }
                         };
 
+                        debug!(
+                            job_id,
+                            channel_id = share_sent_up.channel_id,
+                            "Share acknowledged by the pool"
+                        );
+                        crate::api::metrics::share_acknowledged(share_sent_up.sequence_number);
+                        crate::router::health::share_acknowledged();
+                        ledger::share_ok(
+                            share_sent_up.channel_id,
+                            share_sent_up.sequence_number,
+                            job_id,
+                            msg.ref_job_id,
+                        );
+                        // The channels of a previous downstream are closed
+                        if channels.closed.contains(&share_sent_up.channel_id) {
+                            continue;
+                        }
                         let success = Mining::SubmitSharesSuccess(SubmitSharesSuccess {
                             channel_id: share_sent_up.channel_id,
                             last_sequence_number: share_sent_up.sequence_number,
+                            // Each ack is relayed on its own
                             new_submits_accepted_count: 1,
-                            new_shares_sum: 1,
+                            new_shares_sum: share_sent_up.difficulty.round().max(1.0) as u64,
                         });
-                        if let Err(e) = sender.send(success).await {
-                            error!("{e:?}");
-                            ProxyState::update_share_accounter_state(ShareAccounterState::Down);
-                            break;
-                        }
+                        send_downstream(&downstream, success).await;
+                    } else {
+                        // Fails when reconciliation is disabled, the messages are then dropped
+                        let _ = to_reconcile.try_send(msg);
                     };
                 }
                 PoolExtMessages::Mining(msg) => {
-                    if let Err(e) = sender.send(msg).await {
-                        error!("{e}");
-                        ProxyState::update_share_accounter_state(ShareAccounterState::Down);
-                        break;
+                    // Keep the channels opened by the downstream, with their difficulty for
+                    // the ledger
+                    match &msg {
+                        Mining::OpenExtendedMiningChannelSuccess(m) => {
+                            let difficulty = ledger::target_to_difficulty(&m.target.to_vec());
+                            channels.closed.remove(&m.channel_id);
+                            channels.open.insert(m.channel_id, difficulty);
+                        }
+                        Mining::SetTarget(m) => {
+                            if let Some(mut difficulty) = channels.open.get_mut(&m.channel_id) {
+                                *difficulty =
+                                    ledger::target_to_difficulty(&m.maximum_target.to_vec());
+                            }
+                        }
+                        Mining::NewExtendedMiningJob(_)
+                        | Mining::SetNewPrevHash(_)
+                        | Mining::SetCustomMiningJobSuccess(_) => crate::router::health::new_job(),
+                        _ => (),
                     }
+                    if let Some(channel_id) = channel_id(&msg) {
+                        if channels.closed.contains(&channel_id) {
+                            debug!(channel_id, "Dropping message for a closed channel");
+                            continue;
+                        }
+                        // A group channel, or one opened before the proxy saw it: the
+                        // downstream knows what to do with it
+                        if !channels.open.contains_key(&channel_id) {
+                            warn!(channel_id, "Relaying message for a channel not opened here");
+                            crate::api::metrics::unknown_channel_message();
+                        }
+                    }
+                    send_downstream(&downstream, msg).await;
                 }
                 _ => {
                     error!("Pool send unexpected message on mining connection");
-                    ProxyState::update_pool_state(PoolState::Down);
+                    ProxyState::update_pool_state(
+                        PoolState::Down,
+                        "relay_down: pool sent an unexpected message",
+                    );
                     break;
                 }
             }
@@ -125,3 +413,16 @@ fn relay_down(
     });
     task.into()
 }
+
+/// Sends to the current downstream. Messages sent while the mining subsystem restarts are
+/// dropped, the new downstream opens its own channels.
+async fn send_downstream(
+    downstream: &tokio::sync::watch::Receiver<Option<tokio::sync::mpsc::Sender<Mining<'static>>>>,
+    msg: Mining<'static>,
+) {
+    let sender = downstream.borrow().clone();
+    match sender {
+        Some(sender) if sender.send(msg).await.is_ok() => (),
+        _ => debug!("No downstream attached, message dropped"),
+    }
+}
@@ -0,0 +1,293 @@
+//! Shares sent to the pool that are waiting for a `ShareOk`.
+//!
+//! Each share is tracked on its own by channel and sequence number. A `ShareOk` carries only
+//! the `ref_job_id` of the share it accounts for, not its channel or sequence number, so it is
+//! matched to the oldest share of that job still waiting, assuming the pool accounts for the
+//! shares of a job in the order they are sent. This is an approximation: the number of acks of
+//! each job is exact, which of its shares each ack is for is not. When the pool skips a share of
+//! a job, the ack of the next one is attributed to it and the last one times out instead. The
+//! `SubmitSharesSuccess` sent downstream, the `ShareOk` records of the ledger and the acks of
+//! each downstream in the metrics rely on this attribution.
+//!
+//! Shares not acknowledged within `ack_timeout_secs` are counted as lost. They are kept for a
+//! while longer so that an ack arriving late is recognized and counted as such, rather than
+//! taken for an ack of a share we never sent.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Max number of shares waiting for an ack, the oldest are counted as lost past this.
+const MAX_PENDING_SHARES: usize = 10_000;
+/// Max number of expired shares kept to recognize late acks.
+const MAX_EXPIRED_SHARES: usize = 10_000;
+/// How long after the timeout an ack is still counted as late.
+const LATE_ACK_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Window over which lost shares are counted for alerting.
+pub const ALERT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Channel id and sequence number of a share.
+type ShareId = (u32, u32);
+
+#[derive(Debug, Clone)]
+pub struct ShareSentUp {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub job_id: u32,
+    /// Difficulty of the channel target when the share was sent
+    pub difficulty: f64,
+    pub sent_at: Instant,
+}
+
+impl ShareSentUp {
+    fn id(&self) -> ShareId {
+        (self.channel_id, self.sequence_number)
+    }
+}
+
+/// What a `ShareOk` acknowledges.
+#[derive(Debug)]
+pub enum Ack {
+    Pending(ShareSentUp),
+    /// The share was already counted as lost
+    Late(ShareSentUp),
+    /// No share is waiting for an ack on this job: a duplicate ack or an ack for a share we
+    /// never sent
+    Unexpected,
+}
+
+#[derive(Debug, Default)]
+pub struct PendingShares {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    shares: HashMap<ShareId, ShareSentUp>,
+    /// Pending shares in the order they were sent, the acknowledged ones are skipped
+    sent: VecDeque<ShareId>,
+    /// Shares that timed out and when they did
+    expired: HashMap<ShareId, (ShareSentUp, Instant)>,
+    /// Expired shares in the order they timed out, the acknowledged ones are skipped
+    expired_order: VecDeque<ShareId>,
+    /// Pending and expired shares of each job, in the order they were sent
+    by_job: HashMap<u32, VecDeque<ShareId>>,
+    /// When the shares of the last `ALERT_WINDOW` were lost, oldest first
+    lost_at: VecDeque<Instant>,
+}
+
+impl Inner {
+    fn expire_share(&mut self, id: ShareId, now: Instant) -> bool {
+        let Some(share) = self.shares.remove(&id) else {
+            return false;
+        };
+        self.expired.insert(id, (share, now));
+        self.expired_order.push_back(id);
+        self.lost_at.push_back(now);
+        true
+    }
+
+    /// Oldest pending share, the acknowledged ones in front of it are dropped.
+    fn oldest_pending(&mut self) -> Option<&ShareSentUp> {
+        while let Some(id) = self.sent.front() {
+            if self.shares.contains_key(id) {
+                return self.shares.get(id);
+            }
+            self.sent.pop_front();
+        }
+        None
+    }
+}
+
+impl PendingShares {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns true if the oldest share had to be counted as lost to make room.
+    pub fn insert(&self, share: ShareSentUp) -> bool {
+        let mut inner = self.lock();
+        let mut lost = false;
+        if inner.shares.len() >= MAX_PENDING_SHARES {
+            if let Some(oldest) = inner.oldest_pending().map(ShareSentUp::id) {
+                inner.sent.pop_front();
+                lost = inner.expire_share(oldest, Instant::now());
+            }
+        }
+        inner
+            .by_job
+            .entry(share.job_id)
+            .or_default()
+            .push_back(share.id());
+        inner.sent.push_back(share.id());
+        inner.shares.insert(share.id(), share);
+        lost
+    }
+
+    /// Takes the oldest share sent for `job_id`, see the module documentation.
+    pub fn acknowledge(&self, job_id: u32) -> Ack {
+        let mut inner = self.lock();
+        let Inner {
+            shares,
+            expired,
+            by_job,
+            ..
+        } = &mut *inner;
+        let Some(queue) = by_job.get_mut(&job_id) else {
+            return Ack::Unexpected;
+        };
+        let mut ack = Ack::Unexpected;
+        while let Some(id) = queue.pop_front() {
+            if let Some(share) = shares.remove(&id) {
+                ack = Ack::Pending(share);
+                break;
+            }
+            if let Some((share, _)) = expired.remove(&id) {
+                ack = Ack::Late(share);
+                break;
+            }
+        }
+        if queue.is_empty() {
+            by_job.remove(&job_id);
+        }
+        ack
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().shares.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().shares.is_empty()
+    }
+
+    /// Counts as lost the shares sent more than `timeout` ago. Returns how many were lost.
+    pub fn expire(&self, timeout: Duration, now: Instant) -> usize {
+        let mut inner = self.lock();
+        let mut lost = 0;
+        while let Some(oldest) = inner
+            .oldest_pending()
+            .filter(|s| now.saturating_duration_since(s.sent_at) >= timeout)
+            .map(ShareSentUp::id)
+        {
+            inner.sent.pop_front();
+            if inner.expire_share(oldest, now) {
+                lost += 1;
+            }
+        }
+
+        let Inner {
+            shares,
+            expired,
+            expired_order,
+            by_job,
+            lost_at,
+            ..
+        } = &mut *inner;
+        while let Some(id) = expired_order.front() {
+            let forget = match expired.get(id) {
+                Some((_, at)) => {
+                    expired.len() > MAX_EXPIRED_SHARES
+                        || now.saturating_duration_since(*at) >= LATE_ACK_WINDOW
+                }
+                // Acknowledged late
+                None => true,
+            };
+            if !forget {
+                break;
+            }
+            expired.remove(id);
+            expired_order.pop_front();
+        }
+        // Forget the shares that are neither pending nor expired anymore
+        by_job.retain(|_, queue| {
+            queue.retain(|id| shares.contains_key(id) || expired.contains_key(id));
+            !queue.is_empty()
+        });
+        while lost_at
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= ALERT_WINDOW)
+        {
+            lost_at.pop_front();
+        }
+        lost
+    }
+
+    /// Number of shares lost in the last `ALERT_WINDOW`.
+    pub fn recently_lost(&self) -> usize {
+        self.lock().lost_at.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share(sequence_number: u32, job_id: u32, sent_at: Instant) -> ShareSentUp {
+        ShareSentUp {
+            channel_id: 1,
+            sequence_number,
+            job_id,
+            difficulty: 1.0,
+            sent_at,
+        }
+    }
+
+    #[test]
+    fn acknowledges_each_share_of_a_job() {
+        let pending = PendingShares::default();
+        let now = Instant::now();
+        pending.insert(share(1, 7, now));
+        pending.insert(share(2, 7, now));
+        pending.insert(share(3, 8, now));
+
+        assert!(matches!(pending.acknowledge(7), Ack::Pending(s) if s.sequence_number == 1));
+        assert!(matches!(pending.acknowledge(7), Ack::Pending(s) if s.sequence_number == 2));
+        // Duplicate ack
+        assert!(matches!(pending.acknowledge(7), Ack::Unexpected));
+        assert!(matches!(pending.acknowledge(9), Ack::Unexpected));
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(pending.acknowledge(8), Ack::Pending(s) if s.sequence_number == 3));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn expires_shares_and_recognizes_late_acks() {
+        let pending = PendingShares::default();
+        let start = Instant::now();
+        pending.insert(share(1, 7, start));
+        pending.insert(share(2, 7, start + Duration::from_secs(30)));
+        let timeout = Duration::from_secs(60);
+
+        assert_eq!(pending.expire(timeout, start + Duration::from_secs(59)), 0);
+        assert_eq!(pending.expire(timeout, start + Duration::from_secs(60)), 1);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.recently_lost(), 1);
+
+        assert!(matches!(pending.acknowledge(7), Ack::Late(s) if s.sequence_number == 1));
+        assert!(matches!(pending.acknowledge(7), Ack::Pending(s) if s.sequence_number == 2));
+        assert!(matches!(pending.acknowledge(7), Ack::Unexpected));
+
+        // Lost shares only count for the alert window
+        pending.expire(timeout, start + Duration::from_secs(60) + ALERT_WINDOW);
+        assert_eq!(pending.recently_lost(), 0);
+        assert!(pending.lock().by_job.is_empty());
+        assert!(pending.lock().expired_order.is_empty());
+    }
+
+    #[test]
+    fn makes_room_by_expiring_the_oldest_share() {
+        let pending = PendingShares::default();
+        let now = Instant::now();
+        for sequence_number in 0..MAX_PENDING_SHARES as u32 {
+            assert!(!pending.insert(share(sequence_number, 7, now)));
+        }
+        // The oldest was acknowledged, the next one makes room
+        assert!(matches!(pending.acknowledge(7), Ack::Pending(s) if s.sequence_number == 0));
+        assert!(!pending.insert(share(10_000, 7, now)));
+        assert!(pending.insert(share(10_001, 7, now)));
+        assert!(matches!(pending.acknowledge(7), Ack::Late(s) if s.sequence_number == 1));
+        assert_eq!(pending.len(), MAX_PENDING_SHARES);
+    }
+}
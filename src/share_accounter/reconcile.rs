@@ -0,0 +1,324 @@
+//! Reconciliation of the shares recorded in the ledger with the pool's share accounting.
+//!
+//! When the pool finds a block it sends `NewBlockFound`. The last block found is kept in a
+//! cursor saved next to the ledger, so that it survives restarts, with the last block
+//! reconciled. Every `reconcile_interval_secs`, until it is reconciled, the PPLNS window of the
+//! last block found is requested with `GetWindow` and the number of shares the pool accounted
+//! for each job of the window is compared with the shares sent for the job recorded in the
+//! ledger. Shares sent but not in the window are reported as missing, with the channel and
+//! sequence number of the ones the pool never acknowledged, shares in the window beyond the ones
+//! sent as extra.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use demand_share_accounting_ext::*;
+use parser::{PoolExtMessages, ShareAccountingMessages};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use super::ledger::{self, SubmittedShare};
+use crate::{config::Configuration, shared::utils::AbortOnDrop};
+
+/// Shares accounted in a slice of the window.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceShares {
+    pub ref_job_id: u64,
+    pub number_of_shares: u64,
+}
+
+/// A job for which the pool accounted a different number of shares than were sent.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub job_id: u32,
+    pub pool_shares: u64,
+    pub submitted_shares: u64,
+    /// `(channel_id, sequence_number)` of the shares sent the pool did not acknowledge
+    pub unacknowledged: Vec<(u32, u32)>,
+}
+
+/// Result of the comparison of a window with the ledger.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub pool_shares: u64,
+    pub submitted_shares: u64,
+    pub missing: u64,
+    pub extra: u64,
+    pub mismatched_jobs: Vec<Mismatch>,
+}
+
+/// Compares the shares accounted by the pool for each job of the window with the shares sent
+/// for the job. Shares sent for jobs outside of the window are not taken into account.
+pub fn compare(window: &[SliceShares], submitted: &HashMap<u32, Vec<SubmittedShare>>) -> Report {
+    let mut report = Report::default();
+    let mut pool: HashMap<u32, u64> = HashMap::new();
+    for slice in window {
+        *pool.entry(super::job_id(slice.ref_job_id)).or_default() += slice.number_of_shares;
+    }
+    let (Some(first), Some(last)) = (pool.keys().min().copied(), pool.keys().max().copied()) else {
+        return report;
+    };
+    let mut job_ids: Vec<u32> = pool
+        .keys()
+        .chain(submitted.keys().filter(|id| (first..=last).contains(*id)))
+        .copied()
+        .collect();
+    job_ids.sort_unstable();
+    job_ids.dedup();
+    for job_id in job_ids {
+        let pool_shares = pool.get(&job_id).copied().unwrap_or(0);
+        let shares = submitted
+            .get(&job_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let submitted_shares = shares.len() as u64;
+        report.pool_shares += pool_shares;
+        report.submitted_shares += submitted_shares;
+        if pool_shares != submitted_shares {
+            report.missing += submitted_shares.saturating_sub(pool_shares);
+            report.extra += pool_shares.saturating_sub(submitted_shares);
+            report.mismatched_jobs.push(Mismatch {
+                job_id,
+                pool_shares,
+                submitted_shares,
+                unacknowledged: shares
+                    .iter()
+                    .filter(|share| !share.acknowledged)
+                    .map(|share| (share.channel_id, share.sequence_number))
+                    .collect(),
+            });
+        }
+    }
+    report
+}
+
+/// Last block found by the pool and last block reconciled, as hex.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cursor {
+    last_block: Option<String>,
+    reconciled: Option<String>,
+}
+
+impl Cursor {
+    fn path(ledger: &Path) -> PathBuf {
+        let mut path = ledger.as_os_str().to_owned();
+        path.push(".reconcile");
+        path.into()
+    }
+
+    /// Reads the cursor saved next to the ledger, a missing or corrupted one starts over.
+    async fn load() -> Self {
+        let Some(path) = ledger::path().map(Self::path) else {
+            return Self::default();
+        };
+        match tokio::fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                warn!("Ignoring reconciliation cursor {}: {e}", path.display());
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                warn!("Can not read reconciliation cursor {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    /// Writes the cursor to a temporary file renamed over the previous one, so that it is never
+    /// half written.
+    async fn save(&self) {
+        let Some(path) = ledger::path().map(Self::path) else {
+            return;
+        };
+        let content = match serde_json::to_vec(self) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Can not serialize reconciliation cursor: {e}");
+                return;
+            }
+        };
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let result = match tokio::fs::write(&tmp, content).await {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Can not save reconciliation cursor {}: {e}", path.display());
+        }
+    }
+
+    /// Block whose window is still to be reconciled.
+    fn pending(&self) -> Option<&str> {
+        let last_block = self.last_block.as_deref()?;
+        (self.reconciled.as_deref() != Some(last_block)).then_some(last_block)
+    }
+}
+
+/// Starts the reconciliation task, it gets the share accounting messages other than `ShareOk`
+/// from `relay_down`.
+pub fn start(
+    up_sender: Sender<PoolExtMessages<'static>>,
+    mut from_pool: Receiver<ShareAccountingMessages<'static>>,
+) -> AbortOnDrop {
+    let task = crate::proxy_state::spawn(async move {
+        let mut cursor = Cursor::load().await;
+        // Block of the window asked to the pool
+        let mut requested: Option<String> = None;
+        let mut next_check = Instant::now() + Configuration::reconcile_interval();
+        loop {
+            tokio::select! {
+                msg = from_pool.recv() => match msg {
+                    Some(ShareAccountingMessages::NewBlockFound(m)) => {
+                        let block_hash = ledger::to_hex(&m.block_hash.to_vec());
+                        info!("Pool found block {block_hash}");
+                        cursor.last_block = Some(block_hash);
+                        cursor.save().await;
+                    }
+                    Some(ShareAccountingMessages::GetWindowSuccess(m)) => {
+                        let window: Vec<SliceShares> = m
+                            .slices
+                            .into_inner()
+                            .iter()
+                            .map(|s| SliceShares {
+                                ref_job_id: s.ref_job_id,
+                                number_of_shares: s.number_of_shares as u64,
+                            })
+                            .collect();
+                        if reconcile(window).await && requested.is_some() {
+                            cursor.reconciled = requested.take();
+                            cursor.save().await;
+                        }
+                        next_check = Instant::now() + Configuration::reconcile_interval();
+                    }
+                    Some(ShareAccountingMessages::GetWindowBusy(m)) => {
+                        debug!("Pool busy, asking the window again in {}s", m.retry_in_seconds);
+                        next_check = Instant::now() + Duration::from_secs(m.retry_in_seconds);
+                    }
+                    Some(_) => (),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(next_check) => {
+                    next_check = Instant::now() + Configuration::reconcile_interval();
+                    let Some(block_hash) = cursor.pending() else {
+                        debug!("No block found by the pool since the last reconciliation");
+                        continue;
+                    };
+                    let Some(get_window) = ledger::from_hex(block_hash)
+                        .and_then(|hash| hash.try_into().ok())
+                        .map(|block_hash| GetWindow { block_hash })
+                    else {
+                        error!("Invalid block hash {block_hash} in the reconciliation cursor");
+                        cursor.last_block = None;
+                        continue;
+                    };
+                    requested = Some(block_hash.to_string());
+                    let msg = PoolExtMessages::ShareAccountingMessages(
+                        ShareAccountingMessages::GetWindow(get_window),
+                    );
+                    // If the pool does not answer we ask again at the next check
+                    if up_sender.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    task.into()
+}
+
+/// Compares the window with the ledger, returns false if the ledger could not be read.
+async fn reconcile(window: Vec<SliceShares>) -> bool {
+    let submitted = match tokio::task::spawn_blocking(ledger::submitted_shares).await {
+        Ok(Some(submitted)) => submitted,
+        Ok(None) => return false,
+        Err(e) => {
+            error!("Failed to read the share ledger: {e}");
+            return false;
+        }
+    };
+    let report = compare(&window, &submitted);
+    crate::api::metrics::reconciliation(report.missing, report.extra);
+    if report.missing == 0 && report.extra == 0 {
+        info!(
+            "Pool window matches the ledger: {} shares in {} slices",
+            report.pool_shares,
+            window.len()
+        );
+    } else {
+        warn!(
+            "Pool window does not match the ledger: {} shares missing, {} extra ({} in the pool window, {} sent)",
+            report.missing, report.extra, report.pool_shares, report.submitted_shares
+        );
+        for mismatch in report.mismatched_jobs {
+            warn!(
+                job_id = mismatch.job_id,
+                pool_shares = mismatch.pool_shares,
+                submitted_shares = mismatch.submitted_shares,
+                "Job does not match, shares not acknowledged (channel, sequence number): {:?}",
+                mismatch.unacknowledged
+            );
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_and_extra_shares() {
+        let slice = |job_id: u64, number_of_shares| SliceShares {
+            ref_job_id: job_id << 32 | 7,
+            number_of_shares,
+        };
+        let window = [slice(10, 3), slice(11, 2), slice(13, 1)];
+        let sent = |sequence_numbers: &[u32]| {
+            sequence_numbers
+                .iter()
+                .map(|&sequence_number| SubmittedShare {
+                    channel_id: 1,
+                    sequence_number,
+                    acknowledged: sequence_number % 2 == 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        // 12 is in the window range but the pool did not account it, 9 and 14 are outside
+        let submitted = HashMap::from([
+            (9, sent(&[1, 2])),
+            (10, sent(&[3, 4, 6])),
+            (11, sent(&[5])),
+            (12, sent(&[7, 8])),
+            (14, sent(&[9])),
+        ]);
+        let report = compare(&window, &submitted);
+        let mismatch = |job_id, pool_shares, submitted_shares, unacknowledged: &[u32]| Mismatch {
+            job_id,
+            pool_shares,
+            submitted_shares,
+            unacknowledged: unacknowledged.iter().map(|&n| (1, n)).collect(),
+        };
+        assert_eq!(
+            report,
+            Report {
+                pool_shares: 6,
+                submitted_shares: 6,
+                missing: 2,
+                extra: 2,
+                mismatched_jobs: vec![
+                    mismatch(11, 2, 1, &[5]),
+                    mismatch(12, 0, 2, &[7]),
+                    mismatch(13, 1, 0, &[]),
+                ],
+            }
+        );
+        assert_eq!(compare(&[], &submitted), Report::default());
+    }
+}
@@ -9,6 +9,9 @@ use tracing::warn;
 enum Task {
     RelayUp(AbortOnDrop),
     RelayDown(AbortOnDrop),
+    Drain(AbortOnDrop),
+    Reconcile(AbortOnDrop),
+    Expire(AbortOnDrop),
 }
 
 pub struct TaskManager {
@@ -20,7 +23,7 @@ impl TaskManager {
     #[allow(unused_variables)]
     pub fn initialize() -> Arc<Mutex<Self>> {
         let (sender, mut receiver) = mpsc::channel(10);
-        let handle = tokio::task::spawn(async move {
+        let handle = crate::proxy_state::spawn(async move {
             let mut tasks = vec![];
             while let Some(task) = receiver.recv().await {
                 tasks.push(task);
@@ -54,4 +57,22 @@ impl TaskManager {
             .await
             .map_err(|_| ())
     }
+    pub async fn add_drain(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task.send(Task::Drain(abortable)).await.map_err(|_| ())
+    }
+    pub async fn add_reconcile(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::Reconcile(abortable))
+            .await
+            .map_err(|_| ())
+    }
+    pub async fn add_expire(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::Expire(abortable))
+            .await
+            .map_err(|_| ())
+    }
 }
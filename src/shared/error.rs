@@ -1,5 +1,17 @@
+#[derive(Debug)]
 pub enum Sv1IngressError {
     TranslatorDropped,
     DownstreamDropped,
-    TaskFailed,
+    /// The SV1 listener can not listen on the address
+    Bind(String, std::io::Error),
+}
+
+impl std::fmt::Display for Sv1IngressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sv1IngressError::TranslatorDropped => write!(f, "translator dropped"),
+            Sv1IngressError::DownstreamDropped => write!(f, "downstream dropped"),
+            Sv1IngressError::Bind(address, e) => write!(f, "can not listen on {address}: {e}"),
+        }
+    }
 }
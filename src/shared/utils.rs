@@ -1,22 +1,40 @@
-use std::fmt::Display;
+use std::{fmt::Display, future::Future, pin::Pin};
 
 use sv1_api::utils::HexU32Be;
 use tokio::task::AbortHandle;
 use tokio::task::JoinHandle;
 
-#[derive(Debug)]
 pub struct AbortOnDrop {
     abort_handle: AbortHandle,
+    /// Resolves when the task finishes, `None` once it did
+    finished: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl std::fmt::Debug for AbortOnDrop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AbortOnDrop")
+            .field("abort_handle", &self.abort_handle)
+            .finish()
+    }
 }
 
 impl AbortOnDrop {
     pub fn new<T: Send + 'static>(handle: JoinHandle<T>) -> Self {
         let abort_handle = handle.abort_handle();
-        Self { abort_handle }
+        Self {
+            abort_handle,
+            finished: Some(Box::pin(async move {
+                let _ = handle.await;
+            })),
+        }
     }
 
-    pub fn is_finished(&self) -> bool {
-        self.abort_handle.is_finished()
+    /// Resolves when the task finishes, whether it returned, panicked or was aborted.
+    pub async fn finished(&mut self) {
+        if let Some(finished) = self.finished.as_mut() {
+            finished.await;
+            self.finished = None;
+        }
     }
 }
 
@@ -0,0 +1,106 @@
+//! Graceful shutdown on SIGINT/SIGTERM.
+//!
+//! When a signal is received the monitor stops accepting SV1 connections, asks the miners to
+//! reconnect and starts the drain: the share accounter waits (bounded) for the `ShareOk` of the
+//! shares already sent up, closes the channels with the pool and notifies that it is done.
+//! A second signal, or the drain taking too long, exits right away.
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Max time we wait for the pool to acknowledge the shares sent up.
+pub const SHARES_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Max time from the signal to the exit, whatever the state of the proxy.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+lazy_static! {
+    static ref REQUESTED: watch::Sender<bool> = watch::Sender::new(false);
+    static ref DRAIN: watch::Sender<bool> = watch::Sender::new(false);
+    /// Number of share accounters that completed the drain
+    static ref DRAINED: watch::Sender<usize> = watch::Sender::new(0);
+}
+
+/// Listens for SIGINT and SIGTERM (Ctrl-C on Windows).
+pub fn listen_for_signals() {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested, draining in-flight shares. Send the signal again to exit now");
+        REQUESTED.send_replace(true);
+        tokio::spawn(async {
+            tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+            warn!("Graceful shutdown timed out, exiting");
+            std::process::exit(1);
+        });
+        wait_for_signal().await;
+        warn!("Second signal received, exiting");
+        std::process::exit(1);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut interrupt, mut terminate) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        _ => {
+            warn!("Impossible to listen for SIGINT/SIGTERM, graceful shutdown disabled");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupt.recv() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        warn!("Impossible to listen for Ctrl-C, graceful shutdown disabled");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Returns true once a shutdown signal has been received.
+pub fn is_requested() -> bool {
+    *REQUESTED.borrow()
+}
+
+/// Resolves once a shutdown signal has been received.
+pub async fn requested() {
+    let mut requested = REQUESTED.subscribe();
+    // The sender lives in a static so it can not be dropped
+    let _ = requested.wait_for(|requested| *requested).await;
+}
+
+/// Resolves when the drain starts, used by the share accounter.
+pub async fn wait_for_drain() {
+    let mut drain = DRAIN.subscribe();
+    // The sender lives in a static so it can not be dropped
+    let _ = drain.wait_for(|started| *started).await;
+}
+
+/// Called by the share accounter when every share has been acknowledged (or the timeout
+/// elapsed) and the channels have been closed.
+pub fn drained() {
+    DRAINED.send_modify(|drained| *drained += 1);
+}
+
+/// Starts the drain and waits, bounded, for the `accounters` share accounters running to
+/// complete it.
+pub async fn drain(accounters: usize) {
+    DRAIN.send_replace(true);
+    let timeout = SHARES_DRAIN_TIMEOUT + Duration::from_secs(1);
+    let mut drained = DRAINED.subscribe();
+    if tokio::time::timeout(timeout, drained.wait_for(|drained| *drained >= accounters))
+        .await
+        .is_err()
+    {
+        warn!("Share accounter did not complete the drain in time");
+    }
+}
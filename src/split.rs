@@ -0,0 +1,509 @@
+//! Hashrate split across several pools, with `pool_policy = "split"`.
+//!
+//! The proxy stays connected to every configured pool. Each pool has its own pipeline: pool
+//! connection, share accounter and translator. The miners are spread across the pipelines in
+//! proportion to the `weight` of the pools, e.g. 80 and 20: a new miner goes to the pool that is
+//! the most under its share of the connected miners. The split is by miner, not by hash time, so
+//! it is only as even as the miners are alike.
+//!
+//! JD is not used in this mode, the translators mine on the pool jobs. The components of a
+//! pipeline run in its own [`Scope`], so that a pipeline going down is seen by its supervisor
+//! only. A pipeline that fails is restarted on its own, with the backoff of the pool subsystem,
+//! and connects to whichever address the pool host resolves to then. Its miners are moved to the
+//! pipelines that are up meanwhile, see [`crate::ingress`], and once it is back the miners above
+//! the share of the other pipelines are asked to reconnect so that they come back to it.
+//!
+//! When the pools are reloaded, only the pipelines of the pools added or removed are started or
+//! stopped, and the miners are spread again for the new weights.
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use demand_share_accounting_ext::parser::PoolExtMessages;
+use tokio::sync::{
+    mpsc::{channel, error::SendError, Receiver, Sender, WeakSender},
+    watch, Notify,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    api,
+    config::{Configuration, PoolConfig},
+    ingress::{
+        self,
+        sv1_ingress::{Attach, RunHandle, TranslatorRun},
+    },
+    proxy_state::{ProxyState, Scope},
+    router::{
+        self,
+        policy::{self, PoolPolicy},
+        Candidate, Router,
+    },
+    sd_notify, share_accounter,
+    shared::utils::AbortOnDrop,
+    shutdown,
+    supervisor::{Backoff, Subsystem},
+    translator, Ingress, Sv1Downstream,
+};
+
+/// When the pipelines start the miners wait this long for all of them to be up, so that they
+/// are not all sent to the first pool that answered.
+const STARTUP_GRACE: Duration = Duration::from_secs(10);
+
+/// A pipeline that came up is given this long to stay up before miners are moved back to it.
+const REBALANCE_DELAY: Duration = Duration::from_secs(60);
+
+/// Runs a pipeline for each pool and spreads the miners across them, until shutdown.
+pub async fn run(router: &mut Router, reload_signal: &mut Receiver<()>) {
+    if Configuration::tp_address().is_some() {
+        warn!("JD is not used when the hashrate is split, mining on the pool jobs");
+    }
+    let (downs_sv1_tx, mut downs_sv1_rx) = channel(10);
+    let mut ingress = Ingress::start(downs_sv1_tx).await;
+    let mut watchdog = tokio::time::interval(Duration::from_secs(1));
+    let mut pipelines: Vec<Pipeline> = router.pools().iter().cloned().map(Pipeline::new).collect();
+    let mut reloaded = false;
+    loop {
+        let total_weight: u32 = pipelines.iter().map(|p| p.pool.weight).sum();
+        for pipeline in &pipelines {
+            info!(
+                "Sending {:.1}% of the miners to {}",
+                f64::from(pipeline.pool.weight) * 100.0 / f64::from(total_weight),
+                pipeline.pool.host
+            );
+        }
+        let rebuild = {
+            let pipeline_up = Notify::new();
+            let supervise = futures::future::join_all(
+                pipelines
+                    .iter()
+                    .map(|pipeline| pipeline.supervise(router, &pipeline_up)),
+            );
+            let dispatch = dispatch(&mut downs_sv1_rx, &pipelines, &pipeline_up);
+            // The weights may have changed
+            let rebalance = rebalance(&pipelines, &pipeline_up, reloaded);
+            tokio::pin!(supervise, dispatch, rebalance);
+            loop {
+                tokio::select! {
+                    _ = shutdown::requested() => {
+                        sd_notify::stopping();
+                        // Stop accepting new miners, the connected ones are asked to reconnect
+                        // elsewhere
+                        ingress.stop();
+                        let notified = ingress::sv1_ingress::reconnect_all().await;
+                        info!("Sent client.reconnect to {notified} miners");
+                        // Keep the pool connections up until the in-flight shares are
+                        // acknowledged
+                        let accounters = pipelines.iter().filter(|p| p.is_up()).count();
+                        shutdown::drain(accounters).await;
+                        return;
+                    }
+                    Some(()) = reload_signal.recv() => {
+                        let (restart_ingress, rebuild) = reload_configuration();
+                        if restart_ingress {
+                            ingress.restart().await;
+                        }
+                        if !matches!(rebuild, Rebuild::None) {
+                            break rebuild;
+                        }
+                    }
+                    _ = ingress.supervise() => (),
+                    // Neither resolves, the pipelines are restarted and the SV1 listener keeps
+                    // its side of the channel
+                    _ = &mut supervise => (),
+                    _ = &mut dispatch => (),
+                    _ = &mut rebalance => (),
+                    _ = watchdog.tick() => {
+                        let up = pipelines.iter().filter(|p| p.is_up()).count();
+                        sd_notify::watchdog(&format!("{up}/{} pools up", pipelines.len()));
+                    }
+                }
+            }
+        };
+        router.update_pools(Configuration::pools());
+        if let Rebuild::All = rebuild {
+            info!("Restarting the pipelines for the new configuration");
+            pipelines.clear();
+        }
+        pipelines = update_pipelines(pipelines, router.pools());
+        reloaded = true;
+    }
+}
+
+/// Keeps the pipelines of the pools still configured, with their new weight, and creates the
+/// ones of the new pools. The others are stopped when dropped, their miners are handed to the
+/// remaining pipelines.
+fn update_pipelines(mut pipelines: Vec<Pipeline>, pools: &[PoolConfig]) -> Vec<Pipeline> {
+    let mut updated = Vec::with_capacity(pools.len());
+    for pool in pools {
+        match pipelines.iter().position(|p| p.pool.same_upstream(pool)) {
+            Some(index) => {
+                let mut pipeline = pipelines.swap_remove(index);
+                pipeline.pool = pool.clone();
+                updated.push(pipeline);
+            }
+            None => {
+                info!("Starting a pipeline for {}", pool.host);
+                updated.push(Pipeline::new(pool.clone()));
+            }
+        }
+    }
+    for pipeline in &pipelines {
+        info!("Stopping the pipeline of {}", pipeline.pool.host);
+    }
+    updated
+}
+
+/// Hands each miner to the pipeline that is the most under its share, see
+/// [`policy::least_served`]. The miners wait while no pipeline is up.
+async fn dispatch(
+    downstreams: &mut Receiver<Attach>,
+    pipelines: &[Pipeline],
+    pipeline_up: &Notify,
+) {
+    let grace = tokio::time::Instant::now() + STARTUP_GRACE;
+    while let Some(Attach {
+        mut downstream,
+        run: slot,
+    }) = downstreams.recv().await
+    {
+        loop {
+            // Created before looking at the pipelines so that a pipeline coming up in between
+            // is not missed
+            let notified = pipeline_up.notified();
+            let served: Vec<Option<(usize, u32)>> = pipelines
+                .iter()
+                .map(|p| p.is_up().then(|| (p.miners(), p.pool.weight)))
+                .collect();
+            let starting = tokio::time::Instant::now() < grace && served.contains(&None);
+            let Some(index) = policy::least_served(&served).filter(|_| !starting) else {
+                tokio::select! {
+                    _ = notified => (),
+                    _ = tokio::time::sleep_until(grace), if starting => (),
+                }
+                continue;
+            };
+            let pipeline = &pipelines[index];
+            let Some((translator, run)) = lock(&pipeline.translator).clone() else {
+                continue;
+            };
+            slot.set(&run);
+            let miner = downstream.0.downgrade();
+            match translator.send(downstream).await {
+                Ok(()) => {
+                    lock(&pipeline.miners).push(miner);
+                    break;
+                }
+                Err(SendError(not_sent)) => {
+                    // The translator is gone, the pipeline is being restarted
+                    *lock(&pipeline.translator) = None;
+                    downstream = not_sent;
+                }
+            }
+        }
+    }
+}
+
+/// Asks the miners above the share of their pipeline to reconnect when a pipeline comes up, or
+/// at once if the pools have just been `reloaded`, see [`policy::over_served`]. They are handed
+/// to the pipelines under their share by [`dispatch`].
+async fn rebalance(pipelines: &[Pipeline], pipeline_up: &Notify, mut reloaded: bool) {
+    loop {
+        if !std::mem::take(&mut reloaded) {
+            pipeline_up.notified().await;
+        }
+        // The pipelines coming up meanwhile are rebalanced at once
+        tokio::time::sleep(REBALANCE_DELAY).await;
+        let served: Vec<Option<(usize, u32)>> = pipelines
+            .iter()
+            .map(|p| p.is_up().then(|| (p.miners(), p.pool.weight)))
+            .collect();
+        for (pipeline, excess) in pipelines.iter().zip(policy::over_served(&served)) {
+            if excess == 0 {
+                continue;
+            }
+            let notified = ingress::sv1_ingress::reconnect(pipeline.release(excess)).await;
+            info!(
+                "Asked {notified} miners of {} to reconnect to rebalance the split",
+                pipeline.pool.host
+            );
+        }
+    }
+}
+
+/// What the pipelines need for a new configuration to take effect.
+enum Rebuild {
+    None,
+    /// Pipelines started or stopped for the pools added or removed
+    Pools,
+    /// Every pipeline restarted
+    All,
+}
+
+/// Reloads the configuration. Returns whether the SV1 listener must be restarted, and what the
+/// pipelines need for the new configuration to take effect.
+fn reload_configuration() -> (bool, Rebuild) {
+    info!("Reloading configuration");
+    let changes = match Configuration::reload() {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("Invalid configuration, keeping the current one: {e}");
+            return (false, Rebuild::None);
+        }
+    };
+    let mut live = changes.live;
+    let mut ignored = changes.ignored;
+    if changes.restart.contains(&"tp_address") {
+        ignored.push("tp_address");
+    }
+    // The process started in split mode, leaving it needs a restart
+    if Configuration::pool_policy() != PoolPolicy::Split {
+        live.retain(|setting| *setting != "pool_policy");
+        ignored.push("pool_policy");
+    }
+    if !live.is_empty() {
+        info!("Configuration applied: {}", live.join(", "));
+    }
+    if !ignored.is_empty() {
+        warn!(
+            "Configuration changes need a restart of the process: {}",
+            ignored.join(", ")
+        );
+    }
+    let restart_ingress = changes.restart.contains(&"listen_address");
+    // The translators read it when they start
+    let rebuild = if changes.restart.contains(&"min_extranonce2_size") {
+        Rebuild::All
+    } else if changes.pools {
+        Rebuild::Pools
+    } else {
+        Rebuild::None
+    };
+    (restart_ingress, rebuild)
+}
+
+/// Where the miners are sent to the translator, and its run.
+type Translator = (Sender<Sv1Downstream>, RunHandle);
+
+/// Components running for a pipeline.
+struct Running {
+    /// Dropped before the handles, so that the miners the translator drops when it stops are
+    /// moved to the other pipelines
+    _run: TranslatorRun,
+    handles: Vec<(AbortOnDrop, String)>,
+    /// Kept so that the share accounter does not see its downstream go away
+    _share_accounter: Sender<share_accounter::Downstream>,
+}
+
+/// A pool and the components mining on it.
+struct Pipeline {
+    pool: PoolConfig,
+    /// State of the components of the pipeline
+    scope: Scope,
+    /// Addresses the pool host resolved to the last time it did
+    candidates: Mutex<Vec<Candidate>>,
+    /// Where the miners are sent to the translator, `None` while the pipeline is down
+    translator: Mutex<Option<Translator>>,
+    /// Miners sent to the translator, gone once it dropped them
+    miners: Mutex<Vec<WeakSender<String>>>,
+    /// Held here rather than in the supervising future, so that on shutdown the share
+    /// accounter can drain and on a reload of the pools the pipeline keeps running
+    running: tokio::sync::Mutex<Option<Running>>,
+    backoff: Mutex<Backoff>,
+}
+
+impl Pipeline {
+    fn new(pool: PoolConfig) -> Self {
+        Self {
+            scope: Scope::new(&pool.host),
+            candidates: Mutex::new(Vec::new()),
+            pool,
+            translator: Mutex::new(None),
+            miners: Mutex::new(Vec::new()),
+            running: tokio::sync::Mutex::new(None),
+            backoff: Mutex::new(Backoff::new(Subsystem::Pool)),
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        lock(&self.translator).is_some()
+    }
+
+    /// Number of miners mining on this pipeline.
+    fn miners(&self) -> usize {
+        let mut miners = lock(&self.miners);
+        miners.retain(|miner| miner.upgrade().is_some());
+        miners.len()
+    }
+
+    /// Takes up to `count` of the miners of the pipeline, the last ones sent to it, so that they
+    /// are not counted here while they reconnect.
+    fn release(&self, count: usize) -> Vec<Sender<String>> {
+        let mut miners = lock(&self.miners);
+        miners.retain(|miner| miner.upgrade().is_some());
+        let keep = miners.len().saturating_sub(count);
+        miners
+            .drain(keep..)
+            .filter_map(|miner| miner.upgrade())
+            .collect()
+    }
+
+    /// Starts the pipeline and restarts it when one of its components stops or goes down, never
+    /// resolves.
+    async fn supervise(&self, router: &Router, pipeline_up: &Notify) {
+        self.scope.run(self.run(router, pipeline_up)).await
+    }
+
+    /// Supervision loop, run in the scope of the pipeline.
+    async fn run(&self, router: &Router, pipeline_up: &Notify) {
+        let host = &self.pool.host;
+        loop {
+            let mut running = self.running.lock().await;
+            // Still running when the supervision resumes after a reload of the pools
+            let started = match running.take() {
+                Some(started) => Some(started),
+                None => {
+                    // Components of the previous run are stopped, start from a clean state
+                    ProxyState::update_proxy_state_up("split: starting a pool pipeline");
+                    match self.start(router).await {
+                        Some((started, translator)) => {
+                            lock(&self.backoff).started(Instant::now());
+                            *lock(&self.translator) = Some(translator);
+                            pipeline_up.notify_waiters();
+                            info!("Mining on {host}");
+                            // A pool connection and the SV1 listener are up, systemd ignores
+                            // the repeated READY
+                            sd_notify::ready();
+                            Some(started)
+                        }
+                        None => {
+                            error!("Impossible to start mining on {host}");
+                            None
+                        }
+                    }
+                }
+            };
+            if let Some(started) = started {
+                let started = running.insert(started);
+                let mut state = ProxyState::subscribe();
+                tokio::select! {
+                    name = crate::task_finished(&mut started.handles) => {
+                        error!("Task {name:?} of {host} finished");
+                    }
+                    down = went_down(&mut state) => error!("{down} of {host} is DOWN"),
+                }
+                *lock(&self.translator) = None;
+                *running = None;
+                api::status::proxy_initialized(None, false);
+            }
+            drop(running);
+            let delay = lock(&self.backoff).failed(Instant::now());
+            warn!(
+                "Restarting the pipeline of {host} in {} seconds...",
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Resolves the pool host and connects to the first of its addresses that accepts.
+    async fn connect(
+        &self,
+        router: &Router,
+    ) -> Option<(
+        Sender<PoolExtMessages<'static>>,
+        Receiver<PoolExtMessages<'static>>,
+        AbortOnDrop,
+    )> {
+        let mut candidates = lock(&self.candidates).clone();
+        match router::resolve_pool(&self.pool).await {
+            Ok(resolved) => {
+                candidates = resolved;
+                *lock(&self.candidates) = candidates.clone();
+            }
+            Err(e) => warn!(
+                "Failed to resolve pool {}, trying its previous addresses: {e}",
+                self.pool.host
+            ),
+        }
+        for candidate in candidates {
+            match router.connect_to(&candidate).await {
+                Ok(connection) => {
+                    api::status::proxy_initialized(Some(candidate), false);
+                    return Some(connection);
+                }
+                Err(e) => warn!("Failed to connect to {}: {e:?}", candidate.address),
+            }
+        }
+        None
+    }
+
+    /// Connects to the pool and starts the share accounter and the translator on top of it.
+    /// Returns them with the sender of the miners to the translator.
+    async fn start(&self, router: &Router) -> Option<(Running, Translator)> {
+        let (send_to_pool, recv_from_pool, pool_connection) = self.connect(router).await?;
+        let (share_accounter_downstreams, downstreams) = channel(1);
+        let share_accounter =
+            match share_accounter::start(downstreams, recv_from_pool, send_to_pool).await {
+                Ok(abortable) => abortable,
+                Err(_) => {
+                    error!("Failed to start share_accounter");
+                    return None;
+                }
+            };
+
+        let (translator_downs_tx, translator_downs_rx) = channel(10);
+        let (translator_up_tx, mut translator_up_rx) = channel(10);
+        let translator = match translator::start(translator_downs_rx, translator_up_tx).await {
+            Ok(abortable) => abortable,
+            Err(e) => {
+                error!("Impossible to initialize translator: {e}");
+                return None;
+            }
+        };
+        let (to_translator, from_translator, _) = translator_up_rx.recv().await?;
+        share_accounter_downstreams
+            .send(share_accounter::Downstream::new(
+                from_translator,
+                to_translator,
+            ))
+            .await
+            .ok()?;
+
+        let run = TranslatorRun::default();
+        let miners = (translator_downs_tx, run.handle());
+        let running = Running {
+            _run: run,
+            handles: vec![
+                (pool_connection, "pool_connection".to_string()),
+                (share_accounter, "share_accounter".to_string()),
+                (translator, "translator".to_string()),
+            ],
+            _share_accounter: share_accounter_downstreams,
+        };
+        Some((running, miners))
+    }
+}
+
+/// Resolves with the component that failed once the pipeline is down. The translator tasks stay
+/// up when it fails, only its state tells.
+async fn went_down(state: &mut watch::Receiver<ProxyState>) -> String {
+    loop {
+        {
+            let state = state.borrow_and_update();
+            if Subsystem::failed(&state.errors()).is_some() {
+                return state.is_down().1.unwrap_or("Pipeline".to_string());
+            }
+        }
+        if state.changed().await.is_err() {
+            // The scope outlives the pipeline
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The values are replaced as a whole, a poisoned lock still holds a consistent one
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,278 @@
+//! Supervision of the proxy components.
+//!
+//! The components are grouped in subsystems, restarted on their own:
+//!
+//! ```text
+//! ingress      SV1 listener, the miners connect to it
+//! pool         pool connection and share accounter
+//! └ mining     translator
+//!   └ jd       JD client and template receiver
+//! ```
+//!
+//! A failure restarts the subsystem it happened in and the ones under it, the others keep
+//! running. The miners stay connected to the ingress while the mining subsystem restarts, their
+//! sessions are then moved to the new translator. The share accounter keeps the pool connection
+//! and the shares waiting for an ack.
+//!
+//! The translator opens its channel through the JD client, that relays it to the pool. When the
+//! Template Provider or the JD client fail, only the JD client is stopped: the translator keeps
+//! running, is attached to the share accounter on the channel it already has and mines on the
+//! pool jobs. Once the Template Provider is reachable again a restart of the mining subsystem is
+//! requested, to go back to JD.
+//!
+//! Each subsystem has its own [`RestartPolicy`]: the first restart is immediate, the next ones
+//! are delayed with an exponential backoff that is reset once the subsystem stayed up long
+//! enough.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Notify,
+};
+
+use crate::{
+    proxy_state::{DownstreamState, DownstreamType, ProxyStates, UpstreamState, UpstreamType},
+    shared::utils::AbortOnDrop,
+};
+
+lazy_static! {
+    static ref RESTARTS: Restarts = Restarts::default();
+}
+
+#[derive(Default)]
+struct Restarts {
+    requests: Mutex<VecDeque<(Subsystem, String)>>,
+    notify: Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Ingress,
+    Pool,
+    Mining,
+    Jd,
+}
+
+impl Subsystem {
+    pub fn policy(self) -> RestartPolicy {
+        match self {
+            Subsystem::Ingress => RestartPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(30),
+                reset_after: Duration::from_secs(60),
+            },
+            Subsystem::Pool => RestartPolicy {
+                initial_delay: Duration::from_secs(10),
+                max_delay: Duration::from_secs(120),
+                reset_after: Duration::from_secs(5 * 60),
+            },
+            Subsystem::Mining => RestartPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+                reset_after: Duration::from_secs(5 * 60),
+            },
+            // Delays the return to JD, the pool jobs are used meanwhile
+            Subsystem::Jd => RestartPolicy {
+                initial_delay: Duration::from_secs(5),
+                max_delay: Duration::from_secs(5 * 60),
+                reset_after: Duration::from_secs(10 * 60),
+            },
+        }
+    }
+
+    /// Subsystem a component belongs to.
+    pub fn of(state: &ProxyStates) -> Self {
+        match state {
+            ProxyStates::Pool(_)
+            | ProxyStates::ShareAccounter(_)
+            | ProxyStates::InternalInconsistency(_) => Subsystem::Pool,
+            ProxyStates::Tp(_) | ProxyStates::Jd(_) => Subsystem::Jd,
+            ProxyStates::Downstream(DownstreamState::Down(down))
+                if down.contains(&DownstreamType::JdClientMiningDownstream) =>
+            {
+                Subsystem::Jd
+            }
+            ProxyStates::Upstream(UpstreamState::Down(down))
+                if down.contains(&UpstreamType::JDCMiningUpstream) =>
+            {
+                Subsystem::Jd
+            }
+            ProxyStates::Downstream(_) | ProxyStates::Upstream(_) | ProxyStates::Translator(_) => {
+                Subsystem::Mining
+            }
+        }
+    }
+
+    /// Subsystem to restart for the components that are down. A failure of JD takes
+    /// precedence over the one of the translator, that usually follows from it.
+    pub fn failed(errors: &[ProxyStates]) -> Option<Self> {
+        let failed: Vec<Subsystem> = errors.iter().map(Subsystem::of).collect();
+        [Subsystem::Pool, Subsystem::Jd, Subsystem::Mining]
+            .into_iter()
+            .find(|subsystem| failed.contains(subsystem))
+    }
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Subsystem::Ingress => "ingress",
+            Subsystem::Pool => "pool",
+            Subsystem::Mining => "mining",
+            Subsystem::Jd => "jd",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Delay of the second restart, doubled at each of the next ones
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// How long the subsystem must stay up for the backoff to be reset
+    pub reset_after: Duration,
+}
+
+/// Restarts of a subsystem.
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RestartPolicy,
+    failures: u32,
+    started_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(subsystem: Subsystem) -> Self {
+        Self {
+            policy: subsystem.policy(),
+            failures: 0,
+            started_at: None,
+        }
+    }
+
+    /// Called when the subsystem is started.
+    pub fn started(&mut self, now: Instant) {
+        self.started_at = Some(now);
+    }
+
+    /// Called when the subsystem failed, returns how long to wait before restarting it.
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        if self
+            .started_at
+            .take()
+            .is_some_and(|at| now.saturating_duration_since(at) >= self.policy.reset_after)
+        {
+            self.failures = 0;
+        }
+        let delay = match self.failures {
+            0 => Duration::ZERO,
+            n => self
+                .policy
+                .initial_delay
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(self.policy.max_delay),
+        };
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+}
+
+/// Asks the supervisor to restart `subsystem`, for a component that needs it without being
+/// down, like the Template Provider becoming reachable again.
+pub fn restart(subsystem: Subsystem, reason: impl Into<String>) {
+    RESTARTS
+        .requests
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push_back((subsystem, reason.into()));
+    RESTARTS.notify.notify_one();
+}
+
+/// Resolves with the next restart requested.
+pub async fn restart_requested() -> (Subsystem, String) {
+    loop {
+        let request = RESTARTS
+            .requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front();
+        if let Some(request) = request {
+            return request;
+        }
+        RESTARTS.notify.notified().await;
+    }
+}
+
+/// Forwards the messages of a channel that outlives the subsystem `to` belongs to. The receiver
+/// is taken back by the next relay when the subsystem restarts.
+pub fn relay<T: Send + 'static>(
+    from: Arc<tokio::sync::Mutex<Receiver<T>>>,
+    to: Sender<T>,
+) -> AbortOnDrop {
+    let task = tokio::spawn(async move {
+        let mut from = from.lock().await;
+        while let Some(msg) = from.recv().await {
+            if to.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+    task.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_state::{PoolState, TpState, TranslatorState};
+
+    #[test]
+    fn backs_off_until_the_subsystem_stays_up() {
+        let mut backoff = Backoff::new(Subsystem::Mining);
+        let start = Instant::now();
+        let delays: Vec<u64> = (0..9)
+            .map(|_| {
+                backoff.started(start);
+                backoff.failed(start).as_secs()
+            })
+            .collect();
+        assert_eq!(delays, vec![0, 1, 2, 4, 8, 16, 32, 60, 60]);
+
+        backoff.started(start);
+        let later = start + Subsystem::Mining.policy().reset_after;
+        assert_eq!(backoff.failed(later), Duration::ZERO);
+        backoff.started(later);
+        assert_eq!(backoff.failed(later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn restarts_the_subsystem_that_failed() {
+        let tp_down = ProxyStates::Tp(TpState::Down);
+        let translator_down = ProxyStates::Translator(TranslatorState::Down);
+        let jdc_down = ProxyStates::Downstream(DownstreamState::Down(vec![
+            DownstreamType::JdClientMiningDownstream,
+        ]));
+        assert_eq!(Subsystem::failed(&[]), None);
+        assert_eq!(
+            Subsystem::failed(&[tp_down.clone(), jdc_down]),
+            Some(Subsystem::Jd)
+        );
+        assert_eq!(
+            Subsystem::failed(&[translator_down.clone(), tp_down.clone()]),
+            Some(Subsystem::Jd)
+        );
+        assert_eq!(
+            Subsystem::failed(&[ProxyStates::Translator(TranslatorState::Down)]),
+            Some(Subsystem::Mining)
+        );
+        assert_eq!(
+            Subsystem::failed(&[translator_down, ProxyStates::Pool(PoolState::Down), tp_down]),
+            Some(Subsystem::Pool)
+        );
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
+    config::Configuration,
     proxy_state::{DownstreamType, ProxyState},
     translator::{
         error::Error, proxy::Bridge, upstream::diff_management::UpstreamDifficultyConfig,
+        utils::ShareRateLimit,
     },
 };
 
@@ -13,8 +15,7 @@ use tokio::sync::{
     broadcast,
     mpsc::{Receiver, Sender},
 };
-use tokio::task;
-use tracing::{error, info};
+use tracing::{error, info, info_span, Instrument};
 
 pub async fn start_accept_connection(
     task_manager: Arc<Mutex<TaskManager>>,
@@ -23,17 +24,18 @@ pub async fn start_accept_connection(
     bridge: Arc<Mutex<super::super::proxy::Bridge>>,
     upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
     mut downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr)>,
+    share_rate_limit: Arc<ShareRateLimit>,
 ) -> Result<(), Error<'static>> {
     let handle = {
         let task_manager = task_manager.clone();
-        task::spawn(async move {
+        crate::proxy_state::spawn(async move {
             // This is needed. When bridge want to send a notification if no downstream is
             // available at least one receiver must be around.
             let _s = tx_mining_notify.subscribe();
             while let Some((send, recv, addr)) = downstreams.recv().await {
                 info!("Translator opening connection for ip {}", addr);
                 // TODO handle also cases where a cpuminer want to connect
-                let expected_hash_rate = *crate::EXPECTED_SV1_HASHPOWER;
+                let expected_hash_rate = Configuration::downstream_hashrate();
                 if Bridge::ready(&bridge).await.is_err() {
                     error!("Bridge not ready");
                     break;
@@ -65,12 +67,21 @@ pub async fn start_accept_connection(
                             send,
                             recv,
                             task_manager.clone(),
+                            share_rate_limit.clone(),
                         )
+                        .instrument(info_span!(
+                            "downstream",
+                            connection_id = opened.channel_id,
+                            ip = %addr
+                        ))
                         .await
                     }
                     Err(e) => {
                         error!("{e:?}");
-                        ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                        ProxyState::update_downstream_state(
+                            DownstreamType::TranslatorDownstream,
+                            format!("start_accept_connection: {e:?}"),
+                        );
                         break;
                     }
                 }
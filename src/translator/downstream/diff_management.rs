@@ -31,8 +31,6 @@ impl Downstream {
         let (message, _) = diff_to_sv1_message(diff as f64)?;
         Downstream::send_message_downstream(self_.clone(), message).await;
 
-        tokio::spawn(crate::translator::utils::check_share_rate_limit());
-
         Ok(())
     }
 
@@ -107,6 +105,24 @@ impl Downstream {
         Ok(())
     }
 
+    /// Sets the share rate the PID controller aims for, so that a configuration reload is applied
+    /// to miners that are already connected.
+    pub fn update_shares_per_minute(
+        self_: &Arc<Mutex<Self>>,
+        shares_per_minute: f32,
+    ) -> ProxyResult<'static, ()> {
+        self_.safe_lock(|d| {
+            if d.difficulty_mgmt.pid_controller.setpoint != shares_per_minute {
+                info!(
+                    "Downstream {}: target share rate updated to {} shares/min",
+                    d.connection_id, shares_per_minute
+                );
+                d.difficulty_mgmt.pid_controller.setpoint(shares_per_minute);
+            }
+        })?;
+        Ok(())
+    }
+
     /// Increments the number of shares since the last difficulty update.
     pub(super) fn save_share(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
         self_.safe_lock(|d| {
@@ -417,7 +433,7 @@ mod test {
         downstream.difficulty_mgmt.estimated_downstream_hash_rate = start_hashrate as f32;
 
         let total_run_time = std::time::Duration::from_secs(10);
-        let config_shares_per_minute = crate::SHARE_PER_MIN;
+        let config_shares_per_minute = crate::config::DEFAULT_SHARES_PER_MINUTE;
         let timer = std::time::Instant::now();
         let mut elapsed = std::time::Duration::from_secs(0);
 
@@ -1,9 +1,12 @@
 use crate::{
+    api::metrics,
+    blocks::{self, Block},
+    config::Configuration,
     proxy_state::{DownstreamType, ProxyState},
     shared::utils::AbortOnDrop,
     translator::{
         error::Error,
-        utils::{allow_submit_share, validate_share},
+        utils::{share_block, validate_share, ShareRateLimit},
     },
 };
 
@@ -33,7 +36,7 @@ use sv1_api::{
     utils::{Extranonce, HexU32Be},
     IsServer,
 };
-use tracing::{error, info, warn};
+use tracing::{error, info, info_span, warn};
 
 #[derive(Debug, Clone)]
 pub struct DownstreamDifficultyConfig {
@@ -77,6 +80,8 @@ pub struct Downstream {
     pub(super) upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
     pub last_call_to_update_hr: u128,
     pub(super) last_notify: Option<server_to_client::Notify<'static>>,
+    /// Shared by the downstreams of the translator
+    share_rate_limit: Arc<ShareRateLimit>,
 }
 
 impl Downstream {
@@ -94,23 +99,24 @@ impl Downstream {
         send_to_down: Sender<String>,
         recv_from_down: Receiver<String>,
         task_manager: Arc<Mutex<TaskManager>>,
+        share_rate_limit: Arc<ShareRateLimit>,
     ) {
         assert!(last_notify.is_some());
 
         let (tx_outgoing, receiver_outgoing) = channel(crate::TRANSLATOR_BUFFER_SIZE);
 
         // The initial difficulty is derived from the formula: difficulty = hash_rate / (shares_per_second * 2^32),
-        let initial_hash_rate = *crate::EXPECTED_SV1_HASHPOWER;
-        let share_per_second = crate::SHARE_PER_MIN / 60.0;
+        let initial_hash_rate = Configuration::downstream_hashrate();
+        let share_per_second = Configuration::shares_per_minute() / 60.0;
         let initial_difficulty = initial_hash_rate / (share_per_second * 2f32.powf(32.0));
 
         // The PID controller uses negative proportional (P) and integral (I) gains to reduce difficulty
-        // when the actual share rate falls below the target rate (SHARE_PER_MIN). Negative gains are chosen
+        // when the actual share rate falls below the target rate (shares_per_minute). Negative gains are chosen
         // because a lower share rate indicates the difficulty is too high for the miner, requiring a downward
         // adjustment to make mining easier.
         //
         // // Example:
-        // - Target share rate (SHARE_PER_MIN) = 10 shares/min.
+        // - Target share rate (shares_per_minute) = 10 shares/min.
         // - Case 1: Actual share rate = 5 shares/min (less than target):
         //   - Error = 10 - 5 = 5 (positive).
         //   - P output = -3.0 * 5 = -15 (reduces difficulty by 15).
@@ -127,18 +133,24 @@ impl Downstream {
         // add a small positive adjustment to prevent overshooting.
 
         let output_limit = initial_hash_rate * 0.7;
-        let mut pid: Pid<f32> = Pid::new(crate::SHARE_PER_MIN, output_limit);
+        let mut pid: Pid<f32> = Pid::new(Configuration::shares_per_minute(), output_limit);
         pid.p(-0.01, output_limit)
             .i(0.01, output_limit)
             .d(0.01, output_limit);
 
         let difficulty_mgmt = DownstreamDifficultyConfig {
-            estimated_downstream_hash_rate: *crate::EXPECTED_SV1_HASHPOWER,
+            estimated_downstream_hash_rate: Configuration::downstream_hashrate(),
             submits_since_last_update: 0,
             timestamp_of_last_update: 0,
             pid_controller: pid,
             current_difficulty: initial_difficulty,
         };
+        metrics::downstream_connected(
+            connection_id,
+            &host,
+            difficulty_mgmt.estimated_downstream_hash_rate,
+            initial_difficulty,
+        );
 
         let downstream = Arc::new(Mutex::new(Downstream {
             connection_id,
@@ -154,6 +166,7 @@ impl Downstream {
             upstream_difficulty_config,
             last_call_to_update_hr: 0,
             last_notify: last_notify.clone(),
+            share_rate_limit,
         }));
 
         if let Err(e) = start_receive_downstream(
@@ -165,7 +178,10 @@ impl Downstream {
         .await
         {
             error!("Failed to start receive downstream task: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                format!("new_downstream: receive task failed to start: {e}"),
+            );
         };
 
         if let Err(e) = start_send_to_downstream(
@@ -178,7 +194,10 @@ impl Downstream {
         .await
         {
             error!("Failed to start send_to_downstream task {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                format!("new_downstream: send task failed to start: {e}"),
+            );
         };
 
         if let Err(e) = start_notify(
@@ -192,7 +211,10 @@ impl Downstream {
         .await
         {
             error!("Failed to start notify task: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                format!("new_downstream: notify task failed to start: {e}"),
+            );
         };
     }
 
@@ -204,6 +226,7 @@ impl Downstream {
         bridge: Arc<Mutex<super::super::proxy::Bridge>>,
         upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
         downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr)>,
+        share_rate_limit: Arc<ShareRateLimit>,
     ) -> Result<AbortOnDrop, Error<'static>> {
         let task_manager = TaskManager::initialize();
         let abortable = task_manager
@@ -217,11 +240,15 @@ impl Downstream {
             bridge,
             upstream_difficulty_config,
             downstreams,
+            share_rate_limit,
         )
         .await
         {
             error!("Translator downstream failed to accept: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                format!("accept_connections: {e}"),
+            );
             return Err(e);
         };
         Ok(abortable)
@@ -272,7 +299,10 @@ impl Downstream {
             Err(e) => {
                 // Poisoned mutex
                 error!("{e}");
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    format!("send_message_downstream: downstream mutex poisoned: {e}"),
+                );
                 return;
             }
         };
@@ -287,13 +317,19 @@ impl Downstream {
             Err(e) => {
                 error!("{e}");
                 // Poisoned mutex
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    format!("send_message_upstream: downstream mutex poisoned: {e}"),
+                );
                 return;
             }
         };
         if sender.send(msg).await.is_err() {
             error!("Translator downstream failed to send message");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                "send_message_upstream: bridge channel closed",
+            );
         }
     }
     #[cfg(test)]
@@ -324,6 +360,7 @@ impl Downstream {
             upstream_difficulty_config,
             last_call_to_update_hr: 0,
             last_notify: None,
+            share_rate_limit: ShareRateLimit::new(),
         }
     }
 }
@@ -383,55 +420,110 @@ impl IsServer<'static> for Downstream {
     /// When miner find the job which meets requested difficulty, it can submit share to the server.
     /// Only [Submit](client_to_server::Submit) requests for authorized user names can be submitted.
     fn handle_submit(&self, request: &client_to_server::Submit<'static>) -> bool {
+        let _span = info_span!("submit", job_id = %request.job_id).entered();
         info!("Down: Handling mining.submit: {:?}", &request);
+        metrics::share_received(self.connection_id);
+        let difficulty = self.difficulty_mgmt.current_difficulty;
+        let worker_share = |outcome| {
+            worker_stats::share(&request.user_name, self.connection_id, outcome, difficulty)
+        };
 
         // check first job received
         if !self.first_job_received {
+            metrics::share_rejected("no_job_sent");
+            worker_share(ShareOutcome::Rejected);
             return false;
         }
         //check allowed to send shares
-        match allow_submit_share() {
+        match self.share_rate_limit.allow_submit_share() {
             Ok(true) => {
                 let Some(job) = &self.last_notify else {
                     error!("Share rejected: No last job found");
+                    metrics::share_rejected("no_job");
+                    worker_share(ShareOutcome::Rejected);
                     return false;
                 };
+                if request.job_id != job.job_id {
+                    warn!("Share rejected: Stale share for job {}", request.job_id);
+                    metrics::share_rejected("stale");
+                    worker_share(ShareOutcome::Stale);
+                    return false;
+                }
+                let key = ShareKey {
+                    job_id: request.job_id.clone(),
+                    extranonce2: request.extra_nonce2.0.as_ref().to_vec(),
+                    ntime: request.time.0,
+                    nonce: request.nonce.0,
+                    version_bits: request.version_bits.as_ref().map(|v| v.0),
+                };
+                if worker_stats::is_duplicate(self.connection_id, key) {
+                    warn!("Share rejected: Duplicate share");
+                    metrics::share_rejected("duplicate");
+                    worker_share(ShareOutcome::Duplicate);
+                    return false;
+                }
                 //check share is valid
                 if validate_share(
                     request,
                     job,
-                    self.difficulty_mgmt.current_difficulty,
+                    difficulty,
                     self.extranonce1.clone(),
                     self.version_rolling_mask.clone(),
                 ) {
+                    metrics::share_validated(self.connection_id);
+                    if let Some((header, coinbase)) = share_block(
+                        request,
+                        job,
+                        &self.extranonce1,
+                        self.version_rolling_mask.clone(),
+                    ) {
+                        if blocks::meets_network_target(&header) {
+                            blocks::found(Block {
+                                worker: Some(request.user_name.clone()),
+                                ..Block::new(&header, &coinbase)
+                            });
+                        }
+                    }
+                    // The bridge records the outcome of the share
                     let to_send = SubmitShareWithChannelId {
                         channel_id: self.connection_id,
                         share: request.clone(),
                         extranonce: self.extranonce1.clone(),
                         extranonce2_len: self.extranonce2_len,
                         version_rolling_mask: self.version_rolling_mask.clone(),
+                        difficulty,
                     };
                     if let Err(e) = self
                         .tx_sv1_bridge
                         .try_send(DownstreamMessages::SubmitShares(to_send))
                     {
                         error!("Failed to start receive downstream task: {e:?}");
+                        metrics::share_rejected("translator_busy");
+                        worker_share(ShareOutcome::Rejected);
                         // Return false because submit was not properly handled
                         return false;
                     };
                     true
                 } else {
                     error!("Share rejected: Invalid share");
+                    metrics::share_rejected("invalid_share");
+                    worker_share(ShareOutcome::Rejected);
                     false
                 }
             }
             Ok(false) => {
                 warn!("Share rejected: Exceeded 70 shares/min limit");
+                metrics::share_rejected("rate_limited");
+                worker_share(ShareOutcome::Rejected);
                 false
             }
             Err(e) => {
                 error!("Failed to record share: {e:?}");
-                ProxyState::update_inconsistency(Some(1)); // restart proxy
+                metrics::share_rejected("internal_error");
+                worker_share(ShareOutcome::Rejected);
+                ProxyState::update_inconsistency(format!(
+                    "handle_submit: share rate limiter failed: {e:?}"
+                )); // restart proxy
                 false
             }
         }
@@ -447,6 +539,7 @@ impl IsServer<'static> for Downstream {
 
     /// Authorizes a Downstream role.
     fn authorize(&mut self, name: &str) {
+        metrics::downstream_authorized(self.connection_id, name);
         self.authorized_names.push(name.to_string());
     }
 
@@ -495,6 +588,14 @@ impl IsServer<'static> for Downstream {
     }
 }
 
+impl Drop for Downstream {
+    fn drop(&mut self) {
+        // Dropped when the connection closes or the translator is restarted
+        metrics::downstream_disconnected(self.connection_id);
+        worker_stats::connection_closed(self.connection_id);
+    }
+}
+
 impl IsMiningDownstream for Downstream {}
 
 impl IsDownstream for Downstream {
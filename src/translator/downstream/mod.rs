@@ -36,6 +36,8 @@ pub struct SubmitShareWithChannelId {
     #[allow(dead_code)]
     extranonce2_len: usize,
     pub version_rolling_mask: Option<HexU32Be>,
+    /// Difficulty of the downstream when the share was submitted
+    pub difficulty: f32,
 }
 
 /// message for notifying the bridge that a downstream target has updated
@@ -1,3 +1,5 @@
+use crate::api::metrics;
+use crate::config::Configuration;
 use crate::proxy_state::{DownstreamType, ProxyState};
 use crate::translator::downstream::SUBSCRIBE_TIMEOUT_SECS;
 use crate::translator::error::Error;
@@ -8,8 +10,7 @@ use std::sync::Arc;
 use sv1_api::json_rpc;
 use sv1_api::server_to_client;
 use tokio::sync::broadcast;
-use tokio::task;
-use tracing::{error, warn};
+use tracing::{error, warn, Instrument};
 
 pub async fn start_notify(
     task_manager: Arc<Mutex<TaskManager>>,
@@ -21,7 +22,7 @@ pub async fn start_notify(
 ) -> Result<(), Error<'static>> {
     let handle = {
         let task_manager = task_manager.clone();
-        task::spawn(async move {
+        crate::proxy_state::spawn(Instrument::in_current_span(async move {
             let timeout_timer = std::time::Instant::now();
             let mut first_sent = false;
             loop {
@@ -29,7 +30,10 @@ pub async fn start_notify(
                     Ok(is_a) => is_a,
                     Err(e) => {
                         error!("{e}");
-                        ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                        ProxyState::update_downstream_state(
+                            DownstreamType::TranslatorDownstream,
+                            format!("start_notify: downstream mutex poisoned: {e}"),
+                        );
                         break;
                     }
                 };
@@ -44,6 +48,7 @@ pub async fn start_notify(
                             error!("sv1_mining_notify_msg is None");
                             ProxyState::update_downstream_state(
                                 DownstreamType::TranslatorDownstream,
+                                "start_notify: no job to send",
                             );
                             break;
                         }
@@ -58,7 +63,10 @@ pub async fn start_notify(
                         .is_err()
                     {
                         error!("Translator Downstream Mutex Poisoned");
-                        ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                        ProxyState::update_downstream_state(
+                            DownstreamType::TranslatorDownstream,
+                            "start_notify: downstream mutex poisoned",
+                        );
                         break;
                     }
                     first_sent = true;
@@ -76,6 +84,7 @@ pub async fn start_notify(
                             error!("Translator Downstream Mutex Poisoned");
                             ProxyState::update_downstream_state(
                                 DownstreamType::TranslatorDownstream,
+                                "start_notify: downstream mutex poisoned",
                             );
                             break;
                         }
@@ -103,7 +112,7 @@ pub async fn start_notify(
                 "Downstream: Shutting down sv1 downstream job notifier for {}",
                 &host
             );
-        })
+        }))
     };
     TaskManager::add_notify(task_manager, handle.into())
         .await
@@ -114,7 +123,7 @@ async fn start_update(
     task_manager: Arc<Mutex<TaskManager>>,
     downstream: Arc<Mutex<Downstream>>,
 ) -> Result<(), Error<'static>> {
-    let handle = task::spawn(async move {
+    let handle = crate::proxy_state::spawn(Instrument::in_current_span(async move {
         loop {
             tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
             let ln = match downstream.safe_lock(|d| d.last_notify.clone()) {
@@ -125,14 +134,30 @@ async fn start_update(
                 }
             };
             assert!(ln.is_some());
+            let shares_per_minute = Configuration::shares_per_minute();
+            if let Err(e) = Downstream::update_shares_per_minute(&downstream, shares_per_minute) {
+                error!("{e}");
+                return;
+            };
             // if hashrate has changed, update difficulty management, and send new
             // mining.set_difficulty
             if let Err(e) = Downstream::try_update_difficulty_settings(&downstream, ln).await {
                 error!("{e}");
                 return;
             };
+            match downstream.safe_lock(|d| (d.connection_id, d.difficulty_mgmt.clone())) {
+                Ok((id, diff_mgmt)) => metrics::downstream_difficulty(
+                    id,
+                    diff_mgmt.estimated_downstream_hash_rate,
+                    diff_mgmt.current_difficulty,
+                ),
+                Err(e) => {
+                    error!("{e}");
+                    return;
+                }
+            }
         }
-    });
+    }));
     TaskManager::add_update(task_manager, handle.into())
         .await
         .map_err(|_| Error::TranslatorTaskManagerFailed)
@@ -7,8 +7,7 @@ use roles_logic_sv2::utils::Mutex;
 use std::sync::Arc;
 use sv1_api::{client_to_server::Submit, json_rpc};
 use tokio::sync::mpsc;
-use tokio::task;
-use tracing::{error, warn};
+use tracing::{error, warn, Instrument};
 
 pub async fn start_receive_downstream(
     task_manager: Arc<Mutex<TaskManager>>,
@@ -16,7 +15,7 @@ pub async fn start_receive_downstream(
     mut recv_from_down: mpsc::Receiver<String>,
     connection_id: u32,
 ) -> Result<(), Error<'static>> {
-    let handle = task::spawn(async move {
+    let handle = crate::proxy_state::spawn(Instrument::in_current_span(async move {
         while let Some(incoming) = recv_from_down.recv().await {
             let incoming: Result<json_rpc::Message, _> = serde_json::from_str(&incoming);
             if let Ok(incoming) = incoming {
@@ -34,7 +33,10 @@ pub async fn start_receive_downstream(
                     Downstream::handle_incoming_sv1(downstream.clone(), incoming).await
                 {
                     error!("Failed to handle incoming sv1 msg: {:?}", error);
-                    ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                    ProxyState::update_downstream_state(
+                        DownstreamType::TranslatorDownstream,
+                        format!("start_receive_downstream: {error:?}"),
+                    );
                 };
             } else {
                 // Message received could not be converted to rpc message
@@ -50,7 +52,7 @@ pub async fn start_receive_downstream(
             "Downstream: Shutting down sv1 downstream reader {}",
             connection_id
         );
-    });
+    }));
     TaskManager::add_receive_downstream(task_manager, handle.into())
         .await
         .map_err(|_| Error::TranslatorTaskManagerFailed)
@@ -4,8 +4,7 @@ use roles_logic_sv2::utils::Mutex;
 use std::sync::Arc;
 use sv1_api::json_rpc;
 use tokio::sync::mpsc;
-use tokio::task;
-use tracing::{error, warn};
+use tracing::{error, warn, Instrument};
 
 pub async fn start_send_to_downstream(
     task_manager: Arc<Mutex<TaskManager>>,
@@ -14,7 +13,7 @@ pub async fn start_send_to_downstream(
     connection_id: u32,
     host: String,
 ) -> Result<(), Error<'static>> {
-    let handle = task::spawn(async move {
+    let handle = crate::proxy_state::spawn(Instrument::in_current_span(async move {
         while let Some(res) = receiver_outgoing.recv().await {
             let to_send = match serde_json::to_string(&res) {
                 Ok(string) => format!("{}\n", string),
@@ -32,7 +31,7 @@ pub async fn start_send_to_downstream(
             "Downstream: Shutting down sv1 downstream writer: {}",
             connection_id
         );
-    });
+    }));
     TaskManager::add_send_downstream(task_manager, handle.into())
         .await
         .map_err(|_| Error::TranslatorTaskManagerFailed)
@@ -22,7 +22,7 @@ pub struct TaskManager {
 impl TaskManager {
     pub fn initialize() -> Arc<Mutex<Self>> {
         let (sender, mut receiver) = mpsc::channel(10);
-        let handle = tokio::task::spawn(async move {
+        let handle = crate::proxy_state::spawn(async move {
             let mut tasks = vec![];
             while let Some(task) = receiver.recv().await {
                 tasks.push(task);
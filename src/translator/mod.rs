@@ -11,13 +11,17 @@ use error::Error;
 use roles_logic_sv2::{parsers::Mining, utils::Mutex};
 use tracing::error;
 
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    net::IpAddr,
+    sync::{atomic::AtomicBool, Arc},
+};
 use tokio::sync::mpsc::channel;
 
 use sv1_api::server_to_client;
 use tokio::sync::broadcast;
 
 use crate::{
+    config::Configuration,
     proxy_state::{ProxyState, TranslatorState},
     shared::utils::AbortOnDrop,
 };
@@ -85,10 +89,11 @@ pub async fn start(
     ) = broadcast::channel(crate::TRANSLATOR_BUFFER_SIZE);
 
     let upstream_diff = UpstreamDifficultyConfig {
-        channel_diff_update_interval: crate::CHANNEL_DIFF_UPDTATE_INTERVAL,
-        channel_nominal_hashrate: *crate::EXPECTED_SV1_HASHPOWER,
+        channel_diff_update_interval: Configuration::channel_diff_update_interval(),
+        channel_nominal_hashrate: Configuration::downstream_hashrate(),
     };
     let diff_config = Arc::new(Mutex::new(upstream_diff));
+    let is_new_job_handled = Arc::new(AtomicBool::new(true));
 
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = upstream::Upstream::new(
@@ -99,6 +104,7 @@ pub async fn start(
         target.clone(),
         diff_config.clone(),
         send_to_up,
+        is_new_job_handled.clone(),
     )
     .await?;
 
@@ -111,12 +117,15 @@ pub async fn start(
     let startup_task = {
         let target = target.clone();
         let task_manager = task_manager.clone();
-        tokio::task::spawn(async move {
+        crate::proxy_state::spawn(async move {
             let (extended_extranonce, up_id) = match rx_sv2_extranonce.recv().await {
                 Some((extended_extranonce, up_id)) => (extended_extranonce, up_id),
                 None => {
                     error!("Failed to receive from rx_sv2_extranonce");
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        "start: extranonce channel closed",
+                    );
                     return;
                 }
             };
@@ -143,6 +152,7 @@ pub async fn start(
                 extended_extranonce,
                 target,
                 up_id,
+                is_new_job_handled,
             ) {
                 Ok(b) => b,
                 Err(e) => {
@@ -166,12 +176,24 @@ pub async fn start(
                 }
             };
 
+            let share_rate_limit = utils::ShareRateLimit::new();
+            let share_rate_limit_aborter: AbortOnDrop =
+                crate::proxy_state::spawn(share_rate_limit.clone().check()).into();
+            if TaskManager::add_share_rate_limit(task_manager.clone(), share_rate_limit_aborter)
+                .await
+                .is_err()
+            {
+                error!("{}", Error::TranslatorTaskManagerFailed);
+                return;
+            };
+
             let downstream_aborter = match downstream::Downstream::accept_connections(
                 tx_sv1_bridge,
                 tx_sv1_notify,
                 b,
                 diff_config,
                 downstreams,
+                share_rate_limit,
             )
             .await
             {
@@ -8,7 +8,7 @@ use roles_logic_sv2::{
     parsers::Mining,
     utils::{GroupId, Mutex},
 };
-use std::sync::Arc;
+use std::sync::{atomic::AtomicBool, Arc};
 use sv1_api::{client_to_server::Submit, server_to_client, utils::HexU32Be};
 use tokio::sync::broadcast;
 
@@ -22,9 +22,10 @@ use super::{
 use crate::{
     proxy_state::{ProxyState, TranslatorState, UpstreamType},
     shared::utils::AbortOnDrop,
+    worker_stats::{self, ShareOutcome},
 };
 use roles_logic_sv2::{channel_logic::channel_factory::OnNewShare, Error as RolesLogicError};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, Instrument};
 
 /// Bridge between the SV2 `Upstream` and SV1 `Downstream` responsible for the following messaging
 /// translation:
@@ -54,6 +55,12 @@ pub struct Bridge {
     last_p_hash: Option<SetNewPrevHash<'static>>,
     target: Arc<Mutex<Vec<u8>>>,
     last_job_id: u32,
+    /// Sequence number of the next share sent on the upstream channel, the downstream channels
+    /// all submit on it
+    next_sequence_number: u32,
+    /// Set to false by the `Upstream` of this translator when it sends a `NewExtendedMiningJob`,
+    /// a `SetNewPrevHash` is handled once the job it may refer to is
+    is_new_job_handled: Arc<AtomicBool>,
 }
 
 impl Bridge {
@@ -75,6 +82,7 @@ impl Bridge {
         extranonces: ExtendedExtranonce,
         target: Arc<Mutex<Vec<u8>>>,
         up_id: u32,
+        is_new_job_handled: Arc<AtomicBool>,
     ) -> Result<Arc<Mutex<Self>>, Error<'static>> {
         info!("Creating new bridge for up_id {}:", up_id);
         let ids = Arc::new(Mutex::new(GroupId::new()));
@@ -100,6 +108,8 @@ impl Bridge {
             last_p_hash: None,
             target,
             last_job_id: 0,
+            next_sequence_number: 0,
+            is_new_job_handled,
         })))
     }
 
@@ -187,22 +197,36 @@ impl Bridge {
         self_: Arc<Mutex<Self>>,
         mut rx_sv1_downstream: tokio::sync::mpsc::Receiver<DownstreamMessages>,
     ) -> JoinHandle<()> {
-        tokio::task::spawn(async move {
+        crate::proxy_state::spawn(async move {
             loop {
                 let msg = match rx_sv1_downstream.recv().await {
                     Some(msg) => msg,
                     None => {
                         error!("Failed to receive message from downstream");
-                        ProxyState::update_translator_state(TranslatorState::Down);
+                        ProxyState::update_translator_state(
+                            TranslatorState::Down,
+                            "handle_downstream_messages: downstream channel closed",
+                        );
                         break;
                     }
                 };
 
                 match msg {
                     DownstreamMessages::SubmitShares(share) => {
-                        if let Err(e) = Self::handle_submit_shares(self_.clone(), share).await {
+                        let span = info_span!(
+                            "share",
+                            channel_id = share.channel_id,
+                            job_id = %share.share.job_id
+                        );
+                        if let Err(e) = Self::handle_submit_shares(self_.clone(), share)
+                            .instrument(span)
+                            .await
+                        {
                             error!("Failed to handle SubmitShareWithChannelId: {e}");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                format!("handle_downstream_messages: SubmitShares failed: {e}"),
+                            );
                             break;
                         }
                     }
@@ -211,7 +235,12 @@ impl Bridge {
                             Self::handle_update_downstream_target(self_.clone(), new_target)
                         {
                             error!("Failed to handle SetDownstreamTarget: {e}");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                format!(
+                                    "handle_downstream_messages: SetDownstreamTarget failed: {e}"
+                                ),
+                            );
                             break;
                         };
                     }
@@ -240,6 +269,7 @@ impl Bridge {
         share: SubmitShareWithChannelId,
     ) -> ProxyResult<'static, ()> {
         let channel_id = share.channel_id;
+        let (worker, difficulty) = (share.share.user_name.clone(), share.difficulty);
         info!("Bridge recv share for channel {:?}", channel_id);
         let (tx_sv2_submit_shares_ext, target_mutex) = self_
             .safe_lock(|s| (s.tx_sv2_submit_shares_ext.clone(), s.target.clone()))
@@ -264,7 +294,12 @@ impl Bridge {
                         return Err(e); // Error will be handled by the caller
                     }
                 };
-                Ok(s.channel_factory.on_submit_shares_extended(sv2_submit))
+                let res = s.channel_factory.on_submit_shares_extended(sv2_submit);
+                // Only the shares sent upstream take a number, the sequence has no gap
+                if let Ok(OnNewShare::SendSubmitShareUpstream(_)) = &res {
+                    s.next_sequence_number = s.next_sequence_number.wrapping_add(1);
+                }
+                Ok(res)
             })
             .map_err(|_| Error::BridgeMutexPoisoned)?;
 
@@ -274,11 +309,22 @@ impl Bridge {
                     .unwrap_or("unparsable error code")
                     .to_string();
                 error!("Submit share error {}", error_code);
+                crate::api::metrics::share_rejected(&error_code);
+                let outcome = ShareOutcome::from_error_code(&error_code);
+                worker_stats::share(&worker, channel_id, outcome, difficulty);
             }
             Ok(Ok(OnNewShare::SendSubmitShareUpstream((share, _)))) => {
                 info!("SHARE MEETS UPSTREAM TARGET channel id: {}", channel_id);
                 match share {
                     Share::Extended(share) => {
+                        // Lets the pool answer be attributed to this downstream and worker
+                        crate::api::metrics::share_forwarded(channel_id, share.sequence_number);
+                        worker_stats::share_forwarded(
+                            &worker,
+                            channel_id,
+                            share.sequence_number,
+                            difficulty,
+                        );
                         if tx_sv2_submit_shares_ext.send(share).await.is_err() {
                             error!("Failed to send SubmitShareExtended downstream");
                             return Err(Error::AsyncChannelError);
@@ -292,6 +338,7 @@ impl Bridge {
             Ok(Ok(OnNewShare::RelaySubmitShareUpstream)) => unreachable!(),
             Ok(Ok(OnNewShare::ShareMeetDownstreamTarget)) => {
                 info!("SHARE MEETS DOWNSTREAM TARGET channel id {}", channel_id);
+                worker_stats::share(&worker, channel_id, ShareOutcome::Accepted, difficulty);
             }
             // Proxy do not have JD capabilities
             Ok(Ok(OnNewShare::ShareMeetBitcoinTarget(..))) => unreachable!(),
@@ -333,8 +380,7 @@ impl Bridge {
         let extranonce2 = mining_device_extranonce;
         Ok(SubmitSharesExtended {
             channel_id,
-            // I put 0 below cause sequence_number is not what should be TODO
-            sequence_number: 0,
+            sequence_number: self.next_sequence_number,
             job_id: sv1_submit.job_id.parse::<u32>().expect("Internal error: this operation can not fail because job_id can always be converted into U32"),
             nonce: sv1_submit.nonce.0,
             ntime: sv1_submit.time.0,
@@ -348,14 +394,19 @@ impl Bridge {
         sv2_set_new_prev_hash: SetNewPrevHash<'static>,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
-        while !super::super::upstream::upstream::IS_NEW_JOB_HANDLED
-            .load(std::sync::atomic::Ordering::SeqCst)
-        {
+        let is_new_job_handled = self_
+            .safe_lock(|s| s.is_new_job_handled.clone())
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        while !is_new_job_handled.load(std::sync::atomic::Ordering::SeqCst) {
             tokio::task::yield_now().await;
         }
         self_
             .safe_lock(|s| s.last_p_hash = Some(sv2_set_new_prev_hash.clone()))
             .map_err(|_| Error::BridgeMutexPoisoned)?;
+        crate::api::status::new_prev_hash(
+            &sv2_set_new_prev_hash.prev_hash.to_vec(),
+            sv2_set_new_prev_hash.job_id,
+        );
 
         self_
             .safe_lock(|s| {
@@ -392,7 +443,10 @@ impl Bridge {
                 if tx_sv1_notify.send(notify.clone()).is_err() {
                     error!("Failed to send mining.notify");
                     // Update translator state to down
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        "handle_new_prev_hash: mining.notify channel closed",
+                    );
                 };
                 match_a_future_job = true;
                 self_
@@ -424,7 +478,7 @@ impl Bridge {
             .safe_lock(|s| s.tx_sv1_notify.clone())
             .map_err(|_| Error::BridgeMutexPoisoned)?;
         debug!("Starting handle_new_prev_hash task");
-        Ok(tokio::task::spawn(async move {
+        Ok(crate::proxy_state::spawn(async move {
             loop {
                 // Receive `SetNewPrevHash` from `Upstream`
                 let sv2_set_new_prev_hash: SetNewPrevHash =
@@ -432,7 +486,10 @@ impl Bridge {
                         Some(set_new_prev_hash) => set_new_prev_hash,
                         None => {
                             error!("Failed to receive SetNewPrevHash");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                "handle_new_prev_hash: SetNewPrevHash channel closed",
+                            );
                             break;
                         }
                     };
@@ -448,7 +505,10 @@ impl Bridge {
                 .await
                 {
                     error!("Failed to handle SetNewPrevHash: {e}");
-                    ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                    ProxyState::update_upstream_state(
+                        UpstreamType::TranslatorUpstream,
+                        format!("handle_new_prev_hash: {e}"),
+                    );
                     return;
                 }
             }
@@ -460,6 +520,10 @@ impl Bridge {
         sv2_new_extended_mining_job: NewExtendedMiningJob<'static>,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
+        crate::api::status::new_job(
+            sv2_new_extended_mining_job.job_id,
+            sv2_new_extended_mining_job.is_future(),
+        );
         // convert to non segwit jobs so we dont have to depend if miner's support segwit or not
         self_
             .safe_lock(|s| {
@@ -529,11 +593,11 @@ impl Bridge {
         self_: Arc<Mutex<Self>>,
         mut rx_sv2_new_ext_mining_job: tokio::sync::mpsc::Receiver<NewExtendedMiningJob<'static>>,
     ) -> Result<JoinHandle<()>, Error<'static>> {
-        let tx_sv1_notify = self_
-            .safe_lock(|s| s.tx_sv1_notify.clone())
+        let (tx_sv1_notify, is_new_job_handled) = self_
+            .safe_lock(|s| (s.tx_sv1_notify.clone(), s.is_new_job_handled.clone()))
             .map_err(|_| Error::BridgeMutexPoisoned)?;
         debug!("Starting handle_new_extended_mining_job task");
-        Ok(tokio::task::spawn(async move {
+        Ok(crate::proxy_state::spawn(async move {
             loop {
                 // Receive `NewExtendedMiningJob` from `Upstream`
                 let sv2_new_extended_mining_job: NewExtendedMiningJob =
@@ -541,7 +605,7 @@ impl Bridge {
                         Some(sv2_new_extended_mining_job) => sv2_new_extended_mining_job,
                         None => {
                             error!("Failed to receive NewExtendedMiningJob from upstream");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(TranslatorState::Down, "handle_new_extended_mining_job: NewExtendedMiningJob channel closed");
                             break;
                         }
                     };
@@ -557,10 +621,12 @@ impl Bridge {
                 .await
                 {
                     error!("Failed to handle NewExtendedMiningJob {e}",);
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        format!("handle_new_extended_mining_job: {e}"),
+                    );
                 };
-                super::super::upstream::upstream::IS_NEW_JOB_HANDLED
-                    .store(true, std::sync::atomic::Ordering::SeqCst);
+                is_new_job_handled.store(true, std::sync::atomic::Ordering::SeqCst);
             }
         }))
     }
@@ -597,6 +663,7 @@ mod test {
                 extranonces,
                 Arc::new(Mutex::new(upstream_target)),
                 1,
+                Arc::new(AtomicBool::new(true)),
             )
             .map_err(|_| ())?;
             Ok(b)
@@ -685,10 +752,13 @@ mod test {
                     .unwrap();
 
                 // pass sv1_submit into Bridge::translate_submit
+                bridge.next_sequence_number = 7;
                 let sv1_submit = test_utils::create_sv1_submit(0);
                 let sv2_message = bridge
                     .translate_submit(channel_id, sv1_submit, None)
                     .unwrap();
+                // Numbered for the upstream channel, the pool acks are keyed on it
+                assert_eq!(sv2_message.sequence_number, 7);
                 // assert sv2 message equals sv1 with version bits added
                 assert_eq!(
                     new_mining_job.version, sv2_message.version,
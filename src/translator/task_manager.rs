@@ -17,6 +17,7 @@ enum Task {
     #[allow(clippy::enum_variant_names)]
     StartupTask(AbortOnDrop),
     Bridge(AbortOnDrop),
+    ShareRateLimit(AbortOnDrop),
 }
 
 pub struct TaskManager {
@@ -31,7 +32,7 @@ impl TaskManager {
         up_connection: Sender<(Sender<Message>, Receiver<Message>, Option<Address>)>,
     ) -> Arc<Mutex<Self>> {
         let (sender, mut receiver) = mpsc::channel(10);
-        let handle = tokio::task::spawn(async move {
+        let handle = crate::proxy_state::spawn(async move {
             let mut tasks = vec![];
             while let Some(task) = receiver.recv().await {
                 tasks.push(task);
@@ -87,4 +88,14 @@ impl TaskManager {
             .await
             .map_err(|_| ())
     }
+    pub async fn add_share_rate_limit(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::ShareRateLimit(abortable))
+            .await
+            .map_err(|_| ())
+    }
 }
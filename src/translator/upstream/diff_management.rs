@@ -1,4 +1,4 @@
-use crate::translator::error::Error;
+use crate::{config::Configuration, translator::error::Error};
 
 use super::Upstream;
 
@@ -26,8 +26,13 @@ impl Upstream {
             RolesLogicError::NotFoundChannelId,
         ))?;
         let (timeout, new_hashrate) = diff_mgmt
-            .safe_lock(|d| (d.channel_diff_update_interval, d.channel_nominal_hashrate))
+            .safe_lock(|d| {
+                // Picks up the value from a configuration reload
+                d.channel_diff_update_interval = Configuration::channel_diff_update_interval();
+                (d.channel_diff_update_interval, d.channel_nominal_hashrate)
+            })
             .map_err(|_| Error::TranslatorDiffConfigMutexPoisoned)?;
+        crate::api::metrics::upstream_nominal_hashrate(new_hashrate);
         // UPDATE CHANNEL
         let update_channel = UpdateChannel {
             channel_id,
@@ -25,20 +25,17 @@ use std::{
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
 };
-use tokio::{
-    sync::mpsc::{Receiver as TReceiver, Sender as TSender},
-    task,
-};
+use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 use tracing::{error, info, warn};
 
 use super::task_manager::TaskManager;
 use crate::{
+    config::Configuration,
     proxy_state::{ProxyState, UpstreamType},
     shared::utils::AbortOnDrop,
 };
 use bitcoin::BlockHash;
 
-pub static IS_NEW_JOB_HANDLED: AtomicBool = AtomicBool::new(true);
 /// Represents the currently active `prevhash` of the mining job being worked on OR being submitted
 /// from the Downstream role.
 #[derive(Debug, Clone)]
@@ -86,6 +83,9 @@ pub struct Upstream {
     // than the configured percentage
     pub(super) difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
     pub sender: TSender<Mining<'static>>,
+    /// Set to false when a `NewExtendedMiningJob` is sent to the `Bridge`, that sets it back to
+    /// true once the job is handled. Shared with the `Bridge` of this translator only.
+    is_new_job_handled: Arc<AtomicBool>,
 }
 
 impl PartialEq for Upstream {
@@ -109,6 +109,7 @@ impl Upstream {
         target: Arc<Mutex<Vec<u8>>>,
         difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
         sender: TSender<Mining<'static>>,
+        is_new_job_handled: Arc<AtomicBool>,
     ) -> ProxyResult<'static, Arc<Mutex<Self>>> {
         Ok(Arc::new(Mutex::new(Self {
             extranonce_prefix: None,
@@ -123,6 +124,7 @@ impl Upstream {
             target,
             difficulty_config,
             sender,
+            is_new_job_handled,
         })))
     }
 
@@ -177,7 +179,7 @@ impl Upstream {
             user_identity, // TODO
             nominal_hash_rate,
             max_target: u256_max(),
-            min_extranonce_size: crate::MIN_EXTRANONCE2_SIZE,
+            min_extranonce_size: Configuration::min_extranonce2_size(),
         });
 
         // reset channel hashrate so downstreams can manage from now on out
@@ -217,7 +219,7 @@ impl Upstream {
                 .map_err(|_| Error::TranslatorUpstreamMutexPoisoned)?;
         let diff_manager_handle = {
             let self_ = self_.clone();
-            task::spawn(async move {
+            crate::proxy_state::spawn(async move {
                 // No need to start diff management immediatly
                 tokio::time::sleep(Duration::from_secs(5)).await;
                 loop {
@@ -231,7 +233,7 @@ impl Upstream {
 
         let main_loop_handle = {
             let self_ = self_.clone();
-            task::spawn(async move {
+            crate::proxy_state::spawn(async move {
                 while let Some(m) = receiver.recv().await {
                     let routing_logic = MiningRoutingLogic::None;
 
@@ -294,7 +296,7 @@ impl Upstream {
                                                 error!(
                                                     "Failed to create a valid extended extranonce from {:?} {:?} {:?} {:?}: {:?}",
                                                     extranonce_prefix, range_0, range_1, range_2, e
-                                                ); ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                                                ); ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream, format!("parse_incoming: invalid extended extranonce: {e:?}"));
                                                 break;
                                             }
                                         };
@@ -384,7 +386,7 @@ impl Upstream {
 
         let handle = {
             let self_ = self_.clone();
-            task::spawn(async move {
+            crate::proxy_state::spawn(async move {
                 loop {
                     let mut sv2_submit: SubmitSharesExtended = match rx_submit.recv().await {
                         Some(msg) => msg,
@@ -601,16 +603,27 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
     /// Handles the SV2 `SubmitSharesSuccess` message.
     fn handle_submit_shares_success(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
+        let first = m
+            .last_sequence_number
+            .wrapping_sub(m.new_submits_accepted_count.saturating_sub(1));
+        for offset in 0..m.new_submits_accepted_count {
+            crate::worker_stats::share_acknowledged(first.wrapping_add(offset));
+        }
         Ok(SendTo::None(None))
     }
 
     /// Handles the SV2 `SubmitSharesError` message.
     fn handle_submit_shares_error(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesError,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
+        let error_code = m.error_code.to_vec();
+        let error_code = String::from_utf8_lossy(&error_code);
+        crate::api::metrics::share_rejected(&format!("pool: {error_code}"));
+        crate::router::health::share_rejected();
+        crate::worker_stats::share_rejected_upstream(m.sequence_number);
         Ok(SendTo::None(None))
     }
 
@@ -634,7 +647,8 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         if self.is_work_selection_enabled() {
             Ok(SendTo::None(None))
         } else {
-            IS_NEW_JOB_HANDLED.store(false, std::sync::atomic::Ordering::SeqCst);
+            self.is_new_job_handled
+                .store(false, std::sync::atomic::Ordering::SeqCst);
             if !m.version_rolling_allowed {
                 warn!("VERSION ROLLING NOT ALLOWED IS A TODO");
                 // todo!()
@@ -4,65 +4,83 @@ use std::{
 };
 
 use crate::translator::error::Error;
-use bitcoin::hashes::{sha256d, Hash};
-use lazy_static::lazy_static;
+use bitcoin::{
+    hashes::{sha256d, Hash},
+    BlockHeader, Transaction,
+};
 use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
 use sv1_api::{client_to_server, server_to_client::Notify};
 use tracing::error;
 
 use super::downstream::Downstream;
-lazy_static! {
-    pub static ref SHARE_TIMESTAMPS: Arc<Mutex<VecDeque<tokio::time::Instant>>> =
-        Arc::new(Mutex::new(VecDeque::with_capacity(70)));
-    pub static ref IS_RATE_LIMITED: AtomicBool = AtomicBool::new(false);
+
+/// Rate limit of 70 shares per minute on the shares the miners of a translator send upstream.
+/// Each translator has its own, the pools of a split hashrate are limited separately.
+#[derive(Debug)]
+pub struct ShareRateLimit {
+    timestamps: Mutex<VecDeque<tokio::time::Instant>>,
+    is_rate_limited: AtomicBool,
 }
 
-/// Checks if a share can be sent upstream based on a rate limit of 70 shares per minute.
-/// Returns `true` if the share can be sent, `false` if the limit is exceeded.
-pub async fn check_share_rate_limit() {
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-    loop {
-        interval.tick().await;
-        let now = tokio::time::Instant::now();
-        let count = SHARE_TIMESTAMPS
-            .safe_lock(|timestamps| {
-                while let Some(&front) = timestamps.front() {
-                    if now.duration_since(front).as_secs() >= 60 {
-                        timestamps.pop_front();
-                    } else {
-                        break;
-                    }
-                }
-                timestamps.len()
-            })
-            .unwrap_or_else(|e| {
-                error!("Failed to lock SHARE_TIMESTAMPS: {:?}", e);
-                0
-            });
+impl ShareRateLimit {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            timestamps: Mutex::new(VecDeque::with_capacity(70)),
+            is_rate_limited: AtomicBool::new(false),
+        })
+    }
 
-        IS_RATE_LIMITED.store(count >= 70, std::sync::atomic::Ordering::SeqCst);
+    /// Checks every second if a share can be sent upstream based on a rate limit of 70 shares
+    /// per minute, never returns.
+    pub async fn check(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let now = tokio::time::Instant::now();
+            let count = self
+                .timestamps
+                .safe_lock(|timestamps| {
+                    while let Some(&front) = timestamps.front() {
+                        if now.duration_since(front).as_secs() >= 60 {
+                            timestamps.pop_front();
+                        } else {
+                            break;
+                        }
+                    }
+                    timestamps.len()
+                })
+                .unwrap_or_else(|e| {
+                    error!("Failed to lock the share timestamps: {:?}", e);
+                    0
+                });
+
+            self.is_rate_limited
+                .store(count >= 70, std::sync::atomic::Ordering::SeqCst);
+        }
     }
-}
 
-/// Checks if a share can be sent by checking if rate is limited
-pub fn allow_submit_share() -> crate::translator::error::ProxyResult<'static, bool> {
-    // Check if rate-limited
-    let is_rate_limited = IS_RATE_LIMITED.load(std::sync::atomic::Ordering::SeqCst);
+    /// Checks if a share can be sent by checking if rate is limited
+    pub fn allow_submit_share(&self) -> crate::translator::error::ProxyResult<'static, bool> {
+        // Check if rate-limited
+        let is_rate_limited = self
+            .is_rate_limited
+            .load(std::sync::atomic::Ordering::SeqCst);
 
-    if is_rate_limited {
-        return Ok(false); // Rate limit exceeded, don’t send
-    }
+        if is_rate_limited {
+            return Ok(false); // Rate limit exceeded, don’t send
+        }
 
-    SHARE_TIMESTAMPS
-        .safe_lock(|timestamps| {
-            timestamps.push_back(tokio::time::Instant::now());
-        })
-        .map_err(|e| {
-            error!("Failed to lock SHARE_TIMESTAMPS: {:?}", e);
-            Error::TranslatorDiffConfigMutexPoisoned
-        })?;
+        self.timestamps
+            .safe_lock(|timestamps| {
+                timestamps.push_back(tokio::time::Instant::now());
+            })
+            .map_err(|e| {
+                error!("Failed to lock the share timestamps: {:?}", e);
+                Error::TranslatorDiffConfigMutexPoisoned
+            })?;
 
-    Ok(true) // Share can be sent
+        Ok(true) // Share can be sent
+    }
 }
 
 pub fn validate_share(
@@ -91,16 +109,7 @@ pub fn validate_share(
     extranonce.extend_from_slice(request.extra_nonce2.0.as_ref());
     let extranonce: &[u8] = extranonce.as_ref();
 
-    let job_version = job.version.0;
-    let request_version = request
-        .version_bits
-        .clone()
-        .map(|vb| vb.0)
-        .unwrap_or(job_version);
-    let mask = version_rolling_mask
-        .unwrap_or(sv1_api::utils::HexU32Be(0x1FFFE000_u32))
-        .0;
-    let version = (job_version & !mask) | (request_version & mask);
+    let version = share_version(request, job, version_rolling_mask);
 
     let mut hash = roles_logic_sv2::utils::get_target(
         request.nonce.0,
@@ -122,6 +131,54 @@ pub fn validate_share(
     hash <= target
 }
 
+/// Version of the header of a share: the version of the job with the bits allowed by the mask
+/// rolled by the miner.
+fn share_version(
+    request: &client_to_server::Submit<'static>,
+    job: &Notify,
+    version_rolling_mask: Option<sv1_api::utils::HexU32Be>,
+) -> u32 {
+    let job_version = job.version.0;
+    let request_version = request
+        .version_bits
+        .clone()
+        .map(|vb| vb.0)
+        .unwrap_or(job_version);
+    let mask = version_rolling_mask
+        .unwrap_or(sv1_api::utils::HexU32Be(0x1FFFE000_u32))
+        .0;
+    (job_version & !mask) | (request_version & mask)
+}
+
+/// Header of the block a share would make and its coinbase, `None` if the coinbase of the job
+/// can not be decoded.
+pub fn share_block(
+    request: &client_to_server::Submit<'static>,
+    job: &Notify,
+    extranonce1: &[u8],
+    version_rolling_mask: Option<sv1_api::utils::HexU32Be>,
+) -> Option<(BlockHeader, Transaction)> {
+    let mut coinbase = Vec::new();
+    coinbase.extend_from_slice(job.coin_base1.as_ref());
+    coinbase.extend_from_slice(extranonce1);
+    coinbase.extend_from_slice(request.extra_nonce2.0.as_ref());
+    coinbase.extend_from_slice(job.coin_base2.as_ref());
+    let coinbase: Transaction = bitcoin::consensus::deserialize(&coinbase).ok()?;
+
+    let prev_hash: Vec<u8> = job.prev_hash.clone().into();
+    let merkle_branch: Vec<Vec<u8>> = job.merkle_branch.iter().map(|b| b.0.to_vec()).collect();
+    let header = crate::blocks::header(
+        share_version(request, job, version_rolling_mask),
+        &prev_hash,
+        &coinbase,
+        &merkle_branch,
+        request.time.0,
+        job.bits.0,
+        request.nonce.0,
+    )?;
+    Some((header, coinbase))
+}
+
 // /// currently the pool only supports 16 bytes exactly for its channels
 // /// to use but that may change
 // pub fn proxy_extranonce1_len(
@@ -0,0 +1,441 @@
+//! Per-worker share statistics.
+//!
+//! Workers are identified by the name used in `mining.submit` and the connection they submit
+//! on. The downstream records the shares it rejects itself (stale, duplicate, invalid, ...) and
+//! the bridge records the outcome of the shares it validates against the channel. The shares
+//! forwarded to the pool are counted accepted when they are sent, and moved to the rejected ones
+//! of their worker if the pool answers with a `SubmitSharesError`.
+//!
+//! Hashrates are computed from the difficulty of the accepted shares, which gives a figure
+//! comparable to the one computed by the pool, unlike the estimation of the difficulty
+//! management that only aims at the configured share rate.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Longest window we compute a hashrate for, older buckets are dropped.
+const MAX_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+const BUCKET_SECS: u64 = 60;
+/// Shares remembered per connection to detect duplicates.
+const MAX_RECENT_SHARES: usize = 1024;
+/// Shares forwarded to the pool remembered until it answers, the oldest are forgotten past this.
+const MAX_FORWARDED_SHARES: usize = 10_000;
+
+lazy_static! {
+    static ref WORKERS: Workers = Workers::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareOutcome {
+    Accepted,
+    Rejected,
+    Stale,
+    Duplicate,
+}
+
+impl ShareOutcome {
+    /// Outcome of a share refused by the channel factory with `error_code`.
+    pub fn from_error_code(error_code: &str) -> Self {
+        if error_code.contains("stale") || error_code == "invalid-job-id" {
+            Self::Stale
+        } else if error_code.contains("duplicate") {
+            Self::Duplicate
+        } else {
+            Self::Rejected
+        }
+    }
+}
+
+/// What identifies a share sent by a SV1 miner on a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShareKey {
+    pub job_id: String,
+    pub extranonce2: Vec<u8>,
+    pub ntime: u32,
+    pub nonce: u32,
+    pub version_bits: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct WorkerStats {
+    connected: bool,
+    accepted: u64,
+    rejected: u64,
+    stale: u64,
+    duplicate: u64,
+    difficulty_sum: f64,
+    first_seen: u64,
+    last_share: Option<u64>,
+    /// Difficulty of the accepted shares per minute, oldest first
+    buckets: VecDeque<(u64, f64)>,
+}
+
+impl WorkerStats {
+    fn new(now: u64) -> Self {
+        Self {
+            connected: true,
+            first_seen: now,
+            ..Default::default()
+        }
+    }
+
+    fn record(&mut self, outcome: ShareOutcome, difficulty: f64, now: u64) {
+        self.last_share = Some(now);
+        match outcome {
+            ShareOutcome::Accepted => {
+                self.accepted += 1;
+                self.difficulty_sum += difficulty;
+                let bucket = now / BUCKET_SECS;
+                match self.buckets.back_mut() {
+                    Some((last, sum)) if *last == bucket => *sum += difficulty,
+                    _ => self.buckets.push_back((bucket, difficulty)),
+                }
+                let oldest = bucket.saturating_sub(MAX_WINDOW.as_secs() / BUCKET_SECS);
+                while self.buckets.front().is_some_and(|(b, _)| *b <= oldest) {
+                    self.buckets.pop_front();
+                }
+            }
+            ShareOutcome::Rejected => self.rejected += 1,
+            ShareOutcome::Stale => self.stale += 1,
+            ShareOutcome::Duplicate => self.duplicate += 1,
+        }
+    }
+
+    /// A share counted accepted at `at` was rejected by the pool.
+    fn rejected_upstream(&mut self, difficulty: f64, at: u64) {
+        self.accepted = self.accepted.saturating_sub(1);
+        self.rejected += 1;
+        self.difficulty_sum = (self.difficulty_sum - difficulty).max(0.0);
+        let bucket = at / BUCKET_SECS;
+        if let Some((_, sum)) = self.buckets.iter_mut().find(|(b, _)| *b == bucket) {
+            *sum = (*sum - difficulty).max(0.0);
+        }
+    }
+
+    /// Hashrate in h/s over the last `window`, or since the worker showed up if it is more
+    /// recent.
+    fn hashrate(&self, window: Duration, now: u64) -> f64 {
+        let buckets = window.as_secs() / BUCKET_SECS;
+        let first_bucket = (now / BUCKET_SECS + 1).saturating_sub(buckets);
+        let difficulty: f64 = self
+            .buckets
+            .iter()
+            .filter(|(b, _)| *b >= first_bucket)
+            .map(|(_, sum)| sum)
+            .sum();
+        let since = (first_bucket * BUCKET_SECS).max(self.first_seen);
+        // Avoid huge figures from the first share of a new worker
+        let elapsed = now.saturating_sub(since).max(BUCKET_SECS);
+        difficulty * 2f64.powi(32) / elapsed as f64
+    }
+}
+
+/// Last shares of a connection, to detect resubmissions.
+#[derive(Debug, Default)]
+struct RecentShares {
+    keys: HashSet<ShareKey>,
+    order: VecDeque<ShareKey>,
+}
+
+impl RecentShares {
+    /// Remembers `key`, returns false if it was already there.
+    fn insert(&mut self, key: ShareKey) -> bool {
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > MAX_RECENT_SHARES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Pipeline and sequence number of a share sent to the pool, see [`crate::split`].
+type ForwardedKey = (Option<Arc<str>>, u32);
+
+/// Worker, difficulty and time of a share sent to the pool.
+#[derive(Debug)]
+struct Forwarded {
+    worker: (String, u32),
+    difficulty: f64,
+    at: u64,
+}
+
+/// Shares sent to the pool the pool did not answer yet.
+#[derive(Debug, Default)]
+struct ForwardedShares {
+    shares: HashMap<ForwardedKey, Forwarded>,
+    /// In the order they were sent, the answered ones are skipped
+    order: VecDeque<ForwardedKey>,
+}
+
+#[derive(Default)]
+struct Workers {
+    stats: DashMap<(String, u32), WorkerStats>,
+    recent_shares: DashMap<u32, RecentShares>,
+    forwarded: Mutex<ForwardedShares>,
+}
+
+impl Workers {
+    fn record(
+        &self,
+        name: &str,
+        connection_id: u32,
+        outcome: ShareOutcome,
+        difficulty: f64,
+        now: u64,
+    ) {
+        self.stats
+            .entry((name.to_string(), connection_id))
+            .or_insert_with(|| WorkerStats::new(now))
+            .record(outcome, difficulty, now);
+    }
+
+    fn forwarded(&self) -> std::sync::MutexGuard<'_, ForwardedShares> {
+        self.forwarded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn share_forwarded(&self, key: ForwardedKey, worker: (String, u32), difficulty: f64, now: u64) {
+        self.record(&worker.0, worker.1, ShareOutcome::Accepted, difficulty, now);
+        let mut forwarded = self.forwarded();
+        while forwarded.shares.len() >= MAX_FORWARDED_SHARES {
+            let Some(oldest) = forwarded.order.pop_front() else {
+                break;
+            };
+            forwarded.shares.remove(&oldest);
+        }
+        forwarded.order.push_back(key.clone());
+        forwarded.shares.insert(
+            key,
+            Forwarded {
+                worker,
+                difficulty,
+                at: now,
+            },
+        );
+        // The answered shares in front are not needed to find the oldest anymore
+        while let Some(front) = forwarded.order.front() {
+            if forwarded.shares.contains_key(front) {
+                break;
+            }
+            forwarded.order.pop_front();
+        }
+    }
+
+    /// Forgets the share, returns it if the pool had not answered yet.
+    fn share_answered(&self, key: &ForwardedKey) -> Option<Forwarded> {
+        self.forwarded().shares.remove(key)
+    }
+
+    fn share_rejected_upstream(&self, key: &ForwardedKey) {
+        let Some(share) = self.share_answered(key) else {
+            return;
+        };
+        if let Some(mut worker) = self.stats.get_mut(&share.worker) {
+            worker.rejected_upstream(share.difficulty, share.at);
+        }
+    }
+
+    fn is_duplicate(&self, connection_id: u32, key: ShareKey) -> bool {
+        !self
+            .recent_shares
+            .entry(connection_id)
+            .or_default()
+            .insert(key)
+    }
+
+    fn connection_closed(&self, connection_id: u32) {
+        self.recent_shares.remove(&connection_id);
+        for mut worker in self.stats.iter_mut() {
+            if worker.key().1 == connection_id {
+                worker.connected = false;
+            }
+        }
+    }
+
+    fn snapshot(&self, now: u64) -> Vec<WorkerSnapshot> {
+        // Forget the workers gone for longer than the longest window
+        self.stats.retain(|_, w| {
+            w.connected || now.saturating_sub(w.last_share.unwrap_or(0)) < MAX_WINDOW.as_secs()
+        });
+        let mut workers: Vec<WorkerSnapshot> = self
+            .stats
+            .iter()
+            .map(|w| {
+                let (name, connection_id) = w.key().clone();
+                WorkerSnapshot {
+                    name,
+                    connection_id,
+                    connected: w.connected,
+                    accepted: w.accepted,
+                    rejected: w.rejected,
+                    stale: w.stale,
+                    duplicate: w.duplicate,
+                    difficulty_sum: w.difficulty_sum,
+                    last_share: w.last_share,
+                    hashrate_5m: w.hashrate(Duration::from_secs(5 * 60), now),
+                    hashrate_1h: w.hashrate(Duration::from_secs(60 * 60), now),
+                    hashrate_24h: w.hashrate(MAX_WINDOW, now),
+                }
+            })
+            .collect();
+        workers.sort_by(|a, b| (&a.name, a.connection_id).cmp(&(&b.name, b.connection_id)));
+        workers
+    }
+}
+
+/// Statistics of a worker, as returned by `GET /workers`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerSnapshot {
+    pub name: String,
+    pub connection_id: u32,
+    pub connected: bool,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+    pub duplicate: u64,
+    pub difficulty_sum: f64,
+    /// Unix time of the last share
+    pub last_share: Option<u64>,
+    pub hashrate_5m: f64,
+    pub hashrate_1h: f64,
+    pub hashrate_24h: f64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Records a share of `name` submitted on `connection_id` with the given difficulty.
+pub fn share(name: &str, connection_id: u32, outcome: ShareOutcome, difficulty: f32) {
+    WORKERS.record(name, connection_id, outcome, difficulty as f64, now())
+}
+
+/// Records a share of `name` submitted on `connection_id` and sent to the pool with
+/// `sequence_number`, accepted until the pool rejects it.
+pub fn share_forwarded(name: &str, connection_id: u32, sequence_number: u32, difficulty: f32) {
+    WORKERS.share_forwarded(
+        (crate::proxy_state::scope(), sequence_number),
+        (name.to_string(), connection_id),
+        difficulty as f64,
+        now(),
+    )
+}
+
+/// The pool accepted the share sent with `sequence_number`.
+pub fn share_acknowledged(sequence_number: u32) {
+    WORKERS.share_answered(&(crate::proxy_state::scope(), sequence_number));
+}
+
+/// The pool rejected the share sent with `sequence_number`, it is moved to the rejected shares
+/// of its worker.
+pub fn share_rejected_upstream(sequence_number: u32) {
+    WORKERS.share_rejected_upstream(&(crate::proxy_state::scope(), sequence_number))
+}
+
+/// Returns true if the same share was already submitted on `connection_id`.
+pub fn is_duplicate(connection_id: u32, key: ShareKey) -> bool {
+    WORKERS.is_duplicate(connection_id, key)
+}
+
+/// The workers of the connection are kept until their stats get older than 24 hours.
+pub fn connection_closed(connection_id: u32) {
+    WORKERS.connection_closed(connection_id)
+}
+
+/// Snapshot of every known worker.
+pub fn workers() -> Vec<WorkerSnapshot> {
+    WORKERS.snapshot(now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_shares_and_estimates_hashrate() {
+        let workers = Workers::default();
+        let start = 1_700_000_000 / BUCKET_SECS * BUCKET_SECS;
+        // One share of difficulty 60 every 10 seconds for two hours: 6 diff/s
+        for i in 0..720 {
+            workers.record("farm.rig1", 1, ShareOutcome::Accepted, 60.0, start + i * 10);
+        }
+        workers.record("farm.rig1", 1, ShareOutcome::Stale, 60.0, start + 7200);
+        workers.record("farm.rig1", 1, ShareOutcome::Duplicate, 60.0, start + 7200);
+        workers.record("farm.rig2", 1, ShareOutcome::Rejected, 60.0, start + 7200);
+
+        let snapshot = workers.snapshot(start + 7200);
+        assert_eq!(snapshot.len(), 2);
+        let rig1 = &snapshot[0];
+        assert_eq!(rig1.name, "farm.rig1");
+        assert_eq!(
+            (rig1.accepted, rig1.rejected, rig1.stale, rig1.duplicate),
+            (720, 0, 1, 1)
+        );
+        assert_eq!(rig1.difficulty_sum, 720.0 * 60.0);
+        assert_eq!(rig1.last_share, Some(start + 7200));
+        let expected = 6.0 * 2f64.powi(32);
+        for hashrate in [rig1.hashrate_5m, rig1.hashrate_1h, rig1.hashrate_24h] {
+            assert!((hashrate / expected - 1.0).abs() < 0.05, "{hashrate}");
+        }
+        assert_eq!(snapshot[1].rejected, 1);
+        assert_eq!(snapshot[1].hashrate_24h, 0.0);
+
+        // Disconnected workers are dropped once their last share is out of the window
+        workers.connection_closed(1);
+        assert_eq!(workers.snapshot(start + 7200 + 3600).len(), 2);
+        assert!(workers
+            .snapshot(start + 7200 + MAX_WINDOW.as_secs())
+            .is_empty());
+    }
+
+    #[test]
+    fn moves_the_shares_rejected_by_the_pool() {
+        let workers = Workers::default();
+        let start = 1_700_000_000 / BUCKET_SECS * BUCKET_SECS;
+        let worker = ("farm.rig1".to_string(), 1);
+        workers.share_forwarded((None, 1), worker.clone(), 60.0, start);
+        workers.share_forwarded((None, 2), worker.clone(), 60.0, start + 10);
+        // Same sequence number on another pipeline
+        let pool: Option<Arc<str>> = Some("pool.example.com:2000".into());
+        workers.share_forwarded((pool, 2), worker, 60.0, start + 10);
+        workers.share_rejected_upstream(&(None, 2));
+        // Answered already
+        workers.share_rejected_upstream(&(None, 2));
+        assert!(workers.share_answered(&(None, 1)).is_some());
+
+        let rig1 = &workers.snapshot(start + 60)[0];
+        assert_eq!((rig1.accepted, rig1.rejected), (2, 1));
+        assert_eq!(rig1.difficulty_sum, 120.0);
+        assert_eq!(workers.forwarded().shares.len(), 1);
+    }
+
+    #[test]
+    fn detects_duplicates_per_connection() {
+        let workers = Workers::default();
+        let key = ShareKey {
+            job_id: "1".to_string(),
+            extranonce2: vec![0, 1],
+            ntime: 1,
+            nonce: 2,
+            version_bits: None,
+        };
+        assert!(!workers.is_duplicate(1, key.clone()));
+        assert!(workers.is_duplicate(1, key.clone()));
+        assert!(!workers.is_duplicate(2, key.clone()));
+        workers.connection_closed(1);
+        assert!(!workers.is_duplicate(1, key));
+    }
+}